@@ -0,0 +1,39 @@
+//! Captures the git commit and rustc version for the `build-info` command.
+
+use std::process::Command;
+
+/// First line of a command's stdout, or "unknown" if it can't be run.
+fn command_output(program: &str, args: &[&str]) -> String {
+    Command::new(program)
+        .args(args)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .and_then(|stdout| stdout.lines().next().map(str::to_string))
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+fn main() {
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+
+    println!(
+        "cargo:rustc-env=BUILD_GIT_COMMIT={}",
+        command_output("git", &["rev-parse", "--short", "HEAD"])
+    );
+    println!(
+        "cargo:rustc-env=BUILD_RUSTC_VERSION={}",
+        command_output(&rustc, &["--version"])
+    );
+    println!(
+        "cargo:rustc-env=BUILD_TARGET={}",
+        std::env::var("TARGET").unwrap_or_default()
+    );
+    println!(
+        "cargo:rustc-env=BUILD_PROFILE={}",
+        std::env::var("PROFILE").unwrap_or_default()
+    );
+
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
@@ -0,0 +1,50 @@
+//! Bulk check of the configured accounts against Fintoc and Lunch Money,
+//! printed by the `audit` command.
+
+use serde::Serialize;
+
+/// One configured asset: an account's main asset, or one of its
+/// `currency_assets`.
+#[derive(Debug, Serialize)]
+pub struct AssetAudit {
+    pub bank: String,
+    pub account: String,
+    /// Currency of the Fintoc account, if the link still returns it
+    pub fintoc_currency: Option<String>,
+    /// Currency the asset is expected to hold
+    pub expected_currency: Option<String>,
+    pub asset_id: String,
+    /// Currency of the Lunch Money asset, if it exists
+    pub asset_currency: Option<String>,
+    pub status: AuditStatus,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case", tag = "kind", content = "detail")]
+pub enum AuditStatus {
+    Ok,
+    /// The asset's currency differs from the expected one
+    CurrencyMismatch,
+    /// No manual Lunch Money asset has this id
+    AssetMissing,
+    /// The bank's link doesn't return the Fintoc account anymore
+    AccountNotOnLink,
+    /// The bank's link couldn't be fetched, e.g. because it expired
+    LinkError(String),
+}
+
+impl AuditStatus {
+    pub fn is_ok(&self) -> bool {
+        *self == AuditStatus::Ok
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            AuditStatus::Ok => "ok".to_string(),
+            AuditStatus::CurrencyMismatch => "currency mismatch".to_string(),
+            AuditStatus::AssetMissing => "asset missing".to_string(),
+            AuditStatus::AccountNotOnLink => "not on the Fintoc link".to_string(),
+            AuditStatus::LinkError(error) => format!("link error: {}", error),
+        }
+    }
+}
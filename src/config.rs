@@ -0,0 +1,1012 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use chrono::offset::{Local, Utc};
+use chrono::DateTime;
+use serde::Deserialize;
+
+use crate::currency::{DisplayFormat, RoundingMode};
+use crate::debug::debug_log;
+use crate::fintoc::FetchOptions;
+use crate::lunchmoney::InsertOptions;
+use crate::types::fintoc::{
+    AccountCredentials, ConversionOptions, DateField, ExternalIdSource, InstallmentSplit,
+    MissingDate, NotesField, OriginalName, PayeeCase, PayeeSource,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct AppConfig {
+    pub tokens: Tokens,
+    pub banks: Vec<Bank>,
+    pub sync_settings: SyncSettings,
+    #[serde(default)]
+    pub http: HttpSettings,
+    pub notify: Option<NotifySettings>,
+    /// Symbol and precision to display amounts with, by currency code
+    #[serde(default)]
+    pub currency_display: HashMap<String, DisplayFormat>,
+}
+
+/// Where to report the outcome of a `sync`.
+#[derive(Debug, Deserialize)]
+pub struct NotifySettings {
+    /// URL that receives a JSON summary, e.g. a Slack incoming webhook
+    pub webhook_url: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Tokens {
+    pub fintoc_secret_token: String,
+    pub lunch_money_api_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Bank {
+    pub name: String,
+    pub link_token: String,
+    pub accounts: Vec<Account>,
+    /// Disabled banks are left out unless named explicitly on the command line
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// Lunch Money token for this bank's accounts, overriding
+    /// `tokens.lunch_money_api_token`
+    pub lunch_money_api_token: Option<String>,
+    /// Overrides `sync_settings.default_start_from` for this bank's accounts,
+    /// e.g. a longer window for a bank that posts movements late
+    pub default_start_from: Option<String>,
+}
+
+/// What to do when fetching movements ends on an empty page right after a full
+/// one, which may mean Fintoc stopped early.
+#[derive(Debug, Default, Deserialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum ShortFetchHandling {
+    /// Print a warning and note it in the summary
+    #[default]
+    Warn,
+    /// Fetch the account's movements once more, then warn if still short
+    Retry,
+    /// Don't check
+    Ignore,
+}
+
+/// Fintoc balance figure reported to Lunch Money, overriding the account
+/// type's usual one.
+#[derive(Debug, Deserialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum BalanceSource {
+    Current,
+    Available,
+    /// What's owed on a credit line
+    LimitMinusAvailable,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Clone, Copy)]
+pub enum AccountType {
+    Checking,
+    Savings,
+    Credit,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Account {
+    pub name: String,
+    pub fintoc_account_id: String,
+    /// Left empty with `match_asset_by_name`, to use the asset named like the
+    /// account
+    #[serde(default)]
+    pub lunch_money_asset_id: String,
+    #[serde(rename = "type")]
+    pub account_type: AccountType,
+    #[serde(default)]
+    pub skip_movements: bool,
+    /// Disabled accounts are left out unless named explicitly on the command
+    /// line. Unlike `skip_movements`, their balance isn't updated either.
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// Minor-unit exponent overriding the currency default, e.g. 0 when Fintoc
+    /// sends already-scaled USD amounts for this account
+    pub amount_scale: Option<u32>,
+    /// Balance figure to use instead of the account type's default, for banks
+    /// that report it differently
+    pub balance_source: Option<BalanceSource>,
+    /// Lowest plausible balance, in major units. Balances below it aren't
+    /// written to Lunch Money unless forced.
+    pub min_balance: Option<f64>,
+    /// Highest plausible balance, in major units
+    pub max_balance: Option<f64>,
+    /// Lunch Money asset ids, keyed by ISO currency code, for movements whose
+    /// currency differs from the account's. Movements in other currencies are
+    /// skipped.
+    #[serde(default)]
+    pub currency_assets: HashMap<String, String>,
+    /// Payee used instead of the movement description, e.g. the account
+    /// nickname. Transfers with a known counterparty keep their own payee.
+    pub default_payee: Option<String>,
+    /// Only use `default_payee` for descriptions matching this regex
+    pub junk_payee_pattern: Option<String>,
+    /// Category for movements no category rule matched, e.g. on a card only
+    /// used for subscriptions
+    pub default_category_id: Option<u64>,
+    /// Negate movement amounts, for banks that report expenses as positive
+    #[serde(default)]
+    pub flip_sign: bool,
+    /// Keep the Lunch Money asset's currency when updating its balance,
+    /// instead of setting the Fintoc one
+    #[serde(default)]
+    pub preserve_asset_currency: bool,
+    /// Overrides `sync_settings.payee_sources` for this account
+    pub payee_sources: Option<Vec<PayeeSource>>,
+    /// Overrides `sync_settings.apply_rules` for this account
+    pub apply_rules: Option<bool>,
+    /// Overrides `sync_settings.check_for_recurring` for this account
+    pub check_for_recurring: Option<bool>,
+    /// Lunch Money token for this account, overriding the bank's and the
+    /// global one
+    pub lunch_money_api_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SyncSettings {
+    pub default_start_from: String,
+    pub notes_template: Option<String>,
+    /// Movement fields joined into the notes when there's no template, e.g.
+    /// `["comment", "reference_id"]`
+    #[serde(default)]
+    pub notes_fields: Vec<NotesField>,
+    /// Separator between `notes_fields`
+    #[serde(default = "default_notes_separator")]
+    pub notes_separator: String,
+    #[serde(default = "default_dead_letter_file")]
+    pub dead_letter_file: String,
+    /// Fields the tool last wrote to each transaction, kept with
+    /// `update_existing` so manual edits aren't overwritten
+    #[serde(default = "default_written_fields_file")]
+    pub written_fields_file: String,
+    /// Prometheus textfile written after each `sync`, e.g. for node-exporter
+    pub metrics_file: Option<String>,
+    /// Directory getting a JSON record of every `sync` run
+    pub history_dir: Option<String>,
+    /// Where the last sync and backfill progress of each account are kept
+    #[serde(default = "default_state_file")]
+    pub state_file: String,
+    #[serde(default)]
+    pub category_rules: Vec<CategoryRule>,
+    /// Movements split into monthly installments once inserted
+    #[serde(default)]
+    pub split_rules: Vec<SplitRule>,
+    /// Whether to check that Lunch Money echoes back exactly the balance and
+    /// currency we sent when updating an asset
+    #[serde(default = "default_true")]
+    pub verify_balance_update: bool,
+    /// Date asset balances with the Fintoc account's `refreshed_at` instead of
+    /// the time of the update
+    #[serde(default = "default_true")]
+    pub balance_as_of_refreshed_at: bool,
+    /// Don't update asset balances that already have the Fintoc balance
+    #[serde(default = "default_true")]
+    pub skip_unchanged_balance: bool,
+    /// How many times transactions that fail to insert are re-attempted
+    /// before giving up on them
+    #[serde(default = "default_insert_retries")]
+    pub insert_retries: u32,
+    /// Let Lunch Money apply the user's rules to inserted transactions
+    #[serde(default = "default_true")]
+    pub apply_rules: bool,
+    /// Let Lunch Money match inserted transactions to recurring items
+    #[serde(default = "default_true")]
+    pub check_for_recurring: bool,
+    /// Default for every concurrency knob that isn't set explicitly. Also set
+    /// by the `--concurrency` flag.
+    pub concurrency: Option<usize>,
+    /// Number of Lunch Money insert requests in flight at once
+    pub insert_concurrency: Option<usize>,
+    /// Number of Fintoc balances fetched at once, in a phase before accounts
+    /// are synced
+    pub balance_concurrency: Option<usize>,
+    /// Safety cap on the number of movement pages fetched per account
+    #[serde(default = "default_max_pages")]
+    pub max_pages: usize,
+    /// Safety cap on the number of movements fetched per account, failing the
+    /// account when exceeded. Unlimited when unset.
+    pub max_movements: Option<usize>,
+    /// Number of movement pages requested concurrently
+    pub prefetch_pages: Option<usize>,
+    /// Look up existing Lunch Money transactions before inserting and skip
+    /// those matching by external id or, failing that, by composite key
+    #[serde(default)]
+    pub composite_dedup: bool,
+    /// Update the payee, notes and status of already-synced transactions when
+    /// their Fintoc data changed, unless they were edited in Lunch Money
+    #[serde(default)]
+    pub update_existing: bool,
+    /// Name check movements `"Check #<reference>"` instead of using their
+    /// description
+    #[serde(default)]
+    pub check_payee: bool,
+    /// Rounding used when converting movement amounts to Lunch Money's 4
+    /// decimal places
+    #[serde(default)]
+    pub rounding: RoundingMode,
+    /// Movement field used as the Lunch Money external id
+    #[serde(default)]
+    pub external_id_source: ExternalIdSource,
+    /// Movement date used as the Lunch Money transaction date
+    #[serde(default)]
+    pub date_field: DateField,
+    /// Handling of movements Fintoc sends without any date
+    #[serde(default)]
+    pub missing_date: MissingDate,
+    /// Case applied to holder names used as transfer payees
+    #[serde(default)]
+    pub payee_case: PayeeCase,
+    /// When transactions get the raw bank description as `original_name`
+    #[serde(default)]
+    pub original_name: OriginalName,
+    /// Movement fields tried in order for the payee, e.g. `["comment",
+    /// "description"]`
+    #[serde(default = "default_payee_sources")]
+    pub payee_sources: Vec<PayeeSource>,
+    /// Leave out movements whose amount is zero, e.g. informational entries
+    #[serde(default)]
+    pub skip_zero_amount: bool,
+    /// Tags added to every inserted transaction
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Tag inserted transactions with their movement type ("transfer",
+    /// "check" or "other")
+    #[serde(default)]
+    pub movement_type_tag: bool,
+    /// Payee for movements whose payee comes out blank, "Unknown" by default
+    pub empty_payee: Option<String>,
+    /// Sync pending movements as pending transactions. `--include-pending` and
+    /// `--exclude-pending` override it for one run.
+    #[serde(default = "default_true")]
+    pub include_pending: bool,
+    /// Require balance currencies to be known ISO 4217 codes. When off, an
+    /// unknown code is sent to Lunch Money as Fintoc reports it.
+    #[serde(default = "default_true")]
+    pub strict_currency: bool,
+    /// Handling of fetches that end on an empty page after a full one
+    #[serde(default)]
+    pub short_fetch: ShortFetchHandling,
+    /// Find the asset of accounts without a `lunch_money_asset_id` by matching
+    /// the account name with the Lunch Money asset names
+    #[serde(default)]
+    pub match_asset_by_name: bool,
+}
+
+fn default_max_pages() -> usize {
+    1000
+}
+
+fn default_insert_retries() -> u32 {
+    2
+}
+
+fn default_notes_separator() -> String {
+    " | ".to_string()
+}
+
+fn default_payee_sources() -> Vec<PayeeSource> {
+    vec![PayeeSource::Description]
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Resolved number of concurrent requests for each parallel feature. Accounts
+/// are always synced one at a time, though their balances can be fetched
+/// ahead concurrently.
+#[derive(Debug, Clone, Copy)]
+pub struct Concurrency {
+    /// Fintoc movement pages requested at once
+    pub prefetch_pages: usize,
+    /// Lunch Money insert requests in flight at once
+    pub insert_requests: usize,
+    /// Fintoc balances fetched at once
+    pub balance_requests: usize,
+}
+
+/// Assigns `category_id` to movements whose description matches `pattern`.
+#[derive(Debug, Deserialize)]
+pub struct CategoryRule {
+    pub pattern: String,
+    pub category_id: u64,
+}
+
+/// Splits movements whose description matches `pattern` into `installments`
+/// equal parts, one a month from the movement's date, e.g. for purchases paid
+/// in installments. `tag` is added to the split transaction.
+#[derive(Debug, Deserialize)]
+pub struct SplitRule {
+    pub pattern: String,
+    pub installments: u32,
+    pub tag: Option<String>,
+}
+
+/// Connection pool settings for the HTTP client.
+///
+/// A sync is a burst of sequential requests to two hosts (Fintoc and Lunch
+/// Money), so a couple of idle connections per host kept alive for a short
+/// while is enough to reuse them across requests.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct HttpSettings {
+    /// How long an idle connection is kept open, e.g. "30s"
+    pub pool_idle_timeout: String,
+    /// Maximum number of idle connections kept per host
+    pub pool_max_idle_per_host: usize,
+    /// How many times a request failing with a network error, a 5xx or a 429
+    /// is re-sent
+    pub retries: u32,
+    /// Delay before the first retry, doubled on each following one, e.g.
+    /// "500ms"
+    pub retry_backoff: String,
+    /// PEM file with extra root certificates to trust, e.g. a proxy's
+    pub ca_bundle: Option<String>,
+    /// Oldest TLS version accepted: "1.0", "1.1" or "1.2"
+    pub min_tls_version: Option<String>,
+    /// Most requests sent per second to each API host, across all accounts.
+    /// Unlimited when unset.
+    pub requests_per_second: Option<u32>,
+}
+
+impl Default for HttpSettings {
+    fn default() -> Self {
+        Self {
+            pool_idle_timeout: "30s".to_string(),
+            pool_max_idle_per_host: 2,
+            retries: 2,
+            retry_backoff: "500ms".to_string(),
+            ca_bundle: None,
+            min_tls_version: None,
+            requests_per_second: None,
+        }
+    }
+}
+
+fn default_state_file() -> String {
+    "sync_state.json".to_string()
+}
+
+fn default_dead_letter_file() -> String {
+    "dead_letter.jsonl".to_string()
+}
+
+fn default_written_fields_file() -> String {
+    "written_fields.json".to_string()
+}
+
+/// Directory under the user's config directory that holds `config.toml`.
+const CONFIG_DIR: &str = "lunchmoney-fintoc-sync";
+
+impl AppConfig {
+    /// Finds the config files to load: `explicit` (from `--config`) when given,
+    /// else the first that exists of `$XDG_CONFIG_HOME/lunchmoney-fintoc-sync/
+    /// config.toml`, `~/.config/lunchmoney-fintoc-sync/config.toml` and
+    /// `./config.toml`. When none exists, `./config.toml` is returned so the
+    /// error names it.
+    pub fn locate(explicit: &[String]) -> Vec<String> {
+        if !explicit.is_empty() {
+            for path in explicit {
+                debug_log!("Using config file {} from --config", path);
+            }
+            return explicit.to_vec();
+        }
+
+        let config_home = |var: &str, suffix: &str| {
+            std::env::var_os(var)
+                .filter(|dir| !dir.is_empty())
+                .map(|dir| Path::new(&dir).join(suffix))
+        };
+        let candidates = [
+            config_home("XDG_CONFIG_HOME", ""),
+            config_home("HOME", ".config"),
+        ]
+        .into_iter()
+        .flatten()
+        .map(|dir| dir.join(CONFIG_DIR).join("config.toml"))
+        .chain([PathBuf::from("config.toml")]);
+
+        for candidate in candidates {
+            if candidate.is_file() {
+                debug_log!("Using config file {}", candidate.display());
+                return vec![candidate.to_string_lossy().into_owned()];
+            }
+            debug_log!("No config file at {}", candidate.display());
+        }
+
+        vec!["config.toml".to_string()]
+    }
+
+    /// Loads the configuration from a TOML file.
+    pub fn from_file(path: &str) -> Result<Self> {
+        Self::from_file_with_overrides(path, &[])
+    }
+
+    /// Loads the configuration from a TOML file, then applies `key=value`
+    /// overrides on top, e.g. `sync_settings.default_start_from=90d`.
+    pub fn from_file_with_overrides(path: &str, overrides: &[String]) -> Result<Self> {
+        Self::from_files_with_overrides(&[path.to_string()], overrides)
+    }
+
+    /// Loads the configuration from several TOML files layered in order, later
+    /// files overriding values of earlier ones, then applies `key=value`
+    /// overrides on top. `banks` are combined across files instead, a bank
+    /// replacing any of the same name from an earlier file.
+    pub fn from_files_with_overrides(paths: &[String], overrides: &[String]) -> Result<Self> {
+        let mut layered = ::config::Config::builder();
+        for path in paths {
+            layered = layered.add_source(::config::File::with_name(path));
+        }
+        if paths.len() > 1 {
+            layered = layered.set_override("banks", Self::combined_banks(paths)?)?;
+        }
+
+        // Overrides go on top of the combined banks, so `banks[0]...` keys
+        // address the final list.
+        let mut builder = ::config::Config::builder().add_source(layered.build()?);
+        let mut keys = Vec::new();
+        for entry in overrides {
+            let (key, value) = match entry.split_once('=') {
+                Some((key, value)) if !key.trim().is_empty() => (key.trim(), value),
+                _ => bail!("Invalid config override {:?}, expected key=value", entry),
+            };
+            builder = builder.set_override(key, value)?;
+            keys.push(key);
+        }
+
+        // Unknown keys are ignored when deserializing, which is fine for the
+        // files but would silently drop a misspelled override
+        let mut ignored = Vec::new();
+        let config = serde_ignored::deserialize(builder.build()?, |path| {
+            ignored.push(path.to_string().to_lowercase())
+        })?;
+        for key in keys {
+            // `banks[1].name` is the path `banks.1.name`
+            let path = key.replace('[', ".").replace(']', "").to_lowercase();
+            if ignored
+                .iter()
+                .any(|unknown| path == *unknown || path.starts_with(&format!("{}.", unknown)))
+            {
+                bail!("Unknown config key {:?} in override", key);
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Banks of every file in `paths`, in order. A bank replaces any earlier
+    /// one with the same name.
+    fn combined_banks(paths: &[String]) -> Result<Vec<::config::Value>> {
+        #[derive(Deserialize)]
+        struct BankName {
+            name: String,
+        }
+
+        let mut combined: Vec<(String, ::config::Value)> = Vec::new();
+        for path in paths {
+            let file = ::config::Config::builder()
+                .add_source(::config::File::with_name(path))
+                .build()?;
+            let banks = match file.get_array("banks") {
+                Ok(banks) => banks,
+                Err(::config::ConfigError::NotFound(_)) => Vec::new(),
+                Err(err) => {
+                    return Err(err)
+                        .with_context(|| format!("Invalid banks in config file {}", path))
+                }
+            };
+            for bank in banks {
+                let BankName { name } = bank
+                    .clone()
+                    .try_deserialize()
+                    .with_context(|| format!("Invalid banks in config file {}", path))?;
+                if combined.iter().any(|(earlier, _)| *earlier == name) {
+                    debug_log!("Bank {} from {} replaces an earlier one", name, path);
+                    combined.retain(|(earlier, _)| *earlier != name);
+                }
+                combined.push((name, bank));
+            }
+        }
+        Ok(combined.into_iter().map(|(_, bank)| bank).collect())
+    }
+
+    /// Banks matching `bank_name`, or all enabled ones when it's empty.
+    pub fn select_banks(&self, bank_name: &str) -> Vec<&Bank> {
+        self.banks
+            .iter()
+            .filter(|b| {
+                if bank_name.is_empty() {
+                    b.enabled
+                } else {
+                    b.name == bank_name
+                }
+            })
+            .collect()
+    }
+
+    /// Accounts matching the given bank and account names. Empty names match
+    /// everything that is enabled.
+    pub fn select_accounts(&self, bank_name: &str, account_name: &str) -> Vec<(&Bank, &Account)> {
+        self.select_banks(bank_name)
+            .into_iter()
+            .flat_map(|bank| {
+                bank.accounts
+                    .iter()
+                    .filter(move |a| {
+                        if account_name.is_empty() {
+                            a.enabled
+                        } else {
+                            a.name == account_name
+                        }
+                    })
+                    .map(move |account| (bank, account))
+            })
+            .collect()
+    }
+
+    /// Lunch Money token used for an account: its own, else its bank's, else
+    /// the global one.
+    pub fn lunch_money_token<'a>(&'a self, bank: &'a Bank, account: &'a Account) -> &'a str {
+        account
+            .lunch_money_api_token
+            .as_deref()
+            .or(bank.lunch_money_api_token.as_deref())
+            .unwrap_or(&self.tokens.lunch_money_api_token)
+    }
+
+    /// Lunch Money token of the account syncing into `asset_id` (directly or
+    /// through `currency_assets`), or the global one if none does.
+    pub fn lunch_money_token_for_asset(&self, asset_id: u64) -> &str {
+        self.banks
+            .iter()
+            .flat_map(|bank| bank.accounts.iter().map(move |account| (bank, account)))
+            .find(|(_, account)| {
+                account.asset_id().ok() == Some(asset_id)
+                    || account.currency_assets.keys().any(|currency| {
+                        account.currency_asset_id(currency).ok().flatten() == Some(asset_id)
+                    })
+            })
+            .map(|(bank, account)| self.lunch_money_token(bank, account))
+            .unwrap_or(&self.tokens.lunch_money_api_token)
+    }
+
+    /// Every distinct Lunch Money token in the config, the global one first.
+    pub fn lunch_money_tokens(&self) -> Vec<&str> {
+        let mut tokens = vec![self.tokens.lunch_money_api_token.as_str()];
+        for bank in &self.banks {
+            for account in &bank.accounts {
+                let token = self.lunch_money_token(bank, account);
+                if !tokens.contains(&token) {
+                    tokens.push(token);
+                }
+            }
+        }
+        tokens
+    }
+
+    /// Replaces every configured token in `text`, so it can be sent outside.
+    pub fn redact_secrets(&self, text: &str) -> String {
+        self.secrets().fold(text.to_string(), |text, secret| {
+            text.replace(secret.as_str(), "[redacted]")
+        })
+    }
+
+    /// Fintoc account ids and Lunch Money asset ids shared by several enabled
+    /// accounts, one line each. Such accounts would be synced twice.
+    pub fn duplicate_ids(&self) -> Vec<String> {
+        let mut fintoc_ids: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+        let mut asset_ids: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+        for (bank, account) in self.select_accounts("", "") {
+            let name = format!("{} - {}", bank.name, account.name);
+            fintoc_ids
+                .entry(&account.fintoc_account_id)
+                .or_default()
+                .push(name.clone());
+            // Unresolved ids are matched by name, they aren't shared
+            if !account.lunch_money_asset_id.is_empty() {
+                asset_ids
+                    .entry(&account.lunch_money_asset_id)
+                    .or_default()
+                    .push(name);
+            }
+        }
+
+        let duplicates = |kind: &str, ids: BTreeMap<&str, Vec<String>>| {
+            ids.into_iter()
+                .filter(|(_, accounts)| accounts.len() > 1)
+                .map(|(id, accounts)| format!("{} {} is used by {}", kind, id, accounts.join(", ")))
+                .collect::<Vec<_>>()
+        };
+        let mut problems = duplicates("Fintoc account", fintoc_ids);
+        problems.extend(duplicates("Lunch Money asset", asset_ids));
+        problems
+    }
+
+    /// Every configured token.
+    pub fn secrets(&self) -> impl Iterator<Item = &String> {
+        [
+            &self.tokens.fintoc_secret_token,
+            &self.tokens.lunch_money_api_token,
+        ]
+        .into_iter()
+        .chain(self.banks.iter().map(|bank| &bank.link_token))
+        .chain(
+            self.banks
+                .iter()
+                .filter_map(|bank| bank.lunch_money_api_token.as_ref()),
+        )
+        .chain(
+            self.banks
+                .iter()
+                .flat_map(|bank| &bank.accounts)
+                .filter_map(|account| account.lunch_money_api_token.as_ref()),
+        )
+        .filter(|secret| !secret.is_empty())
+    }
+
+    pub fn credentials(&self, bank: &Bank, account: &Account) -> AccountCredentials {
+        AccountCredentials {
+            account_id: account.fintoc_account_id.clone(),
+            secret_token: self.tokens.fintoc_secret_token.clone(),
+            link_token: bank.link_token.clone(),
+        }
+    }
+}
+
+impl Bank {
+    /// The configured account for a Fintoc account id, if any.
+    pub fn account_by_fintoc_id(&self, fintoc_account_id: &str) -> Option<&Account> {
+        self.accounts
+            .iter()
+            .find(|account| account.fintoc_account_id == fintoc_account_id)
+    }
+}
+
+impl Account {
+    /// Insert options this account overrides, e.g. `check_for_recurring off`.
+    pub fn insert_overrides(&self) -> Vec<String> {
+        [
+            ("apply_rules", self.apply_rules),
+            ("check_for_recurring", self.check_for_recurring),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| {
+            value.map(|value| format!("{} {}", name, if value { "on" } else { "off" }))
+        })
+        .collect()
+    }
+
+    pub fn asset_id(&self) -> Result<u64> {
+        if self.lunch_money_asset_id.is_empty() {
+            bail!(
+                "Account {} has no lunch_money_asset_id. Set one, or turn on match_asset_by_name.",
+                self.name
+            );
+        }
+        self.lunch_money_asset_id.parse().with_context(|| {
+            format!(
+                "Invalid lunch_money_asset_id {:?} for account {}",
+                self.lunch_money_asset_id, self.name
+            )
+        })
+    }
+
+    /// Why `balance` is outside the account's `min_balance`/`max_balance`, if
+    /// it is.
+    pub fn implausible_balance(&self, balance: f64) -> Option<String> {
+        match (self.min_balance, self.max_balance) {
+            (Some(min), _) if balance < min => {
+                Some(format!("{} is below min_balance {}", balance, min))
+            }
+            (_, Some(max)) if balance > max => {
+                Some(format!("{} is above max_balance {}", balance, max))
+            }
+            _ => None,
+        }
+    }
+
+    /// The asset that movements in `currency` are routed to when it differs
+    /// from the account's own currency, if one is configured.
+    pub fn currency_asset_id(&self, currency: &str) -> Result<Option<u64>> {
+        let asset_id = self
+            .currency_assets
+            .iter()
+            .find(|(code, _)| code.eq_ignore_ascii_case(currency))
+            .map(|(_, asset_id)| asset_id);
+
+        match asset_id {
+            Some(asset_id) => asset_id.parse().map(Some).with_context(|| {
+                format!(
+                    "Invalid {} asset id {:?} in currency_assets for account {}",
+                    currency.to_uppercase(),
+                    asset_id,
+                    self.name
+                )
+            }),
+            None => Ok(None),
+        }
+    }
+}
+
+impl SyncSettings {
+    /// Insert options for an account's transactions, applying its overrides.
+    pub fn insert_options(&self, account: Option<&Account>) -> InsertOptions {
+        InsertOptions {
+            apply_rules: account
+                .and_then(|a| a.apply_rules)
+                .unwrap_or(self.apply_rules),
+            check_for_recurring: account
+                .and_then(|a| a.check_for_recurring)
+                .unwrap_or(self.check_for_recurring),
+            concurrency: self.concurrency().insert_requests,
+        }
+    }
+
+    /// Resolves the concurrency of each parallel feature: its own setting if
+    /// present, else `concurrency`, else 1.
+    pub fn concurrency(&self) -> Concurrency {
+        let default = self.concurrency.unwrap_or(1);
+        Concurrency {
+            prefetch_pages: self.prefetch_pages.unwrap_or(default),
+            insert_requests: self.insert_concurrency.unwrap_or(default),
+            balance_requests: self.balance_concurrency.unwrap_or(default),
+        }
+    }
+
+    /// The sync window: from `default_start_from` ago until now.
+    pub fn window(&self) -> Result<(DateTime<Utc>, DateTime<Utc>)> {
+        Self::window_from(&self.default_start_from)
+    }
+
+    /// How far back a bank's window starts: its own `default_start_from`, or
+    /// the global one.
+    pub fn start_from<'a>(&'a self, bank: &'a Bank) -> &'a str {
+        bank.default_start_from
+            .as_deref()
+            .unwrap_or(&self.default_start_from)
+    }
+
+    /// The sync window of a bank's accounts.
+    pub fn window_for(&self, bank: &Bank) -> Result<(DateTime<Utc>, DateTime<Utc>)> {
+        Self::window_from(self.start_from(bank))
+            .with_context(|| format!("Invalid window for bank {}", bank.name))
+    }
+
+    fn window_from(start_from: &str) -> Result<(DateTime<Utc>, DateTime<Utc>)> {
+        let start_from = humantime::parse_duration(start_from)
+            .with_context(|| format!("Invalid default_start_from {:?}", start_from))?;
+
+        let now = Local::now();
+        let end_date: DateTime<Utc> = now.into();
+        let start_date: DateTime<Utc> = (now - chrono::Duration::from_std(start_from)?).into();
+
+        Ok((start_date, end_date))
+    }
+
+    pub fn fetch_options(&self) -> FetchOptions {
+        FetchOptions {
+            max_pages: self.max_pages,
+            prefetch_pages: self.concurrency().prefetch_pages,
+            max_movements: self.max_movements,
+        }
+    }
+
+    pub fn conversion_options(&self, account: &Account) -> Result<ConversionOptions> {
+        let category_rules = self
+            .category_rules
+            .iter()
+            .map(|rule| {
+                regex::Regex::new(&rule.pattern)
+                    .map(|pattern| (pattern, rule.category_id))
+                    .with_context(|| format!("Invalid category rule pattern {:?}", rule.pattern))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let split_rules = self
+            .split_rules
+            .iter()
+            .map(|rule| {
+                if rule.installments < 2 {
+                    bail!(
+                        "Split rule {:?} needs at least 2 installments, got {}",
+                        rule.pattern,
+                        rule.installments
+                    );
+                }
+                let pattern = regex::Regex::new(&rule.pattern)
+                    .with_context(|| format!("Invalid split rule pattern {:?}", rule.pattern))?;
+                Ok(InstallmentSplit {
+                    pattern,
+                    installments: rule.installments,
+                    tag: rule.tag.clone(),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let junk_payee_pattern = account
+            .junk_payee_pattern
+            .as_deref()
+            .map(regex::Regex::new)
+            .transpose()
+            .with_context(|| format!("Invalid junk_payee_pattern for account {}", account.name))?;
+
+        Ok(ConversionOptions {
+            notes_template: self.notes_template.clone(),
+            notes_fields: self.notes_fields.clone(),
+            notes_separator: self.notes_separator.clone(),
+            category_rules,
+            split_rules,
+            default_category_id: account.default_category_id,
+            amount_scale: account.amount_scale,
+            rounding: self.rounding,
+            external_id_source: self.external_id_source,
+            date_field: self.date_field,
+            missing_date: self.missing_date,
+            payee_case: self.payee_case,
+            original_name: self.original_name,
+            tags: self.tags.clone(),
+            movement_type_tag: self.movement_type_tag,
+            check_payee: self.check_payee,
+            flip_sign: account.flip_sign,
+            empty_payee: self.empty_payee.clone(),
+            default_payee: account.default_payee.clone(),
+            junk_payee_pattern,
+            payee_sources: account
+                .payee_sources
+                .clone()
+                .unwrap_or_else(|| self.payee_sources.clone()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account_with_bounds(min_balance: Option<f64>, max_balance: Option<f64>) -> Account {
+        serde_json::from_value(serde_json::json!({
+            "name": "Cuenta Corriente",
+            "fintoc_account_id": "acc_1",
+            "lunch_money_asset_id": "1",
+            "type": "Checking",
+            "min_balance": min_balance,
+            "max_balance": max_balance,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn balance_below_the_floor_is_implausible() {
+        let account = account_with_bounds(Some(-500_000.0), Some(50_000_000.0));
+        assert_eq!(
+            account.implausible_balance(-600_000.0).as_deref(),
+            Some("-600000 is below min_balance -500000")
+        );
+        assert_eq!(account.implausible_balance(-500_000.0), None);
+    }
+
+    #[test]
+    fn balance_above_the_ceiling_is_implausible() {
+        let account = account_with_bounds(Some(-500_000.0), Some(50_000_000.0));
+        assert_eq!(
+            account.implausible_balance(900_000_000.0).as_deref(),
+            Some("900000000 is above max_balance 50000000")
+        );
+        assert_eq!(account.implausible_balance(50_000_000.0), None);
+    }
+
+    #[test]
+    fn balances_are_plausible_without_bounds() {
+        let account = account_with_bounds(None, None);
+        assert_eq!(account.implausible_balance(f64::MAX), None);
+        assert_eq!(account.implausible_balance(f64::MIN), None);
+    }
+
+    fn write_config(name: &str, contents: &str) -> String {
+        let path = std::env::temp_dir().join(format!(
+            "lunchmoney-fintoc-{}-{}.toml",
+            name,
+            std::process::id()
+        ));
+        std::fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    const COMMON: &str = r#"
+[tokens]
+fintoc_secret_token = "sk_test"
+lunch_money_api_token = "lm_test"
+
+[sync_settings]
+default_start_from = "30d"
+
+[[banks]]
+name = "Banco A"
+link_token = "link_a"
+accounts = []
+"#;
+
+    const OTHER: &str = r#"
+[sync_settings]
+default_start_from = "60d"
+
+[[banks]]
+name = "Banco B"
+link_token = "link_b"
+accounts = []
+"#;
+
+    #[test]
+    fn banks_from_several_files_are_combined_before_overrides() {
+        let common = write_config("common", COMMON);
+        let other = write_config("other", OTHER);
+
+        let config = AppConfig::from_files_with_overrides(
+            &[common.clone(), other.clone()],
+            &["banks[1].link_token=link_override".to_string()],
+        );
+        std::fs::remove_file(&common).unwrap();
+        std::fs::remove_file(&other).unwrap();
+        let config = config.unwrap();
+
+        let banks: Vec<_> = config
+            .banks
+            .iter()
+            .map(|bank| (bank.name.as_str(), bank.link_token.as_str()))
+            .collect();
+        assert_eq!(banks, [("Banco A", "link_a"), ("Banco B", "link_override")]);
+        assert_eq!(config.sync_settings.default_start_from, "60d");
+    }
+
+    #[test]
+    fn a_later_bank_replaces_one_with_the_same_name() {
+        let common = write_config("replaced-common", COMMON);
+        let other = write_config("replaced-other", &OTHER.replace("Banco B", "Banco A"));
+
+        let config = AppConfig::from_files_with_overrides(&[common.clone(), other.clone()], &[]);
+        std::fs::remove_file(&common).unwrap();
+        std::fs::remove_file(&other).unwrap();
+        let config = config.unwrap();
+
+        assert_eq!(config.banks.len(), 1);
+        assert_eq!(config.banks[0].link_token, "link_b");
+    }
+
+    #[test]
+    fn overrides_replace_file_values() {
+        let path = write_config("override", COMMON);
+        let config = AppConfig::from_file_with_overrides(
+            &path,
+            &["sync_settings.default_start_from=90d".to_string()],
+        );
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.unwrap().sync_settings.default_start_from, "90d");
+    }
+
+    #[test]
+    fn overrides_of_unknown_keys_are_rejected() {
+        let path = write_config("unknown-override", COMMON);
+        let misspelled = AppConfig::from_file_with_overrides(
+            &path,
+            &["sync_settings.defualt_start_from=90d".to_string()],
+        );
+        let unknown_in_bank =
+            AppConfig::from_file_with_overrides(&path, &["banks[0].chunk_size=10".to_string()]);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            misspelled.unwrap_err().to_string(),
+            "Unknown config key \"sync_settings.defualt_start_from\" in override"
+        );
+        assert!(unknown_in_bank
+            .unwrap_err()
+            .to_string()
+            .contains("banks[0].chunk_size"));
+    }
+}
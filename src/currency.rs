@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use colored::*;
+use currency_rs::{Currency, CurrencyOpts};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+/// Decimal places Lunch Money keeps for transaction amounts.
+pub const LUNCH_MONEY_DECIMALS: u32 = 4;
+
+/// How the tool handles a currency: the minor-unit exponent Fintoc amounts are
+/// scaled by, and how amounts are displayed.
+#[derive(Debug, Serialize)]
+pub struct CurrencySpec {
+    /// Uppercase ISO 4217 code
+    pub code: &'static str,
+    /// Fintoc amounts are integers in 10^-exponent units of the currency
+    pub exponent: u32,
+    pub symbol: &'static str,
+    /// Decimal places shown when displaying amounts
+    pub precision: i64,
+}
+
+/// Currencies that can be converted without an explicit `amount_scale`.
+pub const SUPPORTED_CURRENCIES: &[CurrencySpec] = &[
+    CurrencySpec {
+        code: "CLP",
+        exponent: 0,
+        symbol: "$",
+        precision: 0,
+    },
+    CurrencySpec {
+        code: "USD",
+        exponent: 2,
+        symbol: "$",
+        precision: 2,
+    },
+    CurrencySpec {
+        code: "EUR",
+        exponent: 2,
+        symbol: "€",
+        precision: 2,
+    },
+];
+
+/// How a currency is displayed, overriding its default symbol and precision.
+/// Set per currency code under `[currency_display]`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct DisplayFormat {
+    pub symbol: Option<String>,
+    /// Decimal places shown
+    pub precision: Option<u32>,
+}
+
+lazy_static! {
+    /// Display overrides by uppercase currency code
+    static ref DISPLAY_FORMATS: RwLock<HashMap<String, DisplayFormat>> =
+        RwLock::new(HashMap::new());
+}
+
+/// Sets the display overrides used by `format_amount_plain`, keyed by
+/// (case-insensitive) currency code.
+pub fn set_display_formats(formats: &HashMap<String, DisplayFormat>) {
+    *DISPLAY_FORMATS.write().unwrap() = formats
+        .iter()
+        .map(|(code, format)| (code.to_uppercase(), format.clone()))
+        .collect();
+}
+
+/// Looks up a supported currency by its (case-insensitive) ISO code.
+pub fn find(code: &str) -> Option<&'static CurrencySpec> {
+    SUPPORTED_CURRENCIES
+        .iter()
+        .find(|spec| spec.code.eq_ignore_ascii_case(code))
+}
+
+/// Converts an amount in minor units to major units, e.g. 1050 with exponent 2
+/// becomes 10.5.
+pub fn to_major_units(minor_units: f64, exponent: u32) -> f64 {
+    minor_units / 10f64.powi(exponent as i32)
+}
+
+/// How to round amounts with more decimals than Lunch Money keeps.
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RoundingMode {
+    /// Halves round away from zero, like most bank statements
+    #[default]
+    HalfUp,
+    /// Halves round to the nearest even digit
+    Bankers,
+    /// Extra decimals are dropped
+    Truncate,
+}
+
+/// Converts an amount in minor units to major units with at most `decimals`
+/// decimal places, rounding the dropped digits with `mode`. Rounding is done on
+/// the integer amount, so the result doesn't depend on float representation.
+pub fn to_major_units_rounded(
+    minor_units: i64,
+    exponent: u32,
+    decimals: u32,
+    mode: RoundingMode,
+) -> f64 {
+    if exponent <= decimals {
+        return to_major_units(minor_units as f64, exponent);
+    }
+
+    let divisor = 10i64.pow(exponent - decimals);
+    let quotient = minor_units / divisor;
+    let remainder = (minor_units % divisor).abs();
+    let away_from_zero = match mode {
+        RoundingMode::Truncate => false,
+        RoundingMode::HalfUp => remainder * 2 >= divisor,
+        RoundingMode::Bankers => {
+            remainder * 2 > divisor || (remainder * 2 == divisor && quotient % 2 != 0)
+        }
+    };
+    let rounded = if away_from_zero {
+        quotient + minor_units.signum()
+    } else {
+        quotient
+    };
+
+    to_major_units(rounded as f64, decimals)
+}
+
+/// Formats an amount with the currency's symbol and precision, from its
+/// `[currency_display]` override or its defaults. Unknown currencies without
+/// an override use plain formatting.
+pub fn format_amount_plain(amount: f64, code: Option<&str>) -> String {
+    let spec = code.and_then(find);
+    let custom = code.and_then(|code| {
+        DISPLAY_FORMATS
+            .read()
+            .unwrap()
+            .get(&code.to_uppercase())
+            .cloned()
+    });
+
+    let opt = if spec.is_none() && custom.is_none() {
+        CurrencyOpts::default()
+    } else {
+        let mut opt = CurrencyOpts::new().set_from_cents(false);
+        let symbol = custom
+            .as_ref()
+            .and_then(|custom| custom.symbol.clone())
+            .or(spec.map(|spec| spec.symbol.to_string()));
+        if let Some(symbol) = symbol {
+            opt = opt.set_symbol(symbol);
+        }
+        let precision = custom
+            .as_ref()
+            .and_then(|custom| custom.precision)
+            .map(i64::from)
+            .or(spec.map(|spec| spec.precision));
+        if let Some(precision) = precision {
+            opt = opt.set_precision(precision);
+        }
+        opt
+    };
+
+    Currency::new_float(amount, Some(opt)).format()
+}
+
+/// Formats an amount like `format_amount_plain`, green when positive and red
+/// when negative.
+pub fn format_amount(amount: f64, code: Option<&str>) -> ColoredString {
+    let formatted = format_amount_plain(amount, code);
+
+    if amount >= 0.0 {
+        formatted.green()
+    } else {
+        formatted.red()
+    }
+}
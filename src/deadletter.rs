@@ -0,0 +1,77 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use crate::types::lunchmoney::Transaction;
+
+/// Appends transactions that failed to insert to the dead-letter file, one JSON
+/// object per line.
+pub fn append(path: &Path, transactions: &[Transaction]) -> Result<()> {
+    if transactions.is_empty() {
+        return Ok(());
+    }
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open dead-letter file {}", path.display()))?;
+
+    for transaction in transactions {
+        writeln!(file, "{}", serde_json::to_string(transaction)?)?;
+    }
+
+    Ok(())
+}
+
+/// Reads every transaction stored in the dead-letter file. A missing file means
+/// there is nothing to retry.
+pub fn read(path: &Path) -> Result<Vec<Transaction>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => {
+            return Err(err)
+                .with_context(|| format!("Failed to open dead-letter file {}", path.display()))
+        }
+    };
+
+    let mut transactions = Vec::new();
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let transaction = serde_json::from_str(&line).with_context(|| {
+            format!(
+                "Invalid transaction on line {} of {}",
+                number + 1,
+                path.display()
+            )
+        })?;
+        transactions.push(transaction);
+    }
+
+    Ok(transactions)
+}
+
+/// Replaces the contents of the dead-letter file with `transactions`, removing
+/// the file entirely once nothing is left to retry.
+pub fn replace(path: &Path, transactions: &[Transaction]) -> Result<()> {
+    if transactions.is_empty() {
+        return match fs::remove_file(path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        };
+    }
+
+    let mut contents = String::new();
+    for transaction in transactions {
+        contents.push_str(&serde_json::to_string(transaction)?);
+        contents.push('\n');
+    }
+    fs::write(path, contents)
+        .with_context(|| format!("Failed to write dead-letter file {}", path.display()))
+}
@@ -0,0 +1,23 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Turns debug output on or off, as set by the `--debug` flag.
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Prints a dimmed message to stderr when `--debug` is set.
+macro_rules! debug_log {
+    ($($arg:tt)*) => {
+        if $crate::debug::enabled() {
+            $crate::output::diagnostic(colored::Colorize::dimmed(format!($($arg)*).as_str()));
+        }
+    };
+}
+
+pub(crate) use debug_log;
@@ -0,0 +1,236 @@
+//! Duplicate detection for banks where Fintoc movement ids are not stable.
+//!
+//! Besides the `external_id`, a transaction can be identified by a composite
+//! key built from its date, amount and bank description:
+//!
+//! ```text
+//! <date as YYYY-MM-DD>|<amount with 4 decimals>|<normalized description>
+//! ```
+//!
+//! The description is normalized by lowercasing it, replacing every character
+//! that isn't a letter or digit with a space, collapsing runs of whitespace
+//! into a single space and trimming both ends. For example a `-1500` CLP
+//! movement on 2024-05-01 described as `"COMPRA  NACIONAL*Líder Express"`
+//! has the key `2024-05-01|-1500.0000|compra nacional líder express`.
+
+use std::collections::HashMap;
+
+use chrono::NaiveDate;
+
+use crate::types::lunchmoney::{Amount, FetchedTransaction, Transaction};
+
+/// Normalizes a bank description as described in the module docs.
+pub fn normalize_description(description: &str) -> String {
+    description
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Builds the composite key for a transaction.
+pub fn composite_key(date: NaiveDate, amount: Amount, description: &str) -> String {
+    format!(
+        "{}|{}|{}",
+        date.format("%Y-%m-%d"),
+        amount,
+        normalize_description(description)
+    )
+}
+
+impl Transaction {
+    /// Composite key from the date, amount and raw bank description (falling
+    /// back to the payee).
+    pub fn composite_key(&self) -> String {
+        let description = self
+            .original_name
+            .as_deref()
+            .or(self.payee.as_deref())
+            .unwrap_or_default();
+        composite_key(self.date.date_naive(), self.amount, description)
+    }
+}
+
+impl FetchedTransaction {
+    pub fn composite_key(&self) -> String {
+        let description = self
+            .original_name
+            .as_deref()
+            .or(self.payee.as_deref())
+            .unwrap_or_default();
+        composite_key(self.date, self.amount, description)
+    }
+}
+
+/// Transactions already in Lunch Money, indexed for duplicate lookups.
+///
+/// Identical purchases on the same day share a composite key, so keys are
+/// counted: each existing transaction matches at most one new one.
+#[derive(Debug, Default)]
+pub struct ExistingTransactions {
+    /// Composite key of each existing transaction, by `external_id`
+    external_ids: HashMap<String, String>,
+    composite_keys: HashMap<String, usize>,
+}
+
+impl ExistingTransactions {
+    pub fn new(transactions: &[FetchedTransaction]) -> Self {
+        let mut existing = Self::default();
+        for transaction in transactions {
+            let key = transaction.composite_key();
+            if let Some(external_id) = &transaction.external_id {
+                existing
+                    .external_ids
+                    .insert(external_id.clone(), key.clone());
+            }
+            *existing.composite_keys.entry(key).or_default() += 1;
+        }
+        existing
+    }
+
+    /// Which of `transactions` are already present, matching by `external_id`
+    /// first and by composite key when that misses. Transactions matched by
+    /// `external_id` use up their existing counterpart before any composite
+    /// key is looked at.
+    pub fn duplicates(&self, transactions: &[Transaction]) -> Vec<bool> {
+        let mut unclaimed = self.composite_keys.clone();
+        let mut claim = |key: &str| match unclaimed.get_mut(key) {
+            Some(count) if *count > 0 => {
+                *count -= 1;
+                true
+            }
+            _ => false,
+        };
+
+        let mut duplicates = transactions
+            .iter()
+            .map(|transaction| {
+                let key = transaction
+                    .external_id
+                    .as_ref()
+                    .and_then(|external_id| self.external_ids.get(external_id));
+                if let Some(key) = key {
+                    claim(key);
+                }
+                key.is_some()
+            })
+            .collect::<Vec<_>>();
+
+        for (transaction, duplicate) in transactions.iter().zip(&mut duplicates) {
+            if !*duplicate {
+                *duplicate = claim(&transaction.composite_key());
+            }
+        }
+        duplicates
+    }
+
+    /// Whether a transaction with the same `external_id` is present, which is
+    /// how Lunch Money itself detects duplicates on insert.
+    pub fn contains_external_id(&self, transaction: &Transaction) -> bool {
+        transaction
+            .external_id
+            .as_ref()
+            .is_some_and(|external_id| self.external_ids.contains_key(external_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::fintoc::{ConversionOptions, Movement, MovementType};
+
+    fn movement(id: &str, amount: i64) -> Movement {
+        Movement {
+            id: id.to_string(),
+            object: "movement".to_string(),
+            amount,
+            post_date: Some("2024-05-01T12:00:00Z".parse().unwrap()),
+            description: "COMPRA  NACIONAL*Líder Express".to_string(),
+            transaction_date: None,
+            currency: "CLP".to_string(),
+            reference_id: None,
+            movement_type: MovementType::Other,
+            pending: false,
+            recipient_account: None,
+            sender_account: None,
+            comment: None,
+        }
+    }
+
+    fn fetched(external_id: &str) -> FetchedTransaction {
+        serde_json::from_value(serde_json::json!({
+            "id": 1,
+            "date": "2024-05-01",
+            "payee": "Líder Express",
+            "amount": "-1500.0000",
+            "currency": "clp",
+            "external_id": external_id,
+            "original_name": "COMPRA  NACIONAL*Líder Express",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn key_matches_the_documented_example() {
+        let date = NaiveDate::from_ymd_opt(2024, 5, 1).unwrap();
+        assert_eq!(
+            composite_key(date, Amount(-1500.0), "COMPRA  NACIONAL*Líder Express"),
+            "2024-05-01|-1500.0000|compra nacional líder express"
+        );
+    }
+
+    #[test]
+    fn normalization_ignores_case_punctuation_and_spacing() {
+        assert_eq!(
+            normalize_description("  Transf.  a JUAN-PÉREZ\t#123 "),
+            "transf a juan pérez 123"
+        );
+    }
+
+    #[test]
+    fn movement_whose_id_changed_is_found_by_composite_key() {
+        let existing = ExistingTransactions::new(&[fetched("mov_old")]);
+        let transaction = movement("mov_new", -1500)
+            .to_lunchmoney_transaction(1, &ConversionOptions::default())
+            .unwrap();
+
+        assert!(!existing.contains_external_id(&transaction));
+        assert_eq!(existing.duplicates(&[transaction]), [true]);
+    }
+
+    #[test]
+    fn different_amount_is_not_a_duplicate() {
+        let existing = ExistingTransactions::new(&[fetched("mov_old")]);
+        let transaction = movement("mov_new", -1600)
+            .to_lunchmoney_transaction(1, &ConversionOptions::default())
+            .unwrap();
+
+        assert_eq!(existing.duplicates(&[transaction]), [false]);
+    }
+
+    #[test]
+    fn identical_purchases_on_the_same_day_are_told_apart() {
+        let options = ConversionOptions::default();
+        let first = movement("mov_1", -1500)
+            .to_lunchmoney_transaction(1, &options)
+            .unwrap();
+        let second = movement("mov_2", -1500)
+            .to_lunchmoney_transaction(1, &options)
+            .unwrap();
+        assert_eq!(first.composite_key(), second.composite_key());
+
+        // Only the first one is in Lunch Money, under an id that has since changed
+        let existing = ExistingTransactions::new(&[fetched("mov_old")]);
+        assert_eq!(
+            existing.duplicates(&[first.clone(), second.clone()]),
+            [true, false]
+        );
+
+        // Only the second one is in Lunch Money, under its own id
+        let existing = ExistingTransactions::new(&[fetched("mov_2")]);
+        assert_eq!(existing.duplicates(&[first, second]), [false, true]);
+    }
+}
@@ -0,0 +1,52 @@
+//! Raw Fintoc movements saved to a file by `export`, so `sync --from-file` can
+//! run the conversion and insert pipeline again without calling Fintoc.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{Account, Bank};
+use crate::types::fintoc::Movement;
+
+/// Movements of each exported account, keyed by `"<bank>/<account>"` like the
+/// state file.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct MovementDump {
+    /// Window the movements were fetched for
+    pub start_date: Option<DateTime<Utc>>,
+    pub end_date: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub accounts: BTreeMap<String, Vec<Movement>>,
+}
+
+impl MovementDump {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read movements file {}", path.display()))?;
+
+        serde_json::from_str(&contents)
+            .with_context(|| format!("Invalid movements file {}", path.display()))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("Failed to write movements file {}", path.display()))
+    }
+
+    pub fn insert(&mut self, bank: &Bank, account: &Account, movements: Vec<Movement>) {
+        self.accounts.insert(Self::key(bank, account), movements);
+    }
+
+    /// Takes an account's movements out of the dump, if it has them.
+    pub fn take(&mut self, bank: &Bank, account: &Account) -> Option<Vec<Movement>> {
+        self.accounts.remove(&Self::key(bank, account))
+    }
+
+    fn key(bank: &Bank, account: &Account) -> String {
+        format!("{}/{}", bank.name, account.name)
+    }
+}
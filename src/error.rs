@@ -0,0 +1,116 @@
+//! Machine-readable errors, printed instead of anyhow's text output when
+//! running with `--format json`.
+
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::config::{Account, Bank};
+use crate::http::EndpointError;
+
+/// Account an error happened on, attached as context by the commands that
+/// loop over accounts.
+#[derive(Debug)]
+pub struct AccountContext {
+    pub bank: String,
+    pub account: String,
+}
+
+impl AccountContext {
+    pub fn new(bank: &Bank, account: &Account) -> Self {
+        Self {
+            bank: bank.name.clone(),
+            account: account.name.clone(),
+        }
+    }
+}
+
+impl fmt::Display for AccountContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Failed on account {} - {}", self.bank, self.account)
+    }
+}
+
+/// A sync where some accounts, or backfill windows, failed. When others went
+/// through, the process exits with 2 instead of 1 so schedulers can tell.
+#[derive(Debug)]
+pub struct PartialSync {
+    pub failed: usize,
+    pub total: usize,
+    /// What was counted, "accounts" or "windows"
+    pub unit: &'static str,
+}
+
+impl PartialSync {
+    pub fn exit_code(&self) -> i32 {
+        if self.failed < self.total {
+            2
+        } else {
+            1
+        }
+    }
+}
+
+impl fmt::Display for PartialSync {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} {} failed to sync",
+            self.failed, self.total, self.unit
+        )
+    }
+}
+
+impl std::error::Error for PartialSync {}
+
+/// A failed command, serialized as `{"error": {...}}`.
+#[derive(Debug, Serialize)]
+pub struct ErrorReport {
+    /// "api_error" for unexpected API responses, "network_error" for requests
+    /// that got no response, "error" for everything else
+    pub code: &'static str,
+    /// The full error chain, as shown in text mode
+    pub message: String,
+    /// "bank - account", when the error happened on one
+    pub account: Option<String>,
+    /// Method, host and path of the failed request, if any
+    pub endpoint: Option<String>,
+    /// HTTP status of the failed request, if it got a response
+    pub status: Option<u16>,
+}
+
+impl ErrorReport {
+    /// Builds the report for an error. `message` is the error text, passed in
+    /// so callers can redact it.
+    pub fn new(err: &anyhow::Error, message: String) -> Self {
+        let endpoint = err.downcast_ref::<EndpointError>();
+        let code = match endpoint {
+            Some(EndpointError {
+                status: Some(_), ..
+            }) => "api_error",
+            Some(_) => "network_error",
+            None => "error",
+        };
+
+        Self {
+            code,
+            message,
+            account: err
+                .downcast_ref::<AccountContext>()
+                .map(|context| format!("{} - {}", context.bank, context.account)),
+            endpoint: endpoint.map(|endpoint| endpoint.endpoint.clone()),
+            status: endpoint.and_then(|endpoint| endpoint.status.map(|status| status.as_u16())),
+        }
+    }
+
+    /// Pretty JSON document wrapping the report in an `error` key.
+    pub fn to_json(&self) -> String {
+        #[derive(Serialize)]
+        struct Document<'a> {
+            error: &'a ErrorReport,
+        }
+
+        serde_json::to_string_pretty(&Document { error: self })
+            .unwrap_or_else(|_| format!("{{\"error\": {{\"message\": {:?}}}}}", self.message))
+    }
+}
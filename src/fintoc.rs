@@ -1,138 +1,737 @@
-use anyhow::anyhow;
 use anyhow::bail;
 use anyhow::Context;
 use anyhow::Result;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Days, Utc};
+use colored::*;
+use futures::future::try_join_all;
 use hyper::header::{AUTHORIZATION, CONTENT_TYPE};
-use hyper::{body, Method, Request, StatusCode};
-use rusty_money::iso::Currency;
+use hyper::{body, Method, Request};
+use indicatif::ProgressBar;
+use serde::Serialize;
 use serde_json::Value;
 
-use crate::types::fintoc::Account;
+use crate::config::{AccountType, BalanceSource};
+use crate::currency;
+use crate::http::{self, ApiClient};
+use crate::output;
+use crate::types::fintoc::{Account, Balance};
 use crate::types::fintoc::{AccountCredentials, Movement};
 use crate::types::lunchmoney::Amount;
-use crate::types::HttpsClient;
-use crate::AccountType;
 
-pub async fn fetch_fintoc_movements(
-    client: &HttpsClient,
+/// Movements requested per page. A page with fewer is the last one.
+const PER_PAGE: usize = 300;
+
+/// Base URL of Fintoc's API.
+const API_URL: &str = "https://api.fintoc.com/v1";
+
+/// Limits applied while paging through Fintoc movements.
+#[derive(Debug, Clone)]
+pub struct FetchOptions {
+    /// Stop after this many pages even if Fintoc keeps returning data.
+    pub max_pages: usize,
+    /// How many pages to request concurrently. 1 fetches them one by one.
+    pub prefetch_pages: usize,
+    /// Fail once more movements than this have been fetched, e.g. because of
+    /// a window far larger than intended.
+    pub max_movements: Option<usize>,
+}
+
+/// Movements fetched for an account.
+#[derive(Debug)]
+pub struct FetchedMovements {
+    pub movements: Vec<Movement>,
+    /// Set when the page that ended the fetch was empty although the one
+    /// before it was full. Fintoc's movement list carries no total to check
+    /// against, so this is the only hint that it stopped early.
+    pub ended_on_empty_page: bool,
+}
+
+/// A fetch that ended on an empty page after a full one, and may be missing
+/// movements.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct ShortFetch {
+    pub fetched: usize,
+}
+
+impl FetchedMovements {
+    pub fn short_fetch(&self) -> Option<ShortFetch> {
+        self.ended_on_empty_page.then_some(ShortFetch {
+            fetched: self.movements.len(),
+        })
+    }
+}
+
+/// Formats the `since`/`until` query parameters for a window.
+///
+/// Both ends of the window are inclusive at day granularity: every movement
+/// posted on the start or end day is fetched. `until` is sent as the day after
+/// `end_date`, so the end day is covered even if Fintoc leaves `until` itself
+/// out. If it doesn't, that day's movements are fetched early, and the next
+/// sync finds them by external id.
+pub fn window_params(start_date: DateTime<Utc>, end_date: DateTime<Utc>) -> (String, String) {
+    let until = end_date.date_naive() + Days::new(1);
+    (
+        start_date.format("%Y-%m-%d").to_string(),
+        until.format("%Y-%m-%d").to_string(),
+    )
+}
+
+async fn fetch_movements_page(
+    api_url: &str,
+    client: &ApiClient,
     credentials: &AccountCredentials,
     start_date: DateTime<Utc>,
     end_date: DateTime<Utc>,
+    page: usize,
 ) -> Result<Vec<Movement>> {
+    let (since, until) = window_params(start_date, end_date);
+    let request = Request::builder()
+        .method(Method::GET)
+        .uri(format!(
+            "{}/accounts/{}/movements?link_token={}&since={}&until={}&per_page={}&page={}",
+            api_url, credentials.account_id, credentials.link_token, since, until, PER_PAGE, page
+        ))
+        .header(AUTHORIZATION, credentials.secret_token.clone())
+        .header(CONTENT_TYPE, "application/json")
+        .body(body::Body::empty())
+        .context("Failed to build request")?;
+
+    let bytes = http::send_ok(client, request)
+        .await
+        .context("Failed to get Fintoc transactions")?;
+
+    let data: Value = serde_json::from_slice(&bytes)?;
+
+    let items = match data.as_array() {
+        Some(items) => items,
+        None => bail!("Data is not an array"),
+    };
+
+    // Deserialize the movements
+    let movements = items
+        .iter()
+        .map(|movement| Ok(serde_json::from_value(movement.clone())?))
+        .collect::<Result<_>>()?;
+
+    Ok(movements)
+}
+
+/// Fetches every movement of an account in the window, including those posted
+/// on the days of `start_date` and `end_date` (see `window_params`).
+///
+/// Pages are requested in batches of `prefetch_pages` concurrent requests and
+/// reassembled in order. Fetching stops at the first short or empty page,
+/// discarding any pages after it that were requested in the same batch. An
+/// empty page right after a full one is flagged in the result, since Fintoc
+/// may have stopped early (see `FetchedMovements::ended_on_empty_page`).
+pub async fn fetch_fintoc_movements(
+    client: &ApiClient,
+    credentials: &AccountCredentials,
+    start_date: DateTime<Utc>,
+    end_date: DateTime<Utc>,
+    options: &FetchOptions,
+    progress: Option<&ProgressBar>,
+) -> Result<FetchedMovements> {
+    fetch_movements_from(
+        API_URL,
+        client,
+        credentials,
+        start_date,
+        end_date,
+        options,
+        progress,
+    )
+    .await
+}
+
+/// `fetch_fintoc_movements` from the API at `api_url`.
+async fn fetch_movements_from(
+    api_url: &str,
+    client: &ApiClient,
+    credentials: &AccountCredentials,
+    start_date: DateTime<Utc>,
+    end_date: DateTime<Utc>,
+    options: &FetchOptions,
+    progress: Option<&ProgressBar>,
+) -> Result<FetchedMovements> {
+    let batch_size = options.prefetch_pages.max(1);
+
     // Pagination
     let mut page = 1;
     let mut movements = Vec::new();
+    let mut ended_on_empty_page = false;
 
-    loop {
-        let request = Request::builder()
-            .method(Method::GET)
-            .uri(format!(
-                "https://api.fintoc.com/v1/accounts/{}/movements?link_token={}&since={}&until={}&per_page=300&page={}",
-                credentials.account_id,
-                credentials.link_token,
-                start_date.format("%Y-%m-%d"),
-                end_date.format("%Y-%m-%d"),
-                page
-            ))
-            .header(AUTHORIZATION, credentials.secret_token.clone())
-            .header(CONTENT_TYPE, "application/json")
-            .body(body::Body::empty())
-            .context("Failed to build request")?;
-
-        let response = client.request(request).await?;
-
-        let status = response.status();
-        let bytes = body::to_bytes(response).await?;
-
-        if status != StatusCode::OK {
-            bail!(
-                "Failed to get Fintoc transactions, code {}, err:\n{:#?}",
-                status,
-                bytes
-            );
-        }
-
-        let data: Value = serde_json::from_slice(&bytes)?;
+    'pages: loop {
+        let last_page = (page + batch_size - 1).min(options.max_pages.max(page));
+        let pages = try_join_all((page..=last_page).map(|page| {
+            fetch_movements_page(api_url, client, credentials, start_date, end_date, page)
+        }))
+        .await?;
 
-        if !data.is_array() {
-            bail!("Data is not an array");
-        }
+        for data in pages {
+            let page_len = data.len();
+            movements.extend(data);
 
-        let data = data.as_array().unwrap();
+            if let Some(max_movements) = options.max_movements {
+                if movements.len() > max_movements {
+                    bail!(
+                        "Stopped after fetching {} movements in {} pages, more than max_movements ({}) allows. Narrow the window, e.g. with a shorter default_start_from or --since-last, or raise max_movements.",
+                        movements.len(),
+                        page,
+                        max_movements
+                    );
+                }
+            }
 
-        // Deserialize the movements
-        for movement in data {
-            let movement: Movement = serde_json::from_value(movement.clone())?;
-            movements.push(movement);
-        }
+            if let Some(progress) = progress {
+                progress.set_message(format!(
+                    "Fetched page {} ({} movements so far)",
+                    page,
+                    movements.len()
+                ));
+            }
 
-        if data.is_empty() {
-            break;
-        } else {
+            if page_len < PER_PAGE {
+                // Only requested because the page before it was full
+                ended_on_empty_page = page_len == 0 && page > 1;
+                break 'pages;
+            } else if page >= options.max_pages {
+                output::status(
+                    format!(
+                        "Stopped fetching movements after {} pages ({} movements) without reaching the end. Increase max_pages if this account really has more.",
+                        page,
+                        movements.len()
+                    )
+                    .yellow(),
+                );
+                break 'pages;
+            }
             page += 1;
         }
     }
 
-    Ok(movements)
+    Ok(FetchedMovements {
+        movements,
+        ended_on_empty_page,
+    })
+}
+
+/// Fetches the accounts attached to a Fintoc link, as raw JSON objects.
+pub async fn fetch_link_accounts(
+    client: &ApiClient,
+    secret_token: &str,
+    link_token: &str,
+) -> Result<Vec<Value>> {
+    let request = Request::builder()
+        .method(Method::GET)
+        .uri(format!("{}/links/{}", API_URL, link_token))
+        .header(AUTHORIZATION, secret_token)
+        .header(CONTENT_TYPE, "application/json")
+        .body(body::Body::empty())
+        .context("Failed to build request")?;
+
+    let bytes = http::send_ok(client, request)
+        .await
+        .context("Failed to get Fintoc link")?;
+
+    let link_data: Value = serde_json::from_slice(&bytes)?;
+
+    match link_data.get("accounts").and_then(|a| a.as_array()) {
+        Some(accounts) => Ok(accounts.clone()),
+        None => bail!("No accounts found or invalid response format"),
+    }
+}
+
+/// An account balance as reported by Fintoc, along with the amount we report to
+/// Lunch Money.
+#[derive(Debug)]
+pub struct FintocBalance {
+    /// Balance computed for the account type, in major units
+    pub amount: Amount,
+    /// Uppercase currency code, ISO 4217 unless `strict_currency` is off
+    pub currency: String,
+    /// Fintoc's `available`/`current`/`limit` figures, in minor units
+    pub raw: Balance,
+    /// When Fintoc last refreshed the account from the bank
+    pub refreshed_at: Option<DateTime<Utc>>,
 }
 
 pub async fn fetch_fintoc_balance(
-    client: &HttpsClient,
+    client: &ApiClient,
     credentials: &AccountCredentials,
     account_type: AccountType,
-) -> Result<(Amount, Currency)> {
+    balance_source: Option<BalanceSource>,
+    amount_scale: Option<u32>,
+    strict_currency: bool,
+) -> Result<FintocBalance> {
     let request = Request::builder()
         .method(Method::GET)
         .uri(format!(
-            "https://api.fintoc.com/v1/accounts/{}?link_token={}",
-            credentials.account_id, credentials.link_token,
+            "{}/accounts/{}?link_token={}",
+            API_URL, credentials.account_id, credentials.link_token,
         ))
         .header(AUTHORIZATION, credentials.secret_token.clone())
         .header(CONTENT_TYPE, "application/json")
         .body(body::Body::empty())
         .context("Failed to build request")?;
 
-    let response = client.request(request).await?;
+    let bytes = http::send_ok(client, request)
+        .await
+        .context("Failed to get Fintoc balance")?;
 
-    let status = response.status();
-    let bytes = body::to_bytes(response).await?;
+    let account: Account = serde_json::from_slice(&bytes)?;
 
-    if status != StatusCode::OK {
-        bail!(
-            "Failed to get Fintoc balance, code {}, err:\n{:#?}",
-            status,
-            bytes
-        );
-    }
+    let balance = reported_balance(account_type, balance_source, &account.balance)?;
 
-    let account: Account = serde_json::from_slice(&bytes)?;
+    let scale = match amount_scale
+        .or_else(|| currency::find(&account.currency).map(|spec| spec.exponent))
+    {
+        Some(scale) => scale,
+        None => bail!(
+            "Currency {} is not supported.",
+            account.currency.to_uppercase(),
+        ),
+    };
+    let balance = Amount(currency::to_major_units(balance.0, scale));
 
-    let mut balance = 
-    match account_type {
-        AccountType::Checking => Amount(account.balance.current as f64),
-        AccountType::Savings => Amount(account.balance.current as f64),
-        AccountType::Credit => {
-            Amount((account.balance.limit - account.balance.available) as f64)
+    let currency = match rusty_money::iso::find(&account.currency) {
+        Some(currency) => currency.iso_alpha_code.to_string(),
+        None if strict_currency => bail!("Given currency {} is not valid", account.currency),
+        None => {
+            output::status(
+                format!(
+                    "Currency {} is not an ISO 4217 code, sending it to Lunch Money as-is.",
+                    account.currency.to_uppercase()
+                )
+                .yellow(),
+            );
+            account.currency.to_uppercase()
         }
     };
 
-    balance = match account.currency.to_uppercase().as_str() {
-        "CLP" => Amount(balance.0),
-        "USD" => Amount(balance.0 / 100.0),
-        "EUR" => Amount(balance.0 / 100.0),
-        _ => {
-            bail!(
-                "Currency {} is not supported.",
-                account.currency.to_uppercase(),
-            );
+    Ok(FintocBalance {
+        amount: balance,
+        currency,
+        raw: account.balance,
+        refreshed_at: account.refreshed_at,
+    })
+}
 
-        }
+/// The balance to report for an account, in the currency's minor units: the
+/// figure picked by its `balance_source` if set, or its type's formula. Fails
+/// when Fintoc didn't send the figures the `balance_source` needs.
+pub fn reported_balance(
+    account_type: AccountType,
+    balance_source: Option<BalanceSource>,
+    balance: &Balance,
+) -> Result<Amount> {
+    match balance_source {
+        Some(source) => source_balance(source, balance),
+        None => Ok(account_balance(account_type, balance)),
+    }
+}
+
+/// Computes the balance to report for an account of the given type, in the
+/// currency's minor units.
+///
+/// Credit balances are `limit - available`, which is the whole limit for a card
+/// that maxed it out. When the limit is missing or zero, or `available` is
+/// missing or above the limit (which some credit products report), that
+/// formula is meaningless and the `current` balance is used instead.
+fn account_balance(account_type: AccountType, balance: &Balance) -> Amount {
+    match account_type {
+        AccountType::Checking | AccountType::Savings => Amount(balance.current as f64),
+        AccountType::Credit => match (balance.limit, balance.available) {
+            (Some(limit), Some(available)) if limit > 0 && available <= limit => {
+                Amount((limit - available) as f64)
+            }
+            (limit, available) => {
+                let figure = |value: Option<i128>| {
+                    value.map_or_else(|| "none".to_string(), |value| value.to_string())
+                };
+                output::status(
+                    format!(
+                        "Credit balance reports limit {} and available {}, using the negated current balance {} instead.",
+                        figure(limit),
+                        figure(available),
+                        balance.current
+                    )
+                    .yellow(),
+                );
+                // Fintoc reports a card's debt as a negative `current`, the
+                // opposite sign of `limit - available`
+                Amount(-balance.current as f64)
+            }
+        },
+    }
+}
+
+/// The balance figure picked by an account's `balance_source`, in minor units.
+/// Unlike the type's formula there's no fallback: the source was chosen
+/// explicitly, so a missing figure fails rather than reporting 0.
+fn source_balance(source: BalanceSource, balance: &Balance) -> Result<Amount> {
+    let figure = |value: Option<i128>, name: &str| match value {
+        Some(value) => Ok(value),
+        None => bail!(
+            "Fintoc sent no {} balance, which the account's balance_source needs",
+            name
+        ),
     };
 
-    Ok((
-        balance,
-        *rusty_money::iso::find(&account.currency)
-            .ok_or_else(|| anyhow!("Given currency {} is not valid", account.currency))?,
-    ))
+    Ok(match source {
+        BalanceSource::Current => Amount(balance.current as f64),
+        BalanceSource::Available => Amount(figure(balance.available, "available")? as f64),
+        BalanceSource::LimitMinusAvailable => Amount(
+            (figure(balance.limit, "limit")? - figure(balance.available, "available")?) as f64,
+        ),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::config::HttpSettings;
+    use crate::http::tests::{read_request, respond};
+
+    fn balance(current: i128, available: Option<i128>, limit: Option<i128>) -> Balance {
+        Balance {
+            available,
+            current,
+            limit,
+        }
+    }
+
+    #[test]
+    fn checking_and_savings_use_current() {
+        let balance = balance(100_000, Some(90_000), Some(0));
+        assert_eq!(
+            account_balance(AccountType::Checking, &balance),
+            Amount(100_000.0)
+        );
+        assert_eq!(
+            account_balance(AccountType::Savings, &balance),
+            Amount(100_000.0)
+        );
+    }
+
+    #[test]
+    fn credit_uses_limit_minus_available() {
+        let balance = balance(-25_000, Some(75_000), Some(100_000));
+        assert_eq!(
+            account_balance(AccountType::Credit, &balance),
+            Amount(25_000.0)
+        );
+    }
+
+    #[test]
+    fn credit_at_its_limit_owes_the_whole_limit() {
+        let balance = balance(-100_000, Some(0), Some(100_000));
+        assert_eq!(
+            account_balance(AccountType::Credit, &balance),
+            Amount(100_000.0)
+        );
+    }
+
+    #[test]
+    fn credit_with_zero_limit_uses_current() {
+        let balance = balance(-30_000, Some(0), Some(0));
+        assert_eq!(
+            account_balance(AccountType::Credit, &balance),
+            Amount(30_000.0)
+        );
+    }
+
+    #[test]
+    fn credit_with_missing_figures_uses_current() {
+        assert_eq!(
+            account_balance(AccountType::Credit, &balance(-30_000, None, None)),
+            Amount(30_000.0)
+        );
+        assert_eq!(
+            account_balance(AccountType::Credit, &balance(-30_000, None, Some(100_000))),
+            Amount(30_000.0)
+        );
+    }
+
+    #[test]
+    fn credit_with_available_above_limit_uses_current() {
+        let balance = balance(5_000, Some(105_000), Some(100_000));
+        assert_eq!(
+            account_balance(AccountType::Credit, &balance),
+            Amount(-5_000.0)
+        );
+    }
+
+    #[test]
+    fn missing_balance_fields_deserialize_as_none() {
+        let balance: Balance = serde_json::from_str(r#"{"current": 1500}"#).unwrap();
+        assert_eq!(balance.available, None);
+        assert_eq!(balance.limit, None);
+        assert_eq!(
+            account_balance(AccountType::Credit, &balance),
+            Amount(-1_500.0)
+        );
+    }
+
+    #[test]
+    fn balance_source_overrides_the_type() {
+        let balance = balance(-58_000, Some(42_000), Some(100_000));
+        assert_eq!(
+            reported_balance(
+                AccountType::Credit,
+                Some(BalanceSource::Available),
+                &balance
+            )
+            .unwrap(),
+            Amount(42_000.0)
+        );
+        assert_eq!(
+            reported_balance(
+                AccountType::Checking,
+                Some(BalanceSource::Current),
+                &balance
+            )
+            .unwrap(),
+            Amount(-58_000.0)
+        );
+    }
+
+    #[test]
+    fn each_balance_source() {
+        let balance = balance(-58_000, Some(42_000), Some(100_000));
+        assert_eq!(
+            source_balance(BalanceSource::Current, &balance).unwrap(),
+            Amount(-58_000.0)
+        );
+        assert_eq!(
+            source_balance(BalanceSource::Available, &balance).unwrap(),
+            Amount(42_000.0)
+        );
+        assert_eq!(
+            source_balance(BalanceSource::LimitMinusAvailable, &balance).unwrap(),
+            Amount(58_000.0)
+        );
+    }
+
+    #[test]
+    fn available_source_fails_without_available() {
+        let balance = balance(-58_000, None, Some(100_000));
+        let error = source_balance(BalanceSource::Available, &balance).unwrap_err();
+        assert!(error.to_string().contains("no available balance"));
+    }
+
+    #[test]
+    fn limit_minus_available_source_fails_without_limit() {
+        let balance = balance(-58_000, Some(42_000), None);
+        let error = source_balance(BalanceSource::LimitMinusAvailable, &balance).unwrap_err();
+        assert!(error.to_string().contains("no limit balance"));
+    }
+
+    #[test]
+    fn limit_minus_available_source_fails_without_available() {
+        let balance = balance(-58_000, None, Some(100_000));
+        let error = source_balance(BalanceSource::LimitMinusAvailable, &balance).unwrap_err();
+        assert!(error.to_string().contains("no available balance"));
+    }
+
+    #[test]
+    fn without_a_balance_source_the_type_decides() {
+        let balance = balance(-58_000, Some(42_000), Some(100_000));
+        assert_eq!(
+            account_balance(AccountType::Checking, &balance),
+            Amount(-58_000.0)
+        );
+        assert_eq!(
+            account_balance(AccountType::Credit, &balance),
+            Amount(58_000.0)
+        );
+    }
+
+    #[test]
+    fn balance_source_names_in_config() {
+        for (name, source) in [
+            ("current", BalanceSource::Current),
+            ("available", BalanceSource::Available),
+            ("limit_minus_available", BalanceSource::LimitMinusAvailable),
+        ] {
+            let parsed: BalanceSource = serde_json::from_value(serde_json::json!(name)).unwrap();
+            assert_eq!(parsed, source);
+        }
+    }
+
+    fn at(timestamp: &str) -> DateTime<Utc> {
+        timestamp.parse().unwrap()
+    }
+
+    #[test]
+    fn window_includes_movements_posted_on_its_end_day() {
+        let (since, until) = window_params(at("2024-05-01T00:00:00Z"), at("2024-05-31T08:30:00Z"));
+        assert_eq!(since, "2024-05-01");
+        assert_eq!(until, "2024-06-01");
+    }
+
+    #[tokio::test]
+    async fn movements_posted_on_the_end_day_are_fetched() {
+        let movement = |id: &str, post_date: &str| {
+            serde_json::json!({
+                "id": id,
+                "object": "movement",
+                "amount": -1500,
+                "post_date": post_date,
+                "description": "COMPRA NACIONAL",
+                "transaction_date": null,
+                "currency": "CLP",
+                "reference_id": null,
+                "type": "other",
+                "pending": false,
+                "recipient_account": null,
+                "sender_account": null,
+                "comment": null,
+            })
+        };
+        let movements = [
+            movement("mov_end_day", "2024-05-31T23:30:00Z"),
+            movement("mov_next_day", "2024-06-01T10:00:00Z"),
+        ];
+
+        // Leaves out movements posted on `until` itself, the stricter of the
+        // two ways the API could read it
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let request = read_request(&mut stream).await;
+                let until = request
+                    .split(['&', ' '])
+                    .find_map(|param| param.strip_prefix("until="))
+                    .unwrap()
+                    .to_string();
+                let page: Vec<_> = movements
+                    .iter()
+                    .filter(|movement| movement["post_date"].as_str().unwrap()[..10] < *until)
+                    .collect();
+                respond(&mut stream, &serde_json::to_string(&page).unwrap()).await;
+            }
+        });
+
+        let client = ApiClient::build(&HttpSettings::default()).unwrap();
+        let credentials = AccountCredentials {
+            secret_token: "sk_test".to_string(),
+            link_token: "link_test".to_string(),
+            account_id: "acc_1".to_string(),
+        };
+        let options = FetchOptions {
+            max_pages: 10,
+            prefetch_pages: 1,
+            max_movements: None,
+        };
+        let fetched = fetch_movements_from(
+            &format!("http://{}/v1", address),
+            &client,
+            &credentials,
+            at("2024-05-01T00:00:00Z"),
+            at("2024-05-31T08:30:00Z"),
+            &options,
+            None,
+        )
+        .await;
+        server.abort();
+
+        let ids: Vec<_> = fetched
+            .unwrap()
+            .movements
+            .into_iter()
+            .map(|movement| movement.id)
+            .collect();
+        assert_eq!(ids, ["mov_end_day"]);
+    }
+
+    /// Fetches from a server whose pages have `page_lens` movements.
+    async fn fetch_pages(page_lens: &'static [usize]) -> FetchedMovements {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let request = read_request(&mut stream).await;
+                let page: usize = request
+                    .split(['&', ' '])
+                    .find_map(|param| param.strip_prefix("page="))
+                    .unwrap()
+                    .parse()
+                    .unwrap();
+                let movements: Vec<_> = (0..page_lens.get(page - 1).copied().unwrap_or(0))
+                    .map(|i| {
+                        serde_json::json!({
+                            "id": format!("mov_{}_{}", page, i),
+                            "object": "movement",
+                            "amount": -1500,
+                            "post_date": "2024-05-01T12:00:00Z",
+                            "description": "COMPRA NACIONAL",
+                            "transaction_date": null,
+                            "currency": "CLP",
+                            "reference_id": null,
+                            "type": "other",
+                            "pending": false,
+                            "recipient_account": null,
+                            "sender_account": null,
+                            "comment": null,
+                        })
+                    })
+                    .collect();
+                respond(&mut stream, &serde_json::to_string(&movements).unwrap()).await;
+            }
+        });
+
+        let fetched = fetch_movements_from(
+            &format!("http://{}/v1", address),
+            &ApiClient::build(&HttpSettings::default()).unwrap(),
+            &AccountCredentials {
+                secret_token: "sk_test".to_string(),
+                link_token: "link_test".to_string(),
+                account_id: "acc_1".to_string(),
+            },
+            at("2024-05-01T00:00:00Z"),
+            at("2024-05-31T00:00:00Z"),
+            &FetchOptions {
+                max_pages: 10,
+                prefetch_pages: 1,
+                max_movements: None,
+            },
+            None,
+        )
+        .await;
+        server.abort();
+        fetched.unwrap()
+    }
+
+    #[tokio::test]
+    async fn an_empty_page_after_a_full_one_flags_the_fetch() {
+        let fetched = fetch_pages(&[PER_PAGE, 0]).await;
+        assert_eq!(fetched.movements.len(), PER_PAGE);
+        assert_eq!(
+            fetched.short_fetch().map(|short| short.fetched),
+            Some(PER_PAGE)
+        );
+    }
+
+    #[tokio::test]
+    async fn a_short_last_page_ends_the_fetch_cleanly() {
+        let fetched = fetch_pages(&[PER_PAGE, 12]).await;
+        assert_eq!(fetched.movements.len(), PER_PAGE + 12);
+        assert!(fetched.short_fetch().is_none());
+
+        let fetched = fetch_pages(&[0]).await;
+        assert!(fetched.movements.is_empty());
+        assert!(fetched.short_fetch().is_none());
+    }
+
+    #[test]
+    fn window_end_late_in_the_day_still_covers_only_the_next_day() {
+        let (_, until) = window_params(at("2024-12-01T00:00:00Z"), at("2024-12-31T23:59:59Z"));
+        assert_eq!(until, "2025-01-01");
+    }
 }
@@ -0,0 +1,46 @@
+//! One JSON record per `sync` run, kept in `history_dir` as an audit trail.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::report::SyncReport;
+
+#[derive(Debug, Serialize)]
+pub struct SyncRecord<'a> {
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    /// Command line of the run, with secrets redacted
+    pub command: Vec<String>,
+    /// "ok", "interrupted" or "failed"
+    pub status: &'static str,
+    pub window_start: Option<DateTime<Utc>>,
+    pub window_end: Option<DateTime<Utc>>,
+    pub report: &'a SyncReport,
+    pub error: Option<String>,
+}
+
+impl SyncRecord<'_> {
+    /// Writes the record to a new timestamped file in `dir`, creating the
+    /// directory if needed. The file is written under a temporary name and
+    /// renamed, so readers never see a partial record.
+    pub fn write(&self, dir: &Path) -> Result<PathBuf> {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create history directory {}", dir.display()))?;
+
+        let path = dir.join(format!(
+            "sync-{}.json",
+            self.started_at.format("%Y%m%dT%H%M%S%.3fZ")
+        ));
+        let temp_path = path.with_extension("json.tmp");
+        fs::write(&temp_path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("Failed to write sync record {}", temp_path.display()))?;
+        fs::rename(&temp_path, &path)
+            .with_context(|| format!("Failed to write sync record {}", path.display()))?;
+
+        Ok(path)
+    }
+}
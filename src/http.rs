@@ -0,0 +1,567 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::num::NonZeroU32;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context, Result};
+use colored::*;
+use flate2::read::GzDecoder;
+use governor::{DefaultKeyedRateLimiter, Quota, RateLimiter};
+use hyper::body::{self, Bytes};
+use hyper::client::{Client, HttpConnector};
+use hyper::header::{HeaderValue, ACCEPT_ENCODING, AUTHORIZATION, CONTENT_ENCODING};
+use hyper::{Body, Request, StatusCode};
+use hyper_tls::HttpsConnector;
+use lazy_static::lazy_static;
+use native_tls::{Certificate, Protocol};
+use regex::Regex;
+
+use crate::config::HttpSettings;
+use crate::debug::debug_log;
+use crate::output;
+use crate::types::HttpsClient;
+
+/// A request that failed, either without a response or with an unexpected
+/// status. Found in the chain of API errors, so `--format json` can report the
+/// endpoint.
+#[derive(Debug)]
+pub struct EndpointError {
+    /// Method, host and path, without the query string
+    pub endpoint: String,
+    pub status: Option<StatusCode>,
+    pub body: Option<String>,
+}
+
+impl fmt::Display for EndpointError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.status, &self.body) {
+            (Some(status), Some(body)) if !body.is_empty() => {
+                write!(f, "{} returned {}: {}", self.endpoint, status, body)
+            }
+            (Some(status), _) => write!(f, "{} returned {}", self.endpoint, status),
+            (None, _) => write!(f, "{} failed", self.endpoint),
+        }
+    }
+}
+
+impl std::error::Error for EndpointError {}
+
+/// Method, host and path of a request. The query string is left out, it can
+/// hold tokens.
+fn endpoint(request: &Request<Body>) -> String {
+    format!(
+        "{} {}{}",
+        request.method(),
+        request.uri().host().unwrap_or_default(),
+        request.uri().path()
+    )
+}
+
+/// How requests that fail with a network error or a retryable status are
+/// re-sent.
+#[derive(Debug, Clone, Copy)]
+struct RetryPolicy {
+    retries: u32,
+    /// Delay before the first retry, doubled on each following one
+    backoff: Duration,
+}
+
+impl RetryPolicy {
+    fn from_settings(settings: &HttpSettings) -> Result<Self> {
+        let backoff = humantime::parse_duration(&settings.retry_backoff)
+            .with_context(|| format!("Invalid http.retry_backoff {:?}", settings.retry_backoff))?;
+        Ok(Self {
+            retries: settings.retries,
+            backoff,
+        })
+    }
+}
+
+/// An `HttpsClient` along with the retry and throttle settings its requests
+/// are sent with, so several clients in one process can use different ones.
+/// Clones share the connection pool and the throttle.
+#[derive(Clone)]
+pub struct ApiClient {
+    client: HttpsClient,
+    retry: RetryPolicy,
+    /// Token buckets, one per host, shared by every request sent through this
+    /// client when `http.requests_per_second` is set
+    throttle: Option<Arc<DefaultKeyedRateLimiter<String>>>,
+}
+
+impl ApiClient {
+    /// Wraps `client`, sending its requests with the retry and throttle
+    /// settings of `settings`. Its pool and TLS settings are left as they are.
+    pub fn new(client: HttpsClient, settings: &HttpSettings) -> Result<Self> {
+        let throttle = match settings.requests_per_second {
+            Some(0) => bail!("http.requests_per_second must be at least 1"),
+            Some(rate) => Some(Arc::new(RateLimiter::keyed(Quota::per_second(
+                NonZeroU32::new(rate).unwrap(),
+            )))),
+            None => None,
+        };
+
+        Ok(Self {
+            client,
+            retry: RetryPolicy::from_settings(settings)?,
+            throttle,
+        })
+    }
+
+    /// Builds a client (see `build_client`) and wraps it with the same
+    /// settings.
+    pub fn build(settings: &HttpSettings) -> Result<Self> {
+        Self::new(build_client(settings)?, settings)
+    }
+
+    /// The underlying hyper client.
+    pub fn inner(&self) -> &HttpsClient {
+        &self.client
+    }
+
+    /// Waits for the host's bucket to allow another request.
+    async fn throttle(&self, host: &str) {
+        if let Some(limiter) = &self.throttle {
+            limiter.until_key_ready(&host.to_string()).await;
+        }
+    }
+}
+
+/// Where `--dump-requests` traces go, and the tokens scrubbed from them.
+struct RequestDump {
+    /// None for stderr
+    file: Option<File>,
+    secrets: Vec<String>,
+}
+
+static REQUEST_DUMP: Mutex<Option<RequestDump>> = Mutex::new(None);
+
+lazy_static! {
+    static ref LINK_TOKEN_PARAM: Regex = Regex::new(r"(link_token=)[^&\s]+").unwrap();
+}
+
+/// Writes every request and response to `path` (`-` for stderr), with the
+/// given secrets, the `Authorization` header and `link_token` parameters
+/// redacted.
+pub fn dump_requests(path: &Path, secrets: Vec<String>) -> Result<()> {
+    let file = if path == Path::new("-") {
+        None
+    } else {
+        Some(
+            File::create(path)
+                .with_context(|| format!("Failed to create request dump {}", path.display()))?,
+        )
+    };
+    *REQUEST_DUMP.lock().unwrap() = Some(RequestDump { file, secrets });
+    Ok(())
+}
+
+/// Writes one attempt's request and outcome to the request dump, if enabled.
+fn dump_exchange(
+    parts: &hyper::http::request::Parts,
+    body: &[u8],
+    result: &Result<(StatusCode, Bytes)>,
+) {
+    let mut dump = REQUEST_DUMP.lock().unwrap();
+    let Some(dump) = dump.as_mut() else {
+        return;
+    };
+
+    let mut trace = format!(">>> {} {}\n", parts.method, parts.uri);
+    for (name, value) in &parts.headers {
+        let value = if name == AUTHORIZATION {
+            "[redacted]".into()
+        } else {
+            String::from_utf8_lossy(value.as_bytes())
+        };
+        trace.push_str(&format!("{}: {}\n", name, value));
+    }
+    if !body.is_empty() {
+        trace.push_str(&format!("\n{}\n", String::from_utf8_lossy(body)));
+    }
+    match result {
+        Ok((status, bytes)) => trace.push_str(&format!(
+            "<<< {}\n{}\n",
+            status,
+            String::from_utf8_lossy(bytes)
+        )),
+        Err(err) => trace.push_str(&format!("<<< failed: {:#}\n", err)),
+    }
+
+    let trace = LINK_TOKEN_PARAM.replace_all(&trace, "${1}[redacted]");
+    let trace = dump
+        .secrets
+        .iter()
+        .fold(trace.into_owned(), |trace, secret| {
+            trace.replace(secret.as_str(), "[redacted]")
+        });
+    let trace = output::redact(&trace);
+
+    match &mut dump.file {
+        Some(file) => {
+            let _ = writeln!(file, "{}", trace);
+        }
+        None => output::diagnostic(trace),
+    }
+}
+
+/// Server errors and rate limiting are worth another try, other statuses
+/// won't change.
+fn is_retryable(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+/// Builds the HTTP client shared by all Fintoc and Lunch Money requests, with
+/// the connection pool and TLS settings. This is the only place clients are
+/// built; embedders can pass their own to `Syncer::with_client` instead.
+/// Requests go through an `ApiClient` wrapping it.
+pub fn build_client(settings: &HttpSettings) -> Result<HttpsClient> {
+    let idle_timeout =
+        humantime::parse_duration(&settings.pool_idle_timeout).with_context(|| {
+            format!(
+                "Invalid http.pool_idle_timeout {:?}",
+                settings.pool_idle_timeout
+            )
+        })?;
+
+    let mut http = HttpConnector::new();
+    http.enforce_http(false);
+    let https = HttpsConnector::from((http, tls_connector(settings)?.into()));
+
+    Ok(Client::builder()
+        .pool_idle_timeout(idle_timeout)
+        .pool_max_idle_per_host(settings.pool_max_idle_per_host)
+        .build::<_, hyper::Body>(https))
+}
+
+/// TLS connector trusting the system roots plus those in `ca_bundle`.
+fn tls_connector(settings: &HttpSettings) -> Result<native_tls::TlsConnector> {
+    let mut builder = native_tls::TlsConnector::builder();
+
+    if let Some(version) = &settings.min_tls_version {
+        let protocol = match version.as_str() {
+            "1.0" => Protocol::Tlsv10,
+            "1.1" => Protocol::Tlsv11,
+            "1.2" => Protocol::Tlsv12,
+            _ => bail!(
+                "Invalid http.min_tls_version {:?}, expected \"1.0\", \"1.1\" or \"1.2\"",
+                version
+            ),
+        };
+        builder.min_protocol_version(Some(protocol));
+    }
+
+    if let Some(path) = &settings.ca_bundle {
+        let pem = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read CA bundle {}", path))?;
+        let certificates = pem_certificates(&pem);
+        if certificates.is_empty() {
+            bail!("No certificates found in CA bundle {}", path);
+        }
+        for certificate in certificates {
+            builder.add_root_certificate(
+                Certificate::from_pem(certificate.as_bytes())
+                    .with_context(|| format!("Invalid certificate in CA bundle {}", path))?,
+            );
+        }
+    }
+
+    builder.build().context("Failed to set up TLS")
+}
+
+/// Splits a PEM bundle into its certificates, ignoring anything in between.
+fn pem_certificates(pem: &str) -> Vec<&str> {
+    const BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+    const END: &str = "-----END CERTIFICATE-----";
+
+    let mut certificates = Vec::new();
+    let mut rest = pem;
+    while let Some(start) = rest.find(BEGIN) {
+        let Some(end) = rest[start..].find(END) else {
+            break;
+        };
+        let end = start + end + END.len();
+        certificates.push(&rest[start..end]);
+        rest = &rest[end..];
+    }
+    certificates
+}
+
+/// Sends a request asking for a gzip-compressed response, and returns the
+/// status and the (decompressed) body.
+///
+/// Requests wait for the host's bucket of the client's throttle, retries
+/// included. Network errors and 5xx/429 responses are retried with exponential
+/// backoff, per the client's `http.retries` and `http.retry_backoff`. This includes
+/// Lunch Money inserts: every transaction carries an external id, so a retried
+/// insert that had already gone through is reported as existing rather than
+/// duplicated.
+pub async fn send(client: &ApiClient, request: Request<Body>) -> Result<(StatusCode, Bytes)> {
+    let policy = client.retry;
+    let endpoint = endpoint(&request);
+    let host = request.uri().host().unwrap_or_default().to_string();
+
+    // Keep the body around so the request can be rebuilt for each attempt
+    let (parts, body) = request.into_parts();
+    let body = body::to_bytes(body).await?;
+    let build = || {
+        let mut request = Request::new(Body::from(body.clone()));
+        *request.method_mut() = parts.method.clone();
+        *request.uri_mut() = parts.uri.clone();
+        *request.version_mut() = parts.version;
+        *request.headers_mut() = parts.headers.clone();
+        request
+    };
+
+    let mut attempt = 0;
+    loop {
+        client.throttle(&host).await;
+        let result = send_once(&client.client, build(), &endpoint).await;
+        dump_exchange(&parts, &body, &result);
+
+        let failure = match &result {
+            Ok((status, _)) if is_retryable(*status) => format!("returned {}", status),
+            Ok(_) => return result,
+            Err(err) => format!("failed: {}", err.root_cause()),
+        };
+        if attempt >= policy.retries {
+            return result;
+        }
+
+        let delay = policy.backoff * 2u32.saturating_pow(attempt);
+        attempt += 1;
+        output::status(
+            format!(
+                "{} {}, retrying in {} (attempt {} of {})",
+                endpoint,
+                failure,
+                humantime::format_duration(delay),
+                attempt,
+                policy.retries
+            )
+            .yellow(),
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+async fn send_once(
+    client: &HttpsClient,
+    mut request: Request<Body>,
+    endpoint: &str,
+) -> Result<(StatusCode, Bytes)> {
+    request
+        .headers_mut()
+        .insert(ACCEPT_ENCODING, HeaderValue::from_static("gzip"));
+
+    let started = Instant::now();
+
+    let response = client.request(request).await.map_err(|err| {
+        anyhow!(err).context(EndpointError {
+            endpoint: endpoint.to_string(),
+            status: None,
+            body: None,
+        })
+    })?;
+
+    let status = response.status();
+    let gzipped = response
+        .headers()
+        .get(CONTENT_ENCODING)
+        .is_some_and(|encoding| encoding.as_bytes().eq_ignore_ascii_case(b"gzip"));
+    let bytes = body::to_bytes(response).await?;
+
+    debug_log!(
+        "{} -> {} in {} ms",
+        endpoint,
+        status.as_u16(),
+        started.elapsed().as_millis()
+    );
+
+    if !gzipped {
+        return Ok((status, bytes));
+    }
+
+    let mut decoded = Vec::new();
+    GzDecoder::new(&bytes[..])
+        .read_to_end(&mut decoded)
+        .context("Failed to decode gzip response")?;
+
+    Ok((status, decoded.into()))
+}
+
+/// Like `send`, but fails with an `EndpointError` unless the response is
+/// 200 OK, and returns just the body.
+pub async fn send_ok(client: &ApiClient, request: Request<Body>) -> Result<Bytes> {
+    let endpoint = endpoint(&request);
+
+    let (status, bytes) = send(client, request).await?;
+
+    if status != StatusCode::OK {
+        return Err(EndpointError {
+            endpoint,
+            status: Some(status),
+            body: Some(String::from_utf8_lossy(&bytes).into_owned()),
+        }
+        .into());
+    }
+
+    Ok(bytes)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    use hyper::Method;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+
+    /// Reads a request's head and body off `stream`.
+    pub(crate) async fn read_request(stream: &mut TcpStream) -> String {
+        let mut request = Vec::new();
+        let mut buffer = [0; 4096];
+        loop {
+            let read = stream.read(&mut buffer).await.unwrap();
+            if read == 0 {
+                return String::from_utf8_lossy(&request).into_owned();
+            }
+            request.extend_from_slice(&buffer[..read]);
+            let text = String::from_utf8_lossy(&request);
+            if let Some(head_end) = text.find("\r\n\r\n") {
+                let length = text[..head_end]
+                    .lines()
+                    .find_map(|line| {
+                        line.to_lowercase()
+                            .strip_prefix("content-length: ")
+                            .map(str::to_string)
+                    })
+                    .map_or(0, |length| length.trim().parse().unwrap());
+                if request.len() >= head_end + 4 + length {
+                    return text.into_owned();
+                }
+            }
+        }
+    }
+
+    /// Writes a 200 OK response with `body`, closing the connection after it.
+    pub(crate) async fn respond(stream: &mut TcpStream, body: &str) {
+        stream
+            .write_all(
+                format!(
+                    "HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn insert_is_resent_after_the_connection_drops() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let bodies = Arc::new(Mutex::new(Vec::new()));
+
+        let server = {
+            let attempts = attempts.clone();
+            let bodies = bodies.clone();
+            tokio::spawn(async move {
+                loop {
+                    let (mut stream, _) = listener.accept().await.unwrap();
+                    let request = read_request(&mut stream).await;
+                    let body = request.split("\r\n\r\n").nth(1).unwrap_or_default();
+                    bodies.lock().unwrap().push(body.to_string());
+
+                    // The first attempt times out from the client's side: the
+                    // insert may have gone through, but no response arrives
+                    if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+                        continue;
+                    }
+                    respond(
+                        &mut stream,
+                        r#"{"error":["Transaction with external_id mov_1 already exists"]}"#,
+                    )
+                    .await;
+                }
+            })
+        };
+
+        let client = ApiClient::build(&HttpSettings::default()).unwrap();
+        let body = r#"{"transactions":[{"external_id":"mov_1"}]}"#;
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(format!("http://{}/v1/transactions", address))
+            .body(Body::from(body))
+            .unwrap();
+
+        let bytes = send_ok(&client, request).await.unwrap();
+        server.abort();
+
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+        // The retry carries the same external id, so Lunch Money reports the
+        // transaction as existing instead of inserting it twice
+        assert_eq!(*bodies.lock().unwrap(), vec![body, body]);
+        assert!(String::from_utf8_lossy(&bytes).contains("already exists"));
+    }
+
+    #[tokio::test]
+    async fn each_client_keeps_its_own_retry_settings() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let attempts = Arc::new(AtomicUsize::new(0));
+
+        // Every attempt gets its connection dropped without a response
+        let server = {
+            let attempts = attempts.clone();
+            tokio::spawn(async move {
+                loop {
+                    let (mut stream, _) = listener.accept().await.unwrap();
+                    read_request(&mut stream).await;
+                    attempts.fetch_add(1, Ordering::SeqCst);
+                }
+            })
+        };
+
+        let retrying = ApiClient::build(&HttpSettings {
+            retries: 2,
+            retry_backoff: "1ms".to_string(),
+            ..HttpSettings::default()
+        })
+        .unwrap();
+        // Built later, with other settings that must not apply to `retrying`
+        let _single = ApiClient::build(&HttpSettings {
+            retries: 0,
+            ..HttpSettings::default()
+        })
+        .unwrap();
+
+        let request = Request::builder()
+            .uri(format!("http://{}/v1/assets", address))
+            .body(Body::empty())
+            .unwrap();
+        assert!(send(&retrying, request).await.is_err());
+        server.abort();
+
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn only_server_errors_and_rate_limits_are_retried() {
+        assert!(is_retryable(StatusCode::INTERNAL_SERVER_ERROR));
+        assert!(is_retryable(StatusCode::BAD_GATEWAY));
+        assert!(is_retryable(StatusCode::TOO_MANY_REQUESTS));
+        assert!(!is_retryable(StatusCode::BAD_REQUEST));
+        assert!(!is_retryable(StatusCode::UNAUTHORIZED));
+        assert!(!is_retryable(StatusCode::NOT_FOUND));
+    }
+}
@@ -0,0 +1,36 @@
+//! Sync transactions from Chilean banks, through Fintoc, into Lunch Money.
+//!
+//! The `lunchmoney-fintoc` binary is a thin CLI over [`Syncer`], which can also
+//! be embedded in other programs. `Syncer::with_client` takes an existing
+//! [`types::HttpsClient`], e.g. one shared with the embedding program, and
+//! [`http::build_client`] builds one from `HttpSettings`. Retry and throttle
+//! settings are kept per `Syncer`, so several can run side by side.
+
+pub mod audit;
+pub mod config;
+pub mod currency;
+pub mod deadletter;
+pub mod debug;
+pub mod dedup;
+pub mod dump;
+pub mod error;
+pub mod fintoc;
+pub mod history;
+pub mod http;
+pub mod lunchmoney;
+pub mod metrics;
+pub mod notify;
+pub mod output;
+pub mod plan;
+pub mod report;
+pub mod selfcheck;
+pub mod state;
+pub mod stats;
+pub mod syncer;
+pub mod table;
+pub mod types;
+pub mod verify;
+pub mod written;
+
+pub use crate::config::AppConfig;
+pub use crate::syncer::Syncer;
@@ -1,16 +1,28 @@
+use std::collections::{BTreeSet, HashSet};
+
 use anyhow::bail;
+use anyhow::Context;
 use anyhow::Result;
+use chrono::{DateTime, NaiveDate, Utc};
+use colored::*;
+use futures::stream::{self, StreamExt};
 use hyper::header::{AUTHORIZATION, CONTENT_TYPE};
-use hyper::{body, Method, Request, StatusCode};
-use rusty_money::iso::Currency;
+use hyper::{body, Method, Request};
+use tokio::sync::OnceCell;
 
+use crate::debug::debug_log;
+use crate::http::{self, ApiClient};
+use crate::output;
 use crate::types::lunchmoney::Amount;
 use crate::types::lunchmoney::{
-    Asset, GetAllAssetsResponse, InsertTransactionRequest, InsertTransactionResponse, Transaction,
+    Asset, Category, CreateTransactionGroupRequest, CreateTransactionGroupResponse,
+    FetchedTransaction, GetAllAssetsResponse, GetAllCategoriesResponse,
+    GetAllPlaidAccountsResponse, GetAllTransactionsResponse, InsertTransactionRequest,
+    InsertTransactionResponse, PlaidAccount, SplitPart, Transaction, TransactionUpdate,
+    UpdateTransactionRequest, UpdateTransactionResponse,
 };
-use crate::types::HttpsClient;
 
-pub async fn get_all_assets(client: &HttpsClient, api_token: &str) -> Result<Vec<Asset>> {
+pub async fn get_all_assets(client: &ApiClient, api_token: &str) -> Result<Vec<Asset>> {
     let request = Request::builder()
         .method(Method::GET)
         .uri("https://dev.lunchmoney.app/v1/assets")
@@ -18,33 +30,238 @@ pub async fn get_all_assets(client: &HttpsClient, api_token: &str) -> Result<Vec
         .body(body::Body::empty())
         .unwrap();
 
-    let response = client.request(request).await?;
+    let bytes = http::send_ok(client, request)
+        .await
+        .context("Failed to get Lunch Money assets")?;
 
-    let status = response.status();
-    let bytes = body::to_bytes(response).await?;
+    let response: GetAllAssetsResponse = serde_json::from_slice(&bytes)?;
 
-    if status != StatusCode::OK {
-        bail!(
-            "Failed to get Lunch Money assets, code {}, err:\n{:#?}",
-            status,
-            bytes
+    Ok(response.assets)
+}
+
+pub async fn get_all_plaid_accounts(
+    client: &ApiClient,
+    api_token: &str,
+) -> Result<Vec<PlaidAccount>> {
+    let request = Request::builder()
+        .method(Method::GET)
+        .uri("https://dev.lunchmoney.app/v1/plaid_accounts")
+        .header(AUTHORIZATION, format!("Bearer {}", api_token))
+        .body(body::Body::empty())
+        .unwrap();
+
+    let bytes = http::send_ok(client, request)
+        .await
+        .context("Failed to get Lunch Money Plaid accounts")?;
+
+    let response: GetAllPlaidAccountsResponse = serde_json::from_slice(&bytes)?;
+
+    Ok(response.plaid_accounts)
+}
+
+pub async fn get_all_categories(client: &ApiClient, api_token: &str) -> Result<Vec<Category>> {
+    let request = Request::builder()
+        .method(Method::GET)
+        .uri("https://dev.lunchmoney.app/v1/categories")
+        .header(AUTHORIZATION, format!("Bearer {}", api_token))
+        .body(body::Body::empty())
+        .unwrap();
+
+    let bytes = http::send_ok(client, request)
+        .await
+        .context("Failed to get Lunch Money categories")?;
+
+    let response: GetAllCategoriesResponse = serde_json::from_slice(&bytes)?;
+
+    Ok(response.categories)
+}
+
+/// Fetches every transaction of an asset between two days (inclusive), following
+/// Lunch Money's offset pagination. Debits are returned as negative amounts,
+/// matching how we insert them, and split transactions as they were inserted
+/// rather than as their parts.
+pub async fn get_transactions(
+    client: &ApiClient,
+    api_token: &str,
+    asset_id: u64,
+    start_date: NaiveDate,
+    end_date: NaiveDate,
+) -> Result<Vec<FetchedTransaction>> {
+    const PAGE_SIZE: usize = 500;
+
+    let mut offset = 0;
+    let mut transactions = Vec::new();
+
+    loop {
+        let request = Request::builder()
+            .method(Method::GET)
+            .uri(format!(
+                "https://dev.lunchmoney.app/v1/transactions?asset_id={}&start_date={}&end_date={}&debit_as_negative=true&offset={}&limit={}",
+                asset_id,
+                start_date.format("%Y-%m-%d"),
+                end_date.format("%Y-%m-%d"),
+                offset,
+                PAGE_SIZE
+            ))
+            .header(AUTHORIZATION, format!("Bearer {}", api_token))
+            .body(body::Body::empty())
+            .unwrap();
+
+        let bytes = http::send_ok(client, request)
+            .await
+            .context("Failed to get Lunch Money transactions")?;
+
+        let response: GetAllTransactionsResponse = serde_json::from_slice(&bytes)?;
+        let page_len = response.transactions.len();
+        transactions.extend(response.transactions);
+
+        if !response.has_more || page_len == 0 {
+            break;
+        }
+        offset += page_len;
+    }
+
+    collapse_splits(client, api_token, transactions, start_date, end_date).await
+}
+
+/// Fetches a single transaction, with debits as negative amounts.
+pub async fn get_transaction(
+    client: &ApiClient,
+    api_token: &str,
+    transaction_id: u64,
+) -> Result<FetchedTransaction> {
+    let request = Request::builder()
+        .method(Method::GET)
+        .uri(format!(
+            "https://dev.lunchmoney.app/v1/transactions/{}?debit_as_negative=true",
+            transaction_id
+        ))
+        .header(AUTHORIZATION, format!("Bearer {}", api_token))
+        .body(body::Body::empty())
+        .unwrap();
+
+    let bytes = http::send_ok(client, request)
+        .await
+        .with_context(|| format!("Failed to get Lunch Money transaction {}", transaction_id))?;
+
+    Ok(serde_json::from_slice(&bytes)?)
+}
+
+/// Swaps the parts of split transactions (those with a `parent_id`) for the
+/// transaction they were split from, which Lunch Money leaves out of listings,
+/// so it matches the movement it was inserted from by external id. Parents
+/// dated outside the range are left out along with their parts, like any other
+/// transaction of another window.
+async fn collapse_splits(
+    client: &ApiClient,
+    api_token: &str,
+    transactions: Vec<FetchedTransaction>,
+    start_date: NaiveDate,
+    end_date: NaiveDate,
+) -> Result<Vec<FetchedTransaction>> {
+    let parent_ids: BTreeSet<u64> = transactions
+        .iter()
+        .filter_map(|transaction| transaction.parent_id)
+        .collect();
+    if parent_ids.is_empty() {
+        return Ok(transactions);
+    }
+
+    let listed: HashSet<u64> = transactions
+        .iter()
+        .map(|transaction| transaction.id)
+        .collect();
+    let mut collapsed: Vec<FetchedTransaction> = transactions
+        .into_iter()
+        .filter(|transaction| transaction.parent_id.is_none())
+        .collect();
+    for parent_id in parent_ids {
+        if listed.contains(&parent_id) {
+            continue;
+        }
+        let parent = get_transaction(client, api_token, parent_id).await?;
+        debug_log!(
+            "Listing split transaction {} instead of its parts",
+            parent_id
         );
+        if (start_date..=end_date).contains(&parent.date) {
+            collapsed.push(parent);
+        }
     }
+    Ok(collapsed)
+}
 
-    let response: GetAllAssetsResponse = serde_json::from_slice(&bytes)?;
+/// Lunch Money data that is fetched at most once per run.
+#[derive(Default)]
+pub struct LunchMoneyCache {
+    assets: OnceCell<Vec<Asset>>,
+    plaid_accounts: OnceCell<Vec<PlaidAccount>>,
+    categories: OnceCell<Vec<Category>>,
+}
 
-    Ok(response.assets)
+impl LunchMoneyCache {
+    /// Manually-managed assets, the only ones whose balance we can update.
+    pub async fn assets(&self, client: &ApiClient, api_token: &str) -> Result<&[Asset]> {
+        let assets = self
+            .assets
+            .get_or_try_init(|| get_all_assets(client, api_token))
+            .await?;
+        Ok(assets)
+    }
+
+    pub async fn plaid_accounts(
+        &self,
+        client: &ApiClient,
+        api_token: &str,
+    ) -> Result<&[PlaidAccount]> {
+        let plaid_accounts = self
+            .plaid_accounts
+            .get_or_try_init(|| get_all_plaid_accounts(client, api_token))
+            .await?;
+        Ok(plaid_accounts)
+    }
+
+    pub async fn categories(&self, client: &ApiClient, api_token: &str) -> Result<&[Category]> {
+        let categories = self
+            .categories
+            .get_or_try_init(|| get_all_categories(client, api_token))
+            .await?;
+        Ok(categories)
+    }
+}
+
+/// How transactions are inserted into Lunch Money.
+#[derive(Debug, Clone)]
+pub struct InsertOptions {
+    /// Let Lunch Money apply the user's rules to inserted transactions
+    pub apply_rules: bool,
+    /// Let Lunch Money match inserted transactions to recurring items
+    pub check_for_recurring: bool,
+    /// Number of insert requests in flight at once
+    pub concurrency: usize,
 }
 
 async fn insert_single_transaction(
-    client: &HttpsClient,
+    client: &ApiClient,
     api_token: &str,
     transaction: &Transaction,
+    options: &InsertOptions,
 ) -> Result<Option<u64>> {
+    // The split is only kept for after the insert
+    let unsplit;
+    let transaction = if transaction.split.is_some() {
+        unsplit = Transaction {
+            split: None,
+            ..transaction.clone()
+        };
+        &unsplit
+    } else {
+        transaction
+    };
     let request_body = InsertTransactionRequest {
         transactions: vec![transaction],
-        apply_rules: Some(true),
-        check_for_recurring: Some(true),
+        apply_rules: Some(options.apply_rules),
+        check_for_recurring: Some(options.check_for_recurring),
         debit_as_negative: Some(true),
         skip_balance_update: None,
         skip_duplicates: None,
@@ -58,20 +275,29 @@ async fn insert_single_transaction(
         .body(serde_json::to_vec(&request_body)?.into())
         .unwrap();
 
-    let response = client.request(request).await?;
-
-    let status = response.status();
-    let bytes = body::to_bytes(response).await?;
+    let bytes = http::send_ok(client, request)
+        .await
+        .context("Failed to insert Lunch Money transaction")?;
 
-    if status != StatusCode::OK {
-        bail!(
-            "Failed to insert Lunch Money transaction, code {}, err:\n{:#?}",
-            status,
-            bytes
-        );
-    }
+    inserted_id(&bytes)
+}
 
-    let response: InsertTransactionResponse = serde_json::from_slice(&bytes)?;
+/// Id of the transaction an insert response reports, or None when Lunch
+/// Money says it already exists, e.g. because a retried insert had gone
+/// through the first time.
+fn inserted_id(bytes: &[u8]) -> Result<Option<u64>> {
+    // Lunch Money sometimes answers 200 with a body of another shape, fail just
+    // this transaction instead of surfacing a bare deserialization error
+    let response: InsertTransactionResponse = match serde_json::from_slice(bytes) {
+        Ok(response) => response,
+        Err(err) => {
+            debug_log!(
+                "Unexpected Lunch Money insert response: {}",
+                String::from_utf8_lossy(bytes)
+            );
+            bail!("Unexpected Lunch Money insert response: {}", err);
+        }
+    };
 
     match response {
         InsertTransactionResponse {
@@ -86,7 +312,7 @@ async fn insert_single_transaction(
                 if error.contains("already exists") {
                     return Ok(None); // Indicate that the transaction already exists
                 } else {
-                    eprintln!("Error: {}", error);
+                    output::diagnostic(format!("Error: {}", error));
                 }
             }
             Ok(ids.into_iter().next())
@@ -95,51 +321,111 @@ async fn insert_single_transaction(
             ids: None,
             error: Some(errors),
         } => {
-            for error in errors {
-                if error.contains("already exists") {
-                    return Ok(None); // Indicate that the transaction already exists
-                } else {
-                    eprintln!("Error: {}", error);
-                }
+            if errors.iter().any(|error| error.contains("already exists")) {
+                return Ok(None); // Indicate that the transaction already exists
             }
-            Ok(None)
+            bail!(
+                "Lunch Money rejected the transaction: {}",
+                errors.join(", ")
+            )
         }
         InsertTransactionResponse {
             ids: None,
             error: None,
-        } => Ok(None),
+        } => {
+            debug_log!(
+                "Unexpected Lunch Money insert response: {}",
+                String::from_utf8_lossy(bytes)
+            );
+            bail!("Lunch Money returned neither ids nor errors for the transaction")
+        }
     }
 }
 
+/// Result of inserting a batch of transactions.
+#[derive(Debug, Default)]
+pub struct InsertOutcome {
+    pub inserted_ids: Vec<u64>,
+    /// External ids of the inserted transactions
+    pub inserted_external_ids: Vec<String>,
+    pub existing_count: u64,
+    /// Transactions that could not be inserted for reasons other than being
+    /// duplicates.
+    pub failed: Vec<Transaction>,
+}
+
+impl InsertOutcome {
+    /// Adds the results of another batch.
+    pub fn extend(&mut self, other: InsertOutcome) {
+        self.inserted_ids.extend(other.inserted_ids);
+        self.inserted_external_ids
+            .extend(other.inserted_external_ids);
+        self.existing_count += other.existing_count;
+        self.failed.extend(other.failed);
+    }
+}
+
+/// Inserts transactions one request at a time, with up to
+/// `options.concurrency` requests in flight.
 pub async fn insert_transactions(
-    client: &HttpsClient,
+    client: &ApiClient,
     api_token: &str,
     transactions: Vec<Transaction>,
-) -> Result<(Vec<u64>, u64)> {
-    let mut inserted_ids = vec![];
-    let mut existing_count = 0;
-
-    for transaction in &transactions {
-        match insert_single_transaction(client, api_token, transaction).await {
-            Ok(Some(id)) => inserted_ids.push(id),
-            Ok(None) => existing_count += 1, // Count existing transactions
-            Err(err) => eprintln!("Failed to insert transaction: {:?}", err),
+    options: &InsertOptions,
+) -> Result<InsertOutcome> {
+    let mut outcome = InsertOutcome::default();
+
+    let results = stream::iter(transactions)
+        .map(|transaction| async move {
+            let result = insert_single_transaction(client, api_token, &transaction, options).await;
+            (transaction, result)
+        })
+        .buffer_unordered(options.concurrency.max(1))
+        .collect::<Vec<_>>()
+        .await;
+
+    for (transaction, result) in results {
+        match result {
+            Ok(Some(id)) => {
+                if let Some(parts) = &transaction.split {
+                    // The transaction is in, so a failed split only warns
+                    if let Err(err) = split_transaction(client, api_token, id, parts).await {
+                        output::diagnostic(
+                            format!("Failed to split transaction {}: {:#}", id, err).yellow(),
+                        );
+                    }
+                }
+                outcome.inserted_ids.push(id);
+                outcome
+                    .inserted_external_ids
+                    .extend(transaction.external_id);
+            }
+            Ok(None) => outcome.existing_count += 1, // Count existing transactions
+            Err(err) => {
+                output::diagnostic(format!("Failed to insert transaction: {:?}", err));
+                outcome.failed.push(transaction);
+            }
         }
     }
 
-    Ok((inserted_ids, existing_count))
+    Ok(outcome)
 }
+
 pub async fn update_asset_balance(
-    client: &HttpsClient,
+    client: &ApiClient,
     api_token: &str,
     asset_id: u64,
     new_balance: Amount,
-    balance_currency: Currency,
+    balance_currency: &str,
+    balance_as_of: Option<DateTime<Utc>>,
+    verify: bool,
 ) -> Result<()> {
+    // Without `balance_as_of`, Lunch Money uses the time of the update
     let updated_asset = Asset {
         id: Some(asset_id),
         balance: new_balance,
-        currency: balance_currency.to_string().to_lowercase(),
+        balance_as_of,
+        currency: balance_currency.to_lowercase(),
         ..Default::default()
     };
 
@@ -151,20 +437,24 @@ pub async fn update_asset_balance(
         .body(serde_json::to_vec(&updated_asset)?.into())
         .unwrap();
 
-    let response = client.request(request).await?;
+    let bytes = http::send_ok(client, request)
+        .await
+        .context("Failed to update Lunch Money asset balance")?;
 
-    let status = response.status();
-
-    if status != StatusCode::OK {
-        bail!(
-            "Failed to update Lunch Money asset balance, code {}",
-            status
-        );
-    }
+    let updated_asset: Asset = serde_json::from_slice(&bytes)?;
 
-    let bytes = body::to_bytes(response).await?;
+    debug_log!(
+        "Asset {} balance update: expected {} {}, Lunch Money reports {} {}",
+        asset_id,
+        new_balance,
+        balance_currency.to_lowercase(),
+        updated_asset.balance,
+        updated_asset.currency
+    );
 
-    let updated_asset: Asset = serde_json::from_slice(&bytes)?;
+    if !verify {
+        return Ok(());
+    }
 
     // Assert new balance = updated_asset.balance
     if updated_asset.balance != new_balance {
@@ -175,7 +465,7 @@ pub async fn update_asset_balance(
         );
     }
     // Assert new currency = updated_asset.currency
-    if updated_asset.currency != balance_currency.to_string().to_lowercase() {
+    if updated_asset.currency != balance_currency.to_lowercase() {
         bail!(
             "Failed to update Lunch Money asset balance, expected {}, got {}",
             balance_currency,
@@ -185,3 +475,189 @@ pub async fn update_asset_balance(
 
     Ok(())
 }
+
+/// Changes fields of an existing transaction.
+pub async fn update_transaction(
+    client: &ApiClient,
+    api_token: &str,
+    transaction_id: u64,
+    update: &TransactionUpdate,
+) -> Result<()> {
+    let request_body = UpdateTransactionRequest {
+        transaction: update,
+        split: None,
+        debit_as_negative: None,
+    };
+
+    let request = Request::builder()
+        .method(Method::PUT)
+        .uri(format!(
+            "https://dev.lunchmoney.app/v1/transactions/{}",
+            transaction_id
+        ))
+        .header(AUTHORIZATION, format!("Bearer {}", api_token))
+        .header(CONTENT_TYPE, "application/json; charset=utf-8")
+        .body(serde_json::to_vec(&request_body)?.into())
+        .unwrap();
+
+    let bytes = http::send_ok(client, request).await.with_context(|| {
+        format!(
+            "Failed to update Lunch Money transaction {}",
+            transaction_id
+        )
+    })?;
+
+    let response: UpdateTransactionResponse = serde_json::from_slice(&bytes)?;
+
+    match response {
+        UpdateTransactionResponse {
+            updated: Some(true),
+            ..
+        } => Ok(()),
+        UpdateTransactionResponse { error, .. } => bail!(
+            "Failed to update Lunch Money transaction {}: {}",
+            transaction_id,
+            error.unwrap_or_default()
+        ),
+    }
+}
+
+/// Splits an existing transaction into `parts`, returning the ids of the
+/// transactions created for them. Amounts are given with debits negative, like
+/// inserts.
+pub async fn split_transaction(
+    client: &ApiClient,
+    api_token: &str,
+    transaction_id: u64,
+    parts: &[SplitPart],
+) -> Result<Vec<u64>> {
+    let request_body = UpdateTransactionRequest {
+        transaction: &TransactionUpdate::default(),
+        split: Some(parts),
+        debit_as_negative: Some(true),
+    };
+
+    let request = Request::builder()
+        .method(Method::PUT)
+        .uri(format!(
+            "https://dev.lunchmoney.app/v1/transactions/{}",
+            transaction_id
+        ))
+        .header(AUTHORIZATION, format!("Bearer {}", api_token))
+        .header(CONTENT_TYPE, "application/json; charset=utf-8")
+        .body(serde_json::to_vec(&request_body)?.into())
+        .unwrap();
+
+    let bytes = http::send_ok(client, request)
+        .await
+        .with_context(|| format!("Failed to split Lunch Money transaction {}", transaction_id))?;
+
+    let response: UpdateTransactionResponse = serde_json::from_slice(&bytes)?;
+
+    match response {
+        UpdateTransactionResponse {
+            updated: Some(true),
+            split,
+            ..
+        } => {
+            let ids = split.unwrap_or_default();
+            debug_log!("Split transaction {} into {:?}", transaction_id, ids);
+            Ok(ids)
+        }
+        UpdateTransactionResponse { error, .. } => bail!(
+            "Failed to split Lunch Money transaction {}: {}",
+            transaction_id,
+            error.unwrap_or_default()
+        ),
+    }
+}
+
+/// Creates a transaction group out of existing transactions, returning the id
+/// of the group.
+pub async fn create_transaction_group(
+    client: &ApiClient,
+    api_token: &str,
+    group: &CreateTransactionGroupRequest,
+) -> Result<u64> {
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri("https://dev.lunchmoney.app/v1/transactions/group")
+        .header(AUTHORIZATION, format!("Bearer {}", api_token))
+        .header(CONTENT_TYPE, "application/json; charset=utf-8")
+        .body(serde_json::to_vec(group)?.into())
+        .unwrap();
+
+    let bytes = http::send_ok(client, request)
+        .await
+        .context("Failed to create Lunch Money transaction group")?;
+
+    match serde_json::from_slice(&bytes)? {
+        CreateTransactionGroupResponse::Id(group_id) => Ok(group_id),
+        CreateTransactionGroupResponse::Error { error } => {
+            bail!("Failed to create Lunch Money transaction group: {}", error)
+        }
+    }
+}
+
+/// Groups the two sides of an internal transfer, given the ids they were
+/// inserted with. A side that was already in Lunch Money has no id, in which
+/// case grouping is skipped with a warning and `None` is returned.
+pub async fn group_transfer(
+    client: &ApiClient,
+    api_token: &str,
+    date: NaiveDate,
+    payee: &str,
+    outgoing_id: Option<u64>,
+    incoming_id: Option<u64>,
+) -> Result<Option<u64>> {
+    let (outgoing_id, incoming_id) = match (outgoing_id, incoming_id) {
+        (Some(outgoing_id), Some(incoming_id)) => (outgoing_id, incoming_id),
+        _ => {
+            output::status(
+                format!(
+                    "Not grouping transfer {:?} on {}: one of its sides already existed in Lunch Money.",
+                    payee, date
+                )
+                .yellow(),
+            );
+            return Ok(None);
+        }
+    };
+
+    let group = CreateTransactionGroupRequest {
+        date,
+        payee: payee.to_string(),
+        category_id: None,
+        notes: None,
+        transactions: vec![outgoing_id, incoming_id],
+    };
+
+    create_transaction_group(client, api_token, &group)
+        .await
+        .map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inserted_transaction_returns_its_id() {
+        assert_eq!(inserted_id(br#"{"ids": [42]}"#).unwrap(), Some(42));
+        assert_eq!(inserted_id(br#"{"ids": ["42"]}"#).unwrap(), Some(42));
+    }
+
+    #[test]
+    fn retried_insert_that_went_through_counts_as_existing() {
+        let response = br#"{"error": ["Transaction with external_id mov_1 already exists"]}"#;
+        assert_eq!(inserted_id(response).unwrap(), None);
+    }
+
+    #[test]
+    fn rejected_insert_fails() {
+        let err = inserted_id(br#"{"error": ["Invalid date"]}"#).unwrap_err();
+        assert!(err.to_string().contains("Invalid date"));
+        assert!(inserted_id(br#"{}"#).is_err());
+        assert!(inserted_id(b"<html>").is_err());
+    }
+}
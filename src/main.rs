@@ -1,79 +1,107 @@
-use anyhow::Result;
-use chrono::offset::{Local, Utc};
-use chrono::DateTime;
-use clap::{Parser, Subcommand};
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, Local, Months, NaiveDate, NaiveTime, Utc};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use colored::*;
-use config::Config;
-use hyper::client::Client;
-use hyper_tls::HttpsConnector;
-use indicatif::{ProgressBar, ProgressStyle};
-use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
-mod fintoc;
-mod lunchmoney;
-mod types;
+use lunchmoney_fintoc::config::{Account, Bank};
+use lunchmoney_fintoc::dump::MovementDump;
+use lunchmoney_fintoc::error::{AccountContext, ErrorReport, PartialSync};
+use lunchmoney_fintoc::fintoc::{self, fetch_link_accounts};
+use lunchmoney_fintoc::http;
+use lunchmoney_fintoc::report::{AccountReport, SyncReport};
+use lunchmoney_fintoc::state::SyncState;
+use lunchmoney_fintoc::types::fintoc::Movement;
+use lunchmoney_fintoc::verify::Reconciliation;
+use lunchmoney_fintoc::{currency, debug, output, selfcheck, table, AppConfig, Syncer};
 
-use fintoc::fetch_fintoc_movements;
-use itertools::Itertools;
-use lunchmoney::{get_all_assets, insert_transactions, update_asset_balance};
-use types::fintoc::AccountCredentials;
-use types::lunchmoney::Transaction;
-use types::HttpsClient;
+#[derive(Parser)]
+#[clap(author, version, about, long_about = None)]
+struct Cmd {
+    #[clap(subcommand)]
+    verb: Verb,
 
-#[derive(Debug, Deserialize)]
-struct AppConfig {
-    tokens: Tokens,
-    banks: Vec<Bank>,
-    sync_settings: SyncSettings,
-}
+    /// Config file. Repeat to layer several, later files overriding earlier
+    /// ones, with their banks combined. Defaults to the first found of
+    /// `$XDG_CONFIG_HOME/lunchmoney-fintoc-sync/config.toml`,
+    /// `~/.config/lunchmoney-fintoc-sync/config.toml` and `./config.toml`.
+    #[clap(long)]
+    config: Vec<String>,
 
-#[derive(Debug, Deserialize)]
-struct Tokens {
-    fintoc_secret_token: String,
-    lunch_money_api_token: String,
-}
+    #[clap(long)]
+    debug: bool,
 
-#[derive(Debug, Deserialize)]
-struct Bank {
-    name: String,
-    link_token: String,
-    accounts: Vec<Account>,
-}
+    /// Don't draw progress bars, print plain status lines instead
+    #[clap(long)]
+    no_progress: bool,
 
-#[derive(Debug, Deserialize, PartialEq, Clone, Copy)]
-enum AccountType {
-    Checking,
-    Savings,
-    Credit,
-}
+    /// Print only the command's output and errors, without progress bars or
+    /// status lines
+    #[clap(long)]
+    quiet: bool,
 
-#[derive(Debug, Deserialize)]
-struct Account {
-    name: String,
-    fintoc_account_id: String,
-    lunch_money_asset_id: String,
-    #[serde(rename = "type")]
-    account_type: AccountType,
-    #[serde(default)]
-    skip_movements: bool,
-}
+    /// Override `max_pages` from the config file
+    #[clap(long)]
+    max_pages: Option<usize>,
 
-#[derive(Debug, Deserialize)]
-struct SyncSettings {
-    default_start_from: String,
-}
+    /// Override `max_movements` from the config file
+    #[clap(long)]
+    max_movements: Option<usize>,
 
-#[derive(Parser)]
-#[clap(author, version, about, long_about = None)]
-struct Cmd {
-    #[clap(subcommand)]
-    verb: Verb,
+    /// Trust the root certificates in this PEM file too, e.g. those of a TLS
+    /// intercepting proxy. Overrides `http.ca_bundle`.
+    #[clap(long)]
+    ca_bundle: Option<String>,
 
-    #[clap(long, default_value = "config.toml")]
-    config: String,
+    /// Default concurrency for page prefetching, inserts and balance fetches.
+    /// `prefetch_pages`, `insert_concurrency` and `balance_concurrency` in the
+    /// config file take precedence.
+    #[clap(long)]
+    concurrency: Option<usize>,
+
+    /// Override a config value for this run, e.g.
+    /// `--set sync_settings.default_start_from=90d`. Can be repeated.
+    #[clap(long = "set", value_name = "KEY=VALUE")]
+    overrides: Vec<String>,
 
+    /// Mask account names, amounts and account numbers in human-readable
+    /// output, e.g. to share a run when asking for help. JSON output is left
+    /// as-is.
     #[clap(long)]
-    debug: bool,
+    redact_output: bool,
+
+    /// Output style for the `movements` and `assets` listings
+    #[clap(long, value_enum, default_value_t = OutputFormat::Line)]
+    format: OutputFormat,
+
+    /// Only warn when several accounts share a Fintoc account id or Lunch
+    /// Money asset id, instead of refusing to run
+    #[clap(long)]
+    allow_duplicates: bool,
+
+    /// Write every HTTP request and response to this file (`-` for stderr),
+    /// with tokens redacted, e.g. to attach to a bug report
+    #[clap(long, value_name = "FILE")]
+    dump_requests: Option<PathBuf>,
+
+    /// Write the command's output to this file (without colors) instead of
+    /// stdout. Status messages go to stderr.
+    #[clap(long)]
+    output_file: Option<PathBuf>,
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq)]
+enum OutputFormat {
+    /// One colored line per item
+    Line,
+    /// Aligned columns
+    Table,
+    /// Pretty JSON
+    Json,
 }
 
 #[derive(Subcommand)]
@@ -83,394 +111,2105 @@ enum Verb {
         bank_name: String,
         #[clap(default_value = "")]
         account_name: String,
+        #[clap(flatten)]
+        pending: PendingFlags,
+    },
+    Assets {
+        /// Print the full asset definitions as pretty JSON, e.g. for backups.
+        /// Shorthand for `--format json`.
+        #[clap(long)]
+        json: bool,
     },
-    Assets,
     Accounts {
         #[clap(default_value = "")]
         bank_name: String,
     },
+    /// Save the raw Fintoc movements of the window to a JSON file, for
+    /// `sync --from-file`
+    Export {
+        /// File to write
+        #[clap(long, short)]
+        output: PathBuf,
+        #[clap(default_value = "")]
+        bank_name: String,
+        #[clap(default_value = "")]
+        account_name: String,
+    },
+    /// Convert one Fintoc movement (JSON, from a file or stdin) with an
+    /// account's settings and print the resulting transaction
+    TestConvert {
+        bank_name: String,
+        account_name: String,
+        /// File holding the movement. Read from stdin when omitted.
+        #[clap(long, short)]
+        file: Option<PathBuf>,
+        /// Asset id for the transaction, instead of the account's
+        #[clap(long)]
+        asset_id: Option<u64>,
+        /// Currency code to use instead of the movement's
+        #[clap(long)]
+        currency: Option<String>,
+    },
+    /// Print the accounts on each bank's Fintoc link as config.toml entries
+    Discover {
+        #[clap(default_value = "")]
+        bank_name: String,
+    },
     Sync {
         #[clap(default_value = "")]
         bank_name: String,
         #[clap(default_value = "")]
         account_name: String,
+        /// Show the transactions that aren't in Lunch Money yet and the balance
+        /// changes, without writing anything
+        #[clap(long)]
+        dry_run: bool,
+        /// Don't ask before writing to an asset for the first time, e.g. for
+        /// scheduled runs
+        #[clap(long)]
+        yes: bool,
+        /// Read movements from a file written by `export` instead of Fintoc
+        #[clap(long)]
+        from_file: Option<PathBuf>,
+        /// Only refresh balances, skipping movements for every account
+        #[clap(long)]
+        only_balances: bool,
+        /// Start each account's window at its last successful sync, instead of
+        /// `default_start_from`
+        #[clap(long)]
+        since_last: bool,
+        /// Update balances outside an account's `min_balance`/`max_balance`
+        /// instead of refusing them
+        #[clap(long)]
+        force: bool,
+        #[clap(flatten)]
+        pending: PendingFlags,
+    },
+    /// Sync history since a date, one window at a time, resuming where a
+    /// previous backfill stopped
+    Backfill {
+        /// First day to backfill, as YYYY-MM-DD
+        #[clap(long)]
+        since: NaiveDate,
+        /// Length of each window, in months
+        #[clap(long, default_value_t = 1)]
+        step_months: u32,
+        /// Don't ask before writing to an asset for the first time
+        #[clap(long)]
+        yes: bool,
+        #[clap(default_value = "")]
+        bank_name: String,
+        #[clap(default_value = "")]
+        account_name: String,
+    },
+    /// Re-attempt inserting the transactions stored in the dead-letter file
+    Retry,
+    /// Forget the last sync and backfill progress stored for the selected
+    /// accounts, or for every account (after a confirmation) when none is
+    /// named
+    PruneState {
+        #[clap(default_value = "")]
+        bank_name: String,
+        #[clap(default_value = "")]
+        account_name: String,
+        /// Don't ask before clearing the whole state file
+        #[clap(long)]
+        yes: bool,
+    },
+    /// Tag synced transactions (those with an external id) of a Lunch Money
+    /// asset for deletion, e.g. to clean up after a botched backfill. Lunch
+    /// Money's API can't delete transactions, so the tagged ones are then
+    /// deleted in bulk from its web UI.
+    Delete {
+        /// Lunch Money asset whose transactions to tag
+        #[clap(long)]
+        asset_id: u64,
+        /// First day to look at, as YYYY-MM-DD
+        #[clap(long)]
+        since: NaiveDate,
+        /// Last day to look at (inclusive), as YYYY-MM-DD. Defaults to today.
+        #[clap(long)]
+        until: Option<NaiveDate>,
+        /// File with one external id per line. Only those transactions are
+        /// tagged.
+        #[clap(long)]
+        external_ids: Option<PathBuf>,
+        /// Tag marking the transactions to delete
+        #[clap(long, default_value = "fintoc-sync-duplicate")]
+        tag: String,
+        /// List the transactions that would be tagged without tagging them
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Compare Fintoc movements with the Lunch Money transactions of the same
+    /// window without writing anything, unless `--fix` is passed
+    Verify {
+        #[clap(default_value = "")]
+        bank_name: String,
+        #[clap(default_value = "")]
+        account_name: String,
+        /// Print the differences as JSON. Shorthand for `--format json`.
+        #[clap(long)]
+        json: bool,
+        /// Insert the movements missing from Lunch Money, leaving everything
+        /// else untouched
+        #[clap(long)]
+        fix: bool,
+        /// With `--fix`, only say how many transactions would be inserted
+        #[clap(long, requires = "fix")]
+        dry_run: bool,
+        /// With `--fix`, don't ask before inserting
+        #[clap(long, requires = "fix")]
+        yes: bool,
+    },
+    /// Summarize the Fintoc movements of the window without writing anything
+    Stats {
+        #[clap(default_value = "")]
+        bank_name: String,
+        #[clap(default_value = "")]
+        account_name: String,
+    },
+    /// Print the window a sync would use for each selected account, without
+    /// fetching anything
+    Window {
+        #[clap(default_value = "")]
+        bank_name: String,
+        #[clap(default_value = "")]
+        account_name: String,
+        /// Resolve the window as `sync --since-last` would
+        #[clap(long)]
+        since_last: bool,
+    },
+    /// Check the configuration against Lunch Money without syncing anything
+    Doctor,
+    /// Print a table of every configured asset, flagging currency mismatches,
+    /// missing assets and accounts Fintoc no longer returns
+    Audit {
+        #[clap(default_value = "")]
+        bank_name: String,
+        #[clap(default_value = "")]
+        account_name: String,
     },
+    /// Print the parsed configuration, with tokens redacted
+    Config,
+    /// Print the version, git commit and toolchain this binary was built
+    /// with, for bug reports
+    BuildInfo,
+    /// Print the currencies Fintoc amounts can be converted from, with their
+    /// minor-unit exponent and display format
+    Currencies,
+    /// Run offline checks of the conversion pipeline against bundled
+    /// fixtures, to confirm the build works without credentials
+    SelfCheck,
+}
+
+/// Per-run override of `include_pending`. The flag wins over the config.
+#[derive(Args, Debug)]
+struct PendingFlags {
+    /// Include pending movements, whatever `include_pending` says
+    #[clap(long, conflicts_with = "exclude_pending")]
+    include_pending: bool,
+    /// Leave out pending movements, whatever `include_pending` says
+    #[clap(long)]
+    exclude_pending: bool,
+}
+
+impl PendingFlags {
+    fn include_pending(&self) -> Option<bool> {
+        match (self.include_pending, self.exclude_pending) {
+            (true, _) => Some(true),
+            (_, true) => Some(false),
+            _ => None,
+        }
+    }
+}
+
+/// Heading announcing a sync window. Commands print it again when a bank with
+/// its own `default_start_from` changes the window.
+fn time_period(start_date: DateTime<Utc>, end_date: DateTime<Utc>) -> String {
+    format!(
+        "Time period: {} UTC to {} UTC",
+        start_date.format("%Y-%m-%d %H:%M:%S"),
+        end_date.format("%Y-%m-%d %H:%M:%S"),
+    )
 }
 
 async fn cmd_list_fintoc_transactions(
-    client: &HttpsClient,
-    config: &AppConfig,
+    syncer: &Syncer,
     bank_name: &str,
     account_name: &str,
-    _debug: bool,
+    format: OutputFormat,
 ) -> Result<()> {
-    let banks_to_list = if bank_name.is_empty() {
-        config.banks.iter().collect::<Vec<_>>()
-    } else {
-        config
-            .banks
-            .iter()
-            .filter(|b| b.name == bank_name)
-            .collect::<Vec<_>>()
-    };
-
-    let end_date: DateTime<Utc> = Local::now().into();
-    let start_date: DateTime<Utc> = (Local::now()
-        - chrono::Duration::from_std(
-            humantime::parse_duration(&config.sync_settings.default_start_from).unwrap(),
-        )
-        .unwrap())
-    .into();
-
-    println!(
-        "{}",
-        format!(
-            "Time period: {} UTC to {} UTC",
-            start_date.format("%Y-%m-%d %H:%M:%S"),
-            end_date.format("%Y-%m-%d %H:%M:%S"),
-        )
-        .bold()
-    );
+    let settings = &syncer.config().sync_settings;
 
-    for bank in banks_to_list {
-        let accounts_to_list = if account_name.is_empty() {
-            bank.accounts.iter().collect::<Vec<_>>()
-        } else {
-            bank.accounts
-                .iter()
-                .filter(|a| a.name == account_name)
-                .collect::<Vec<_>>()
-        };
-
-        for account in accounts_to_list {
-            println!(
-                "{}",
-                format!("Listing movements for {} - {}", bank.name, account.name).bold()
-            );
+    // With JSON every account goes into a single document, so headings are
+    // only status messages
+    let heading = |line: ColoredString| match format {
+        OutputFormat::Json => output::status(line),
+        _ => output::primary(line),
+    };
 
-            let credentials = AccountCredentials {
-                account_id: account.fintoc_account_id.clone(),
-                secret_token: config.tokens.fintoc_secret_token.clone(),
-                link_token: bank.link_token.clone(),
-            };
+    let mut all_transactions = Vec::new();
+    let mut last_start_from = None;
 
-            let movements =
-                fetch_fintoc_movements(client, &credentials, start_date, end_date).await?;
+    for (bank, account) in syncer.config().select_accounts(bank_name, account_name) {
+        let (start_date, end_date) = settings.window_for(bank)?;
+        if last_start_from.replace(settings.start_from(bank)) != Some(settings.start_from(bank)) {
+            heading(time_period(start_date, end_date).bold());
+        }
+        heading(format!("Listing movements for {} - {}", bank.name, account.name).bold());
 
-            // Convert to lunchmoney transactions
-            let transactions = movements
-                .into_iter()
-                .filter_map(|movement| {
-                    account
-                        .lunch_money_asset_id
-                        .parse::<u64>()
-                        .ok()
-                        .and_then(|asset_id| movement.to_lunchmoney_transaction(asset_id).ok())
-                })
-                .collect::<Vec<Transaction>>();
+        let transactions = syncer
+            .list_movements(bank, account, start_date, end_date)
+            .await
+            .with_context(|| AccountContext::new(bank, account))?;
 
-            for transaction in transactions {
-                println!("{}", transaction.to_colored_string());
+        match format {
+            OutputFormat::Line => {
+                let category_names = syncer.category_names(bank, account, &transactions).await?;
+                for transaction in transactions {
+                    output::primary(transaction.to_preview_string(&category_names));
+                }
+            }
+            OutputFormat::Table => {
+                let category_names = syncer.category_names(bank, account, &transactions).await?;
+                output::primary(table::transactions_table(&transactions, &category_names))
             }
+            OutputFormat::Json => all_transactions.extend(transactions),
         }
     }
 
+    if format == OutputFormat::Json {
+        output::primary(serde_json::to_string_pretty(&all_transactions)?);
+    }
+
     Ok(())
 }
 
-async fn cmd_list_lunch_money_assets(client: &HttpsClient, config: &AppConfig) -> Result<()> {
-    let assets = get_all_assets(client, &config.tokens.lunch_money_api_token).await?;
-    for asset in assets {
-        println!(
-            "{}",
+async fn cmd_export_fintoc_movements(
+    syncer: &Syncer,
+    path: &Path,
+    bank_name: &str,
+    account_name: &str,
+) -> Result<()> {
+    let mut dump = MovementDump::default();
+
+    for (bank, account) in syncer.config().select_accounts(bank_name, account_name) {
+        let (start_date, end_date) = syncer.config().sync_settings.window_for(bank)?;
+        // Banks can have their own windows, record the span of all of them
+        dump.start_date = Some(dump.start_date.map_or(start_date, |s| s.min(start_date)));
+        dump.end_date = Some(dump.end_date.map_or(end_date, |e| e.max(end_date)));
+
+        let movements = syncer
+            .fetch_movements(bank, account, start_date, end_date)
+            .await
+            .with_context(|| AccountContext::new(bank, account))?;
+
+        output::status(
             format!(
-                "{} - {}: {}",
-                asset.id.unwrap().to_string().blue().bold(),
-                asset.display_name.unwrap_or("Unnamed".to_string()),
-                asset.balance.0.to_string().green()
+                "Exported {} movements of {} - {}",
+                movements.len(),
+                bank.name,
+                account.name
             )
-            .bold()
+            .blue(),
         );
+        dump.insert(bank, account, movements);
     }
+
+    dump.save(path)?;
+    output::status(format!("Saved movements to {}", path.display()).green());
+
     Ok(())
 }
 
-async fn cmd_sync_fintoc_movements(
-    client: &HttpsClient,
-    config: &AppConfig,
+fn cmd_test_convert(
+    syncer: &Syncer,
     bank_name: &str,
     account_name: &str,
+    file: Option<&Path>,
+    asset_id: Option<u64>,
+    currency: Option<String>,
 ) -> Result<()> {
-    let end_date: DateTime<Utc> = Local::now().into();
-    let start_date: DateTime<Utc> = (Local::now()
-        - chrono::Duration::from_std(
-            humantime::parse_duration(&config.sync_settings.default_start_from).unwrap(),
-        )
-        .unwrap())
-    .into();
+    let Some((_, account)) = syncer
+        .config()
+        .select_accounts(bank_name, account_name)
+        .into_iter()
+        .next()
+    else {
+        bail!("No account {} in bank {}", account_name, bank_name);
+    };
 
-    let banks_to_sync = if bank_name.is_empty() {
-        config.banks.iter().collect::<Vec<_>>()
-    } else {
-        config
-            .banks
-            .iter()
-            .filter(|b| b.name == bank_name)
-            .collect::<Vec<_>>()
+    let json = match file {
+        Some(path) => std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?,
+        None => std::io::read_to_string(std::io::stdin()).context("Failed to read stdin")?,
     };
+    let mut movement: Movement =
+        serde_json::from_str(&json).context("Failed to parse the Fintoc movement")?;
+    if let Some(currency) = currency {
+        movement.currency = currency;
+    }
 
-    for bank in banks_to_sync {
-        let accounts_to_sync = if account_name.is_empty() {
-            bank.accounts.iter().collect::<Vec<_>>()
-        } else {
-            bank.accounts
-                .iter()
-                .filter(|a| a.name == account_name)
-                .collect::<Vec<_>>()
-        };
-        let _existing_count = 0;
+    let asset_id = match asset_id {
+        Some(asset_id) => asset_id,
+        None => account.asset_id()?,
+    };
+    let options = syncer.config().sync_settings.conversion_options(account)?;
+    let transaction = movement
+        .to_lunchmoney_transaction(asset_id, &options)
+        .map_err(|err| anyhow!("Failed to convert movement {}: {}", movement.id, err))?;
+
+    output::primary(serde_json::to_string_pretty(&transaction)?);
+    output::primary(transaction.to_preview_string(&HashMap::new()));
+
+    Ok(())
+}
+
+async fn cmd_list_lunch_money_assets(syncer: &Syncer, format: OutputFormat) -> Result<()> {
+    let assets = syncer.list_assets().await?;
+
+    match format {
+        OutputFormat::Line => {
+            for asset in assets {
+                output::primary(
+                    format!(
+                        "{} - {}: {}",
+                        asset.id.unwrap().to_string().blue().bold(),
+                        asset.display_name.unwrap_or("Unnamed".to_string()),
+                        currency::format_amount(asset.balance.0, Some(&asset.currency))
+                    )
+                    .bold(),
+                );
+            }
+        }
+        OutputFormat::Table => output::primary(table::assets_table(&assets)),
+        OutputFormat::Json => output::primary(serde_json::to_string_pretty(&assets)?),
+    }
+
+    Ok(())
+}
+
+/// Sets a flag on the first Ctrl-C so the sync can stop after the account in
+/// flight, and exits immediately on the second one.
+fn install_interrupt_handler() -> Arc<AtomicBool> {
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let flag = interrupted.clone();
+
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_err() {
+            return;
+        }
+        flag.store(true, Ordering::SeqCst);
+        output::diagnostic(
+            "Interrupt received, finishing the current account. Press Ctrl-C again to abort."
+                .yellow(),
+        );
+
+        if tokio::signal::ctrl_c().await.is_ok() {
+            std::process::exit(130);
+        }
+    });
+
+    interrupted
+}
+
+async fn cmd_sync_fintoc_movements(
+    syncer: &Syncer,
+    bank_name: &str,
+    account_name: &str,
+    yes: bool,
+    since_last: bool,
+) -> Result<()> {
+    let started_at = Utc::now();
+    let mut report = SyncReport::default();
+    let result = sync_accounts(
+        syncer,
+        bank_name,
+        account_name,
+        yes,
+        since_last,
+        &mut report,
+    )
+    .await;
+
+    if result.is_ok() {
+        report.print();
+    }
+    syncer.write_history(&report, result.as_ref().err(), started_at);
+    syncer.write_metrics(&report, result.is_ok() && report.failed_accounts() == 0);
+    syncer.notify(&report, result.as_ref().err()).await;
+
+    result?;
+    match report.failed_accounts() {
+        0 => Ok(()),
+        failed => Err(PartialSync {
+            failed,
+            total: report.accounts.len(),
+            unit: "accounts",
+        }
+        .into()),
+    }
+}
 
-        for account in accounts_to_sync {
-            println!(
-                "{}",
-                format!("Syncing {} - {}", bank.name, account.name).bold()
+/// Asks a question on stderr and returns whether the answer was "yes".
+fn confirm(question: String) -> Result<bool> {
+    eprint!(
+        "{}",
+        format!("{} Type \"yes\" to confirm: ", question)
+            .yellow()
+            .bold()
+    );
+    let mut answer = String::new();
+    std::io::stdin()
+        .read_line(&mut answer)
+        .context("Failed to read the confirmation")?;
+    Ok(answer.trim() == "yes")
+}
+
+/// Asks before the first write to an account's asset, showing the balance it
+/// would replace, so a wrong `lunch_money_asset_id` doesn't overwrite a
+/// manually maintained asset. Confirmed assets are remembered in the state
+/// file. Returns whether to go ahead with the account.
+async fn confirm_new_asset(
+    syncer: &Syncer,
+    state: &mut SyncState,
+    state_path: &Path,
+    bank: &Bank,
+    account: &Account,
+    yes: bool,
+) -> Result<bool> {
+    let asset_id = account.asset_id()?;
+    if state.knows_asset(asset_id) {
+        return Ok(true);
+    }
+
+    if !yes {
+        if !std::io::stdin().is_terminal() {
+            bail!(
+                "{} - {} would write to Lunch Money asset {} for the first time. Run interactively to confirm, or pass --yes",
+                bank.name,
+                account.name,
+                asset_id
             );
+        }
 
-            let credentials = AccountCredentials {
-                account_id: account.fintoc_account_id.clone(),
-                secret_token: config.tokens.fintoc_secret_token.clone(),
-                link_token: bank.link_token.clone(),
-            };
+        let change = syncer
+            .balance_change(bank, account)
+            .await
+            .with_context(|| AccountContext::new(bank, account))?;
+        let current = match change.current {
+            Some(current) => currency::format_amount_plain(current, Some(&change.currency)),
+            None => "unknown".to_string(),
+        };
+
+        let confirmed = confirm(format!(
+            "This is the first write to Lunch Money asset {}. Syncing {} - {} would change its balance from {} to {} {}.",
+            asset_id,
+            bank.name,
+            account.name,
+            current,
+            currency::format_amount_plain(change.new, Some(&change.currency)),
+            change.currency
+        ))?;
+        if !confirmed {
+            output::status(format!("Skipping {} - {}.", bank.name, account.name).yellow());
+            return Ok(false);
+        }
+    }
+
+    state.confirmed_assets.insert(asset_id);
+    state.save(state_path)?;
+    Ok(true)
+}
 
-            let (balance_amount, balance_currency) =
-                fintoc::fetch_fintoc_balance(client, &credentials, account.account_type).await?;
+/// The window to sync an account over. With `--since-last` it starts at the
+/// account's last successful sync, if there was one.
+fn sync_window(
+    syncer: &Syncer,
+    state: &SyncState,
+    bank: &Bank,
+    account: &Account,
+    since_last: bool,
+) -> Result<(DateTime<Utc>, DateTime<Utc>)> {
+    let (start_date, end_date) = syncer.config().sync_settings.window_for(bank)?;
+    if !since_last {
+        return Ok((start_date, end_date));
+    }
 
-            println!(
-                "{}",
+    match state.account(bank, account).last_sync {
+        Some(last_sync) => Ok((last_sync.min(end_date), end_date)),
+        None => {
+            output::status(
                 format!(
-                    "Found current account balance: {} {}",
-                    balance_amount, balance_currency
+                    "{} - {} wasn't synced before, using default_start_from.",
+                    bank.name, account.name
                 )
-                .blue()
+                .yellow(),
             );
-            let mut existing_count = 0;
+            Ok((start_date, end_date))
+        }
+    }
+}
 
-            if !account.skip_movements {
-                let movements =
-                    fetch_fintoc_movements(client, &credentials, start_date, end_date).await?;
+/// Prints the window `sync` would use for each selected account, and what it
+/// comes from.
+fn cmd_show_sync_window(
+    syncer: &Syncer,
+    bank_name: &str,
+    account_name: &str,
+    since_last: bool,
+    format: OutputFormat,
+) -> Result<()> {
+    let settings = &syncer.config().sync_settings;
+    let state = SyncState::load(Path::new(&settings.state_file))?;
+    let mut windows = Vec::new();
 
-                println!(
-                    "{}",
-                    format!("Fetched a total of {} movements.", movements.len()).blue()
-                );
+    for (bank, account) in syncer.config().select_accounts(bank_name, account_name) {
+        let (start_date, end_date) = sync_window(syncer, &state, bank, account, since_last)?;
+        let (since, until) = fintoc::window_params(start_date, end_date);
+        let origin = match state.account(bank, account).last_sync {
+            Some(_) if since_last => "last sync".to_string(),
+            _ if bank.default_start_from.is_some() => format!(
+                "{}'s default_start_from {}",
+                bank.name,
+                settings.start_from(bank)
+            ),
+            _ => format!("default_start_from {}", settings.start_from(bank)),
+        };
 
-                let pb = ProgressBar::new(movements.len() as u64);
-                pb.set_style(
-                    ProgressStyle::default_bar()
-                        .template("{msg}\n{wide_bar} {pos}/{len} ({eta})")?
-                        .progress_chars("=>-"),
-                );
+        if format == OutputFormat::Json {
+            windows.push(serde_json::json!({
+                "bank": bank.name,
+                "account": account.name,
+                "start_date": start_date,
+                "end_date": end_date,
+                "origin": origin,
+                "since": since,
+                "until": until,
+            }));
+            continue;
+        }
 
-                let lunchmoney_transactions = movements
-                    .into_iter()
-                    .filter_map(|movement| {
-                        account
-                            .lunch_money_asset_id
-                            .parse::<u64>()
-                            .ok()
-                            .and_then(|asset_id| movement.to_lunchmoney_transaction(asset_id).ok())
-                    })
-                    .collect::<Vec<Transaction>>();
-
-                let mut synced_transactions: Vec<u64> = Vec::new();
-
-                for transaction_chunk in &lunchmoney_transactions.into_iter().chunks(50) {
-                    let (ids, existing_count_chunk) = insert_transactions(
-                        client,
-                        &config.tokens.lunch_money_api_token,
-                        transaction_chunk.collect(),
-                    )
-                    .await?;
+        output::primary(format!("{} - {}", bank.name, account.name).bold());
+        for (label, date) in [("start", start_date), ("end", end_date)] {
+            output::primary(format!(
+                "  {}: {} ({} UTC)",
+                label,
+                date.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S %:z"),
+                date.format("%Y-%m-%d %H:%M:%S")
+            ));
+        }
+        output::primary(format!("  from: {}", origin));
+        output::primary(format!("  Fintoc query: since={} until={}", since, until));
+    }
 
-                    existing_count += existing_count_chunk;
-                    synced_transactions.extend(ids);
-                    pb.set_message(format!("Processing chunk..."));
-                    pb.inc(50);
-                }
+    if format == OutputFormat::Json {
+        output::primary(serde_json::to_string_pretty(&windows)?);
+    }
 
-                pb.finish_and_clear();
+    Ok(())
+}
 
-                if existing_count > 0 {
-                    println!(
-                        "{}",
-                        format!(
-                            "Finished syncing movements for {} - {} with {} existing transactions.",
-                            bank.name, account.name, existing_count
-                        )
-                        .blue()
-                    );
-                } else {
-                    println!(
-                        "{}",
-                        format!(
-                            "Finished syncing movements for {} - {}.",
-                            bank.name, account.name
-                        )
-                        .blue()
-                    );
-                }
-            } else {
-                println!(
-                    "{}",
-                    format!(
-                        "Skipping movements sync for {} - {} per configuration.",
-                        bank.name, account.name
-                    )
-                    .yellow()
-                );
-            }
+async fn sync_accounts(
+    syncer: &Syncer,
+    bank_name: &str,
+    account_name: &str,
+    yes: bool,
+    since_last: bool,
+    report: &mut SyncReport,
+) -> Result<()> {
+    syncer.validate().await?;
 
-            update_asset_balance(
-                client,
-                &config.tokens.lunch_money_api_token,
-                account.lunch_money_asset_id.parse()?,
-                balance_amount,
-                balance_currency,
-            )
-            .await?;
+    let state_path = Path::new(&syncer.config().sync_settings.state_file);
+    let mut state = SyncState::load(state_path)?;
 
-            println!(
-                "{}",
-                format!(
-                    "Updated asset balance successfully to {} {}",
-                    balance_amount, balance_currency
-                )
-                .to_string()
-                .blue()
+    let accounts = syncer.config().select_accounts(bank_name, account_name);
+    syncer.prefetch_balances(&accounts).await;
+
+    let interrupted = install_interrupt_handler();
+
+    for (bank, account) in accounts {
+        if interrupted.load(Ordering::SeqCst) {
+            break;
+        }
+        // One account failing, e.g. because its bank's link expired,
+        // shouldn't keep the others from syncing
+        let result: Result<Option<AccountReport>> = async {
+            if !confirm_new_asset(syncer, &mut state, state_path, bank, account, yes).await? {
+                return Ok(None);
+            }
+
+            let (start_date, end_date) = sync_window(syncer, &state, bank, account, since_last)?;
+            // Banks can have their own windows, record the span of all of them
+            report.window_start = Some(
+                report
+                    .window_start
+                    .map_or(start_date, |s| s.min(start_date)),
             );
+            report.window_end = Some(report.window_end.map_or(end_date, |e| e.max(end_date)));
 
-            // Finished sync! (either with or without movements)
-            if existing_count > 0 {
-                println!(
-                    "{}",
-                    format!(
-                        "Finished sync for {} - {} with {} existing transactions.",
-                        bank.name, account.name, existing_count
-                    )
-                    .bold()
-                );
-            } else {
-                println!(
-                    "{}",
-                    format!("Finished sync for {} - {}.", bank.name, account.name).bold()
-                );
+            let account_report = syncer
+                .sync_account(bank, account, start_date, end_date)
+                .await
+                .with_context(|| AccountContext::new(bank, account))?;
+
+            if account_report.failed == 0 && syncer.fetches_movements(account) {
+                state.account_mut(bank, account).last_sync = Some(end_date);
+                state.save(state_path)?;
+            }
+            Ok(Some(account_report))
+        }
+        .await;
+        match result {
+            Ok(Some(account_report)) => report.accounts.push(account_report),
+            Ok(None) => {}
+            Err(err) => {
+                let error = syncer.config().redact_secrets(&format!("{:#}", err));
+                output::status(format!("{} - {} failed: {}", bank.name, account.name, error).red());
+                report.accounts.push(AccountReport {
+                    bank: bank.name.clone(),
+                    account: account.name.clone(),
+                    error: Some(error),
+                    ..Default::default()
+                });
             }
         }
     }
 
+    report.interrupted = interrupted.load(Ordering::SeqCst);
+
     Ok(())
 }
 
-async fn cmd_list_fintoc_accounts(
-    client: &HttpsClient,
-    config: &AppConfig,
+/// Shows what `sync` would do to each account, its new transactions and
+/// balance change, without writing anything to Lunch Money.
+async fn cmd_plan_fintoc_movements(
+    syncer: &Syncer,
     bank_name: &str,
+    account_name: &str,
+    since_last: bool,
+    format: OutputFormat,
 ) -> Result<()> {
-    let banks_to_list = if bank_name.is_empty() {
-        config.banks.iter().collect::<Vec<_>>()
-    } else {
-        config
-            .banks
-            .iter()
-            .filter(|b| b.name == bank_name)
-            .collect::<Vec<_>>()
-    };
+    let mut plans = Vec::new();
+    let state = SyncState::load(Path::new(&syncer.config().sync_settings.state_file))?;
 
-    for bank in banks_to_list {
-        println!(
-            "{}",
-            format!("Listing accounts for bank: {}", bank.name).bold()
-        );
+    for (bank, account) in syncer.config().select_accounts(bank_name, account_name) {
+        output::status(format!("Planning {} - {}", bank.name, account.name).bold());
+        let (start_date, end_date) = sync_window(syncer, &state, bank, account, since_last)?;
 
-        // Make request to get link details
-        let request = hyper::Request::builder()
-            .method(hyper::Method::GET)
-            .uri(format!("https://api.fintoc.com/v1/links/{}", bank.link_token))
-            .header("Authorization", &config.tokens.fintoc_secret_token)
-            .header("Content-Type", "application/json")
-            .body(hyper::Body::empty())?;
-
-        let response = client.request(request).await?;
-        let status = response.status();
-        let bytes = hyper::body::to_bytes(response).await?;
-
-        if status != hyper::StatusCode::OK {
-            println!(
-                "{}",
-                format!(
-                    "Failed to get accounts for bank {}, code {}, error: {}",
-                    bank.name,
-                    status,
-                    String::from_utf8_lossy(&bytes)
-                )
-                .red()
-            );
+        let plan = syncer
+            .plan_account(bank, account, start_date, end_date)
+            .await
+            .with_context(|| AccountContext::new(bank, account))?;
+
+        if format == OutputFormat::Json {
+            plans.push(plan);
             continue;
         }
 
-        let link_data: serde_json::Value = serde_json::from_slice(&bytes)?;
-
-        if let Some(accounts) = link_data.get("accounts").and_then(|a| a.as_array()) {
-            for account in accounts {
-                if let (Some(id), Some(name), Some(account_type)) = (
-                    account.get("id").and_then(|i| i.as_str()),
-                    account.get("name").and_then(|n| n.as_str()),
-                    account.get("type").and_then(|t| t.as_str()),
-                ) {
-                    println!(
-                        "  {} - {} ({})",
-                        id.blue().bold(),
-                        name,
-                        account_type.green()
-                    );
+        let category_names = syncer
+            .category_names(bank, account, &plan.new_transactions)
+            .await?;
+        match format {
+            OutputFormat::Table if !plan.new_transactions.is_empty() => output::primary(
+                table::transactions_table(&plan.new_transactions, &category_names),
+            ),
+            _ => {
+                for transaction in &plan.new_transactions {
+                    output::primary(format!(
+                        "New: {}",
+                        transaction.to_preview_string(&category_names)
+                    ));
                 }
             }
-        } else {
-            println!("No accounts found or invalid response format");
         }
+
+        output::primary(
+            format!(
+                "{} new, {} already in Lunch Money, {} skipped.",
+                plan.new_transactions.len(),
+                plan.existing,
+                plan.skipped
+            )
+            .blue(),
+        );
+
+        let balance = &plan.balance;
+        let new_balance = currency::format_amount(balance.new, Some(&balance.currency));
+        match balance.current {
+            Some(current) if balance.changes() => output::primary(format!(
+                "Balance: {} -> {}",
+                currency::format_amount(current, Some(&balance.currency)),
+                new_balance
+            )),
+            Some(_) => output::primary(format!("Balance: {} (unchanged)", new_balance)),
+            None => output::primary(format!(
+                "Balance: {} (asset not found in Lunch Money)",
+                new_balance
+            )),
+        }
+    }
+
+    if format == OutputFormat::Json {
+        output::primary(serde_json::to_string_pretty(&plans)?);
     }
 
     Ok(())
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let cmd = Cmd::parse();
-
-    let config = Config::builder()
-        .add_source(config::File::with_name(&cmd.config))
-        .build()?;
+/// Runs the normal sync over consecutive windows from `since` until now.
+/// Windows already covered by a previous backfill are skipped, and a failed
+/// window is reported without stopping the ones after it. Like `sync`, the
+/// run is recorded in the history, metrics and notification, and fails with
+/// `PartialSync` when any window failed.
+async fn cmd_backfill_fintoc_movements(
+    syncer: &Syncer,
+    since: NaiveDate,
+    step_months: u32,
+    bank_name: &str,
+    account_name: &str,
+    yes: bool,
+) -> Result<()> {
+    if step_months == 0 {
+        bail!("--step-months must be at least 1");
+    }
 
-    let config: AppConfig = config.try_deserialize()?;
+    let started_at = Utc::now();
+    let mut progress = BackfillProgress::default();
+    let result = backfill_windows(
+        syncer,
+        since,
+        step_months,
+        bank_name,
+        account_name,
+        yes,
+        &mut progress,
+    )
+    .await;
 
-    let https = HttpsConnector::new();
-    let client = Client::builder().build::<_, hyper::Body>(https);
+    let BackfillProgress {
+        report,
+        failed_windows,
+        windows,
+    } = progress;
+    if result.is_ok() {
+        report.print();
 
+        if !failed_windows.is_empty() {
+            output::primary(
+                "Failed windows (run backfill again to retry them):"
+                    .red()
+                    .bold(),
+            );
+            for window in &failed_windows {
+                output::primary(format!("  {}", window));
+            }
+        }
+    }
+    syncer.write_history(&report, result.as_ref().err(), started_at);
+    syncer.write_metrics(&report, result.is_ok() && failed_windows.is_empty());
+    syncer.notify(&report, result.as_ref().err()).await;
+
+    result?;
+    match failed_windows.len() {
+        0 => Ok(()),
+        failed => Err(PartialSync {
+            failed,
+            total: windows,
+            unit: "windows",
+        }
+        .into()),
+    }
+}
+
+/// What a backfill went through so far, kept when it stops on an error.
+#[derive(Default)]
+struct BackfillProgress {
+    report: SyncReport,
+    /// "<bank> - <account>: <start> to <end>" of each window that failed
+    failed_windows: Vec<String>,
+    windows: usize,
+}
+
+async fn backfill_windows(
+    syncer: &Syncer,
+    since: NaiveDate,
+    step_months: u32,
+    bank_name: &str,
+    account_name: &str,
+    yes: bool,
+    progress: &mut BackfillProgress,
+) -> Result<()> {
+    syncer.validate().await?;
+
+    let state_path = Path::new(&syncer.config().sync_settings.state_file);
+    let mut state = SyncState::load(state_path)?;
+
+    let since = since.and_time(NaiveTime::MIN).and_utc();
+    let now = Utc::now();
+
+    let interrupted = install_interrupt_handler();
+
+    'accounts: for (bank, account) in syncer.config().select_accounts(bank_name, account_name) {
+        if !confirm_new_asset(syncer, &mut state, state_path, bank, account, yes).await? {
+            continue;
+        }
+
+        let mut window_start = match state.account(bank, account).backfill_cursor {
+            Some(cursor) if cursor > since => {
+                output::status(
+                    format!(
+                        "Resuming backfill of {} - {} from {}",
+                        bank.name,
+                        account.name,
+                        cursor.format("%Y-%m-%d")
+                    )
+                    .blue(),
+                );
+                cursor
+            }
+            _ => since,
+        };
+        // The cursor only moves past windows that, like all before them, synced
+        let mut contiguous = true;
+
+        while window_start < now {
+            if interrupted.load(Ordering::SeqCst) {
+                break 'accounts;
+            }
+
+            let window_end = window_start
+                .checked_add_months(Months::new(step_months))
+                .map_or(now, |end| end.min(now));
+            progress.windows += 1;
+
+            output::status(
+                format!(
+                    "Backfilling {} - {}: {} to {}",
+                    bank.name,
+                    account.name,
+                    window_start.format("%Y-%m-%d"),
+                    window_end.format("%Y-%m-%d")
+                )
+                .bold(),
+            );
+
+            let succeeded = match syncer
+                .sync_account(bank, account, window_start, window_end)
+                .await
+            {
+                Ok(account_report) => {
+                    let succeeded = account_report.failed == 0;
+                    progress.report.accounts.push(account_report);
+                    succeeded
+                }
+                Err(err) => {
+                    let error = syncer.config().redact_secrets(&format!("{:#}", err));
+                    output::status(
+                        format!(
+                            "Window {} to {} failed: {}",
+                            window_start.format("%Y-%m-%d"),
+                            window_end.format("%Y-%m-%d"),
+                            error
+                        )
+                        .red(),
+                    );
+                    progress.report.accounts.push(AccountReport {
+                        bank: bank.name.clone(),
+                        account: account.name.clone(),
+                        error: Some(error),
+                        ..Default::default()
+                    });
+                    false
+                }
+            };
+
+            if !succeeded {
+                contiguous = false;
+                progress.failed_windows.push(format!(
+                    "{} - {}: {} to {}",
+                    bank.name,
+                    account.name,
+                    window_start.format("%Y-%m-%d"),
+                    window_end.format("%Y-%m-%d")
+                ));
+            } else if contiguous {
+                let account_state = state.account_mut(bank, account);
+                account_state.backfill_cursor = Some(window_end);
+                if window_end == now && syncer.fetches_movements(account) {
+                    account_state.last_sync = Some(now);
+                }
+                state.save(state_path)?;
+            }
+
+            window_start = window_end;
+        }
+    }
+
+    progress.report.interrupted = interrupted.load(Ordering::SeqCst);
+
+    Ok(())
+}
+
+async fn cmd_list_fintoc_accounts(syncer: &Syncer, bank_name: &str) -> Result<()> {
+    let config = syncer.config();
+
+    for bank in config.select_banks(bank_name) {
+        output::primary(format!("Listing accounts for bank: {}", bank.name).bold());
+
+        let accounts = match fetch_link_accounts(
+            syncer.client(),
+            &config.tokens.fintoc_secret_token,
+            &bank.link_token,
+        )
+        .await
+        {
+            Ok(accounts) => accounts,
+            Err(err) => {
+                output::status(
+                    format!("Failed to get accounts for bank {}: {}", bank.name, err).red(),
+                );
+                continue;
+            }
+        };
+
+        for account in accounts {
+            if let (Some(id), Some(name), Some(account_type)) = (
+                account.get("id").and_then(|i| i.as_str()),
+                account.get("name").and_then(|n| n.as_str()),
+                account.get("type").and_then(|t| t.as_str()),
+            ) {
+                // Configured accounts go by their config name, with Fintoc's
+                // name as context
+                let official_name = account
+                    .get("official_name")
+                    .and_then(|n| n.as_str())
+                    .unwrap_or(name);
+                output::add_redacted_name(name);
+                output::add_redacted_name(official_name);
+                let label = match bank.account_by_fintoc_id(id) {
+                    Some(configured) => {
+                        format!("{} [Fintoc: {}]", configured.name, official_name)
+                    }
+                    None => format!("{} [not in config]", official_name),
+                };
+                output::primary(format!(
+                    "  {} - {} ({})",
+                    id.blue().bold(),
+                    label,
+                    account_type.green()
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Config `type` for a Fintoc account type, when there is a matching one.
+fn config_account_type(fintoc_type: &str) -> Option<&'static str> {
+    match fintoc_type {
+        "checking_account" | "sight_account" => Some("Checking"),
+        "savings_account" => Some("Savings"),
+        "credit_card" | "line_of_credit" => Some("Credit"),
+        _ => None,
+    }
+}
+
+async fn cmd_discover_fintoc_accounts(syncer: &Syncer, bank_name: &str) -> Result<()> {
+    let config = syncer.config();
+
+    for bank in config.select_banks(bank_name) {
+        output::status(format!("Discovering accounts for bank: {}", bank.name).bold());
+
+        let accounts = fetch_link_accounts(
+            syncer.client(),
+            &config.tokens.fintoc_secret_token,
+            &bank.link_token,
+        )
+        .await
+        .with_context(|| format!("Failed to get accounts for bank {}", bank.name))?;
+
+        output::primary(format!("# Accounts on the link of {}", bank.name));
+
+        for account in accounts {
+            let field = |name: &str| account.get(name).and_then(|v| v.as_str()).unwrap_or("");
+            let id = field("id");
+            let fintoc_type = field("type");
+            output::add_redacted_name(field("name"));
+            output::add_redacted_name(field("official_name"));
+
+            output::primary("");
+            let configured = bank.account_by_fintoc_id(id);
+            match configured {
+                Some(configured) => output::primary(format!(
+                    "# {} - Fintoc: {} ({}, {})",
+                    configured.name,
+                    field("official_name"),
+                    fintoc_type,
+                    field("currency")
+                )),
+                None => output::primary(format!(
+                    "# {} ({}, {})",
+                    field("official_name"),
+                    fintoc_type,
+                    field("currency")
+                )),
+            }
+            if configured.is_some() {
+                output::primary("# Already in config.toml");
+            }
+            output::primary("[[banks.accounts]]");
+            output::primary(format!("name = {:?}", field("name")));
+            output::primary(format!("fintoc_account_id = {:?}", id));
+            output::primary("lunch_money_asset_id = \"\" # see `assets`");
+            match config_account_type(fintoc_type) {
+                Some(account_type) => output::primary(format!("type = {:?}", account_type)),
+                None => output::primary("type = \"\" # Checking, Savings or Credit"),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn cmd_audit_config(
+    syncer: &Syncer,
+    bank_name: &str,
+    account_name: &str,
+    format: OutputFormat,
+) -> Result<()> {
+    let audits = syncer.audit(bank_name, account_name).await?;
+
+    if format == OutputFormat::Json {
+        output::primary(serde_json::to_string_pretty(&audits)?);
+        return Ok(());
+    }
+
+    output::primary(table::audit_table(&audits));
+
+    let problems = audits.iter().filter(|audit| !audit.status.is_ok()).count();
+    if problems == 0 {
+        output::status(format!("All {} assets look good.", audits.len()).green());
+    } else {
+        output::status(format!("{} of {} assets need attention.", problems, audits.len()).yellow());
+    }
+
+    Ok(())
+}
+
+fn cmd_prune_state(syncer: &Syncer, bank_name: &str, account_name: &str, yes: bool) -> Result<()> {
+    let state_path = Path::new(&syncer.config().sync_settings.state_file);
+    let mut state = SyncState::load(state_path)?;
+
+    let accounts = syncer.config().select_accounts(bank_name, account_name);
+
+    if bank_name.is_empty() && account_name.is_empty() {
+        if !yes
+            && !confirm(format!(
+                "Clear the stored progress of all {} accounts in {}?",
+                state.accounts.len(),
+                state_path.display()
+            ))?
+        {
+            output::status("Nothing was cleared.".bold());
+            return Ok(());
+        }
+        state.accounts.clear();
+    } else {
+        for (bank, account) in &accounts {
+            state.clear_account(bank, account);
+        }
+    }
+
+    state.save(state_path)?;
+    output::status(
+        format!(
+            "Cleared the stored progress of {}. The next backfill starts over.",
+            if bank_name.is_empty() && account_name.is_empty() {
+                "every account".to_string()
+            } else {
+                accounts
+                    .iter()
+                    .map(|(bank, account)| format!("{} - {}", bank.name, account.name))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            }
+        )
+        .green(),
+    );
+
+    Ok(())
+}
+
+async fn cmd_retry_failed_transactions(syncer: &Syncer) -> Result<()> {
+    let dead_letter_file = &syncer.config().sync_settings.dead_letter_file;
+
+    output::status(format!("Retrying failed transactions from {}", dead_letter_file).bold());
+
+    let outcome = syncer.retry_failed().await?;
+
+    if outcome.inserted_ids.is_empty() && outcome.existing_count == 0 && outcome.failed.is_empty() {
+        output::status("No failed transactions to retry.".bold());
+        return Ok(());
+    }
+
+    output::status(
+        format!(
+            "Inserted {} transactions, {} already existed, {} still failing.",
+            outcome.inserted_ids.len(),
+            outcome.existing_count,
+            outcome.failed.len()
+        )
+        .blue(),
+    );
+
+    Ok(())
+}
+
+async fn cmd_delete_transactions(
+    syncer: &Syncer,
+    asset_id: u64,
+    since: NaiveDate,
+    until: Option<NaiveDate>,
+    external_ids: Option<&Path>,
+    tag: &str,
+    dry_run: bool,
+) -> Result<()> {
+    let until = until.unwrap_or_else(|| Utc::now().date_naive());
+    if until < since {
+        bail!("--until must not be before --since");
+    }
+
+    let wanted = match external_ids {
+        Some(path) => Some(
+            std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read external ids from {}", path.display()))?
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(str::to_string)
+                .collect::<HashSet<_>>(),
+        ),
+        None => None,
+    };
+
+    let transactions = syncer
+        .list_transactions(asset_id, since, until)
+        .await?
+        .into_iter()
+        .filter(|transaction| match (&transaction.external_id, &wanted) {
+            (Some(external_id), Some(wanted)) => wanted.contains(external_id),
+            (Some(_), None) => true,
+            (None, _) => false,
+        })
+        .collect::<Vec<_>>();
+
+    let matching = transactions.len();
+    let transactions = transactions
+        .into_iter()
+        .filter_map(|transaction| {
+            let update = transaction.tag_update(tag)?;
+            Some((transaction, update))
+        })
+        .collect::<Vec<_>>();
+    if transactions.len() < matching {
+        output::status(
+            format!(
+                "{} matching transactions are already tagged {:?}.",
+                matching - transactions.len(),
+                tag
+            )
+            .bold(),
+        );
+    }
+
+    if transactions.is_empty() {
+        output::status("No matching transactions.".bold());
+        return Ok(());
+    }
+
+    for (transaction, _) in &transactions {
+        output::primary(format!(
+            "{} - {}: {} [{}] (id {})",
+            transaction.date.format("%Y-%m-%d"),
+            transaction.payee.as_deref().unwrap_or("Unknown"),
+            currency::format_amount(transaction.amount.0, transaction.currency.as_deref()),
+            transaction.external_id.as_deref().unwrap_or_default(),
+            transaction.id
+        ));
+    }
+
+    if dry_run {
+        output::status(
+            format!(
+                "Dry run: would tag {} transactions from asset {} with {:?}.",
+                transactions.len(),
+                asset_id,
+                tag
+            )
+            .blue(),
+        );
+        return Ok(());
+    }
+
+    let confirmed = confirm(format!(
+        "Tag these {} transactions from asset {} with {:?} for deletion?",
+        transactions.len(),
+        asset_id,
+        tag
+    ))?;
+    if !confirmed {
+        output::status("Nothing was tagged.".bold());
+        return Ok(());
+    }
+
+    let mut tagged = 0;
+    let mut failed = 0;
+    for (transaction, update) in &transactions {
+        match syncer
+            .update_transaction(asset_id, transaction.id, update)
+            .await
+        {
+            Ok(()) => tagged += 1,
+            Err(err) => {
+                failed += 1;
+                output::status(format!("{:#}", err).red());
+            }
+        }
+    }
+
+    let summary = format!("Tagged {} transactions, {} failed.", tagged, failed);
+    if failed > 0 {
+        output::status(summary.red());
+    } else {
+        output::status(summary.blue());
+    }
+    if tagged > 0 {
+        output::status(format!(
+            "Filter by the {:?} tag in Lunch Money's transactions view to select and delete them.",
+            tag
+        ));
+    }
+
+    Ok(())
+}
+
+/// How `verify --fix` inserts the movements missing from Lunch Money.
+#[derive(Clone, Copy)]
+struct GapFix {
+    dry_run: bool,
+    yes: bool,
+}
+
+async fn cmd_verify_fintoc_movements(
+    syncer: &Syncer,
+    bank_name: &str,
+    account_name: &str,
+    format: OutputFormat,
+    fix: Option<GapFix>,
+) -> Result<()> {
+    if fix.is_some_and(|fix| !fix.dry_run) {
+        syncer.validate().await?;
+    }
+
+    let mut reconciliations = Vec::new();
+    let mut filled = 0;
+
+    for (bank, account) in syncer.config().select_accounts(bank_name, account_name) {
+        let (start_date, end_date) = syncer.config().sync_settings.window_for(bank)?;
+
+        if account.skip_movements {
+            if format != OutputFormat::Json {
+                output::primary(format!("Verifying {} - {}", bank.name, account.name).bold());
+                output::primary("Movements are not synced for this account, skipping.".yellow());
+            }
+            continue;
+        }
+
+        let reconciliation = syncer
+            .verify_account(bank, account, start_date, end_date)
+            .await
+            .with_context(|| AccountContext::new(bank, account))?;
+
+        if format == OutputFormat::Json {
+            let account_filled = match fix {
+                Some(fix) => fill_gaps(syncer, bank, account, &reconciliation, fix).await?,
+                None => 0,
+            };
+            filled += account_filled;
+            reconciliations.push(serde_json::json!({
+                "bank": bank.name,
+                "account": account.name,
+                "reconciliation": reconciliation,
+                "filled": account_filled,
+            }));
+            continue;
+        }
+
+        output::primary(format!("Verifying {} - {}", bank.name, account.name).bold());
+        if reconciliation.is_clean() {
+            output::primary("Lunch Money matches Fintoc.".green());
+            continue;
+        }
+
+        for line in reconciliation.diff_lines() {
+            output::primary(line);
+        }
+
+        output::primary(
+            format!(
+                "{} missing, {} not in Fintoc, {} mismatched.",
+                reconciliation.missing.len(),
+                reconciliation.orphans.len(),
+                reconciliation.mismatched.len()
+            )
+            .bold(),
+        );
+
+        if let Some(fix) = fix {
+            filled += fill_gaps(syncer, bank, account, &reconciliation, fix).await?;
+        }
+    }
+
+    if format == OutputFormat::Json {
+        output::primary(serde_json::to_string_pretty(&reconciliations)?);
+    }
+    if fix.is_some_and(|fix| !fix.dry_run) {
+        output::status(format!("Filled {} gaps in Lunch Money.", filled).green());
+    }
+
+    Ok(())
+}
+
+/// Inserts an account's transactions that `verify` found missing from Lunch
+/// Money, after confirming. Returns how many were inserted.
+async fn fill_gaps(
+    syncer: &Syncer,
+    bank: &Bank,
+    account: &Account,
+    reconciliation: &Reconciliation,
+    fix: GapFix,
+) -> Result<usize> {
+    let missing = &reconciliation.missing;
+    if missing.is_empty() {
+        return Ok(0);
+    }
+
+    if fix.dry_run {
+        output::status(
+            format!(
+                "Would insert {} missing transactions into {} - {}.",
+                missing.len(),
+                bank.name,
+                account.name
+            )
+            .blue(),
+        );
+        return Ok(0);
+    }
+
+    if !fix.yes {
+        if !std::io::stdin().is_terminal() {
+            bail!(
+                "Inserting {} missing transactions into {} - {} needs confirmation. Run interactively, or pass --yes",
+                missing.len(),
+                bank.name,
+                account.name
+            );
+        }
+        let confirmed = confirm(format!(
+            "Insert {} missing transactions into {} - {}?",
+            missing.len(),
+            bank.name,
+            account.name
+        ))?;
+        if !confirmed {
+            output::status(format!("Skipping {} - {}.", bank.name, account.name).yellow());
+            return Ok(0);
+        }
+    }
+
+    let outcome = syncer
+        .fill_gaps(bank, account, missing.clone())
+        .await
+        .with_context(|| AccountContext::new(bank, account))?;
+
+    output::status(
+        format!(
+            "Inserted {} missing transactions into {} - {} ({} already there, {} failed).",
+            outcome.inserted_ids.len(),
+            bank.name,
+            account.name,
+            outcome.existing_count,
+            outcome.failed.len()
+        )
+        .blue(),
+    );
+    if !outcome.failed.is_empty() {
+        output::status(
+            format!(
+                "Failed transactions were saved to {}. Run `retry` to re-attempt them.",
+                syncer.config().sync_settings.dead_letter_file
+            )
+            .yellow(),
+        );
+    }
+    Ok(outcome.inserted_ids.len())
+}
+
+async fn cmd_show_movement_stats(
+    syncer: &Syncer,
+    bank_name: &str,
+    account_name: &str,
+) -> Result<()> {
+    let settings = &syncer.config().sync_settings;
+    let mut last_start_from = None;
+
+    for (bank, account) in syncer.config().select_accounts(bank_name, account_name) {
+        let (start_date, end_date) = settings.window_for(bank)?;
+        if last_start_from.replace(settings.start_from(bank)) != Some(settings.start_from(bank)) {
+            output::primary(time_period(start_date, end_date).bold());
+        }
+        output::primary(format!("Stats for {} - {}", bank.name, account.name).bold());
+
+        let stats = syncer
+            .movement_stats(bank, account, start_date, end_date)
+            .await
+            .with_context(|| AccountContext::new(bank, account))?;
+
+        if stats.count == 0 {
+            output::primary("  No movements in this period.");
+            continue;
+        }
+
+        let currency = stats.currency.as_deref();
+        output::primary(format!("  Movements: {}", stats.count));
+        output::primary(format!(
+            "  Inflow: {}",
+            currency::format_amount(stats.inflow, currency)
+        ));
+        output::primary(format!(
+            "  Outflow: {}",
+            currency::format_amount(stats.outflow, currency)
+        ));
+        output::primary(format!(
+            "  Net: {}",
+            currency::format_amount(stats.inflow + stats.outflow, currency)
+        ));
+
+        output::primary("  By type:");
+        for (movement_type, count) in &stats.by_type {
+            output::primary(format!("    {}: {}", movement_type, count));
+        }
+
+        output::primary("  Top payees:");
+        for payee in &stats.top_payees {
+            output::primary(format!(
+                "    {} - {} movements, {}",
+                payee.payee,
+                payee.count,
+                currency::format_amount(payee.total, currency)
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+async fn cmd_check_config(syncer: &Syncer) -> Result<()> {
+    syncer.validate().await?;
+    output::primary("Configuration looks good.".green());
+    Ok(())
+}
+
+/// Hides all but the last 4 characters of a secret, or all of it when it's
+/// too short for that to be safe.
+fn redact(secret: &str) -> String {
+    let len = secret.chars().count();
+    if len <= 8 {
+        return "****".to_string();
+    }
+    let suffix: String = secret.chars().skip(len - 4).collect();
+    format!("****{}", suffix)
+}
+
+fn cmd_show_config(config: &AppConfig, path: &str) -> Result<()> {
+    let settings = &config.sync_settings;
+    let (start_date, end_date) = settings.window()?;
+
+    output::primary(format!("Configuration from {}", path).bold());
+    output::primary("Tokens:");
+    output::primary(format!(
+        "  fintoc_secret_token: {}",
+        redact(&config.tokens.fintoc_secret_token)
+    ));
+    output::primary(format!(
+        "  lunch_money_api_token: {}",
+        redact(&config.tokens.lunch_money_api_token)
+    ));
+
+    output::primary("Sync settings:");
+    output::primary(format!(
+        "  default_start_from: {} ({} to {} UTC)",
+        settings.default_start_from,
+        start_date.format("%Y-%m-%d %H:%M:%S"),
+        end_date.format("%Y-%m-%d %H:%M:%S")
+    ));
+    if let Some(template) = &settings.notes_template {
+        output::primary(format!("  notes_template: {:?}", template));
+    }
+    if !settings.notes_fields.is_empty() {
+        output::primary(format!(
+            "  notes_fields: {:?} (separator {:?})",
+            settings.notes_fields, settings.notes_separator
+        ));
+    }
+    output::primary(format!("  dead_letter_file: {}", settings.dead_letter_file));
+    output::primary(format!("  state_file: {}", settings.state_file));
+    if let Some(metrics_file) = &settings.metrics_file {
+        output::primary(format!("  metrics_file: {}", metrics_file));
+    }
+    if let Some(history_dir) = &settings.history_dir {
+        output::primary(format!("  history_dir: {}", history_dir));
+    }
+    output::primary(format!(
+        "  category_rules: {}",
+        settings.category_rules.len()
+    ));
+    for rule in &settings.category_rules {
+        output::primary(format!("    {:?} -> {}", rule.pattern, rule.category_id));
+    }
+    output::primary(format!("  split_rules: {}", settings.split_rules.len()));
+    for rule in &settings.split_rules {
+        output::primary(format!(
+            "    {:?} -> {} installments{}",
+            rule.pattern,
+            rule.installments,
+            rule.tag
+                .as_ref()
+                .map(|tag| format!(", tagged {:?}", tag))
+                .unwrap_or_default()
+        ));
+    }
+    output::primary(format!(
+        "  verify_balance_update: {}",
+        settings.verify_balance_update
+    ));
+    output::primary(format!(
+        "  balance_as_of_refreshed_at: {}",
+        settings.balance_as_of_refreshed_at
+    ));
+    output::primary(format!(
+        "  skip_unchanged_balance: {}",
+        settings.skip_unchanged_balance
+    ));
+    output::primary(format!("  apply_rules: {}", settings.apply_rules));
+    output::primary(format!(
+        "  check_for_recurring: {}",
+        settings.check_for_recurring
+    ));
+    output::primary(format!("  insert_retries: {}", settings.insert_retries));
+    let concurrency = settings.concurrency();
+    output::primary(format!(
+        "  insert_concurrency: {}",
+        concurrency.insert_requests
+    ));
+    output::primary(format!("  max_pages: {}", settings.max_pages));
+    if let Some(max_movements) = settings.max_movements {
+        output::primary(format!("  max_movements: {}", max_movements));
+    }
+    output::primary(format!("  prefetch_pages: {}", concurrency.prefetch_pages));
+    output::primary(format!(
+        "  balance_concurrency: {}",
+        concurrency.balance_requests
+    ));
+    output::primary(format!("  composite_dedup: {}", settings.composite_dedup));
+    output::primary(format!("  update_existing: {}", settings.update_existing));
+    output::primary(format!(
+        "  written_fields_file: {}",
+        settings.written_fields_file
+    ));
+    output::primary(format!("  check_payee: {}", settings.check_payee));
+    output::primary(format!("  rounding: {:?}", settings.rounding));
+    output::primary(format!("  date_field: {:?}", settings.date_field));
+    output::primary(format!("  missing_date: {:?}", settings.missing_date));
+    output::primary(format!("  payee_case: {:?}", settings.payee_case));
+    output::primary(format!("  original_name: {:?}", settings.original_name));
+    output::primary(format!("  payee_sources: {:?}", settings.payee_sources));
+    if let Some(empty_payee) = &settings.empty_payee {
+        output::primary(format!("  empty_payee: {:?}", empty_payee));
+    }
+    output::primary(format!("  skip_zero_amount: {}", settings.skip_zero_amount));
+    output::primary(format!("  tags: {:?}", settings.tags));
+    output::primary(format!(
+        "  movement_type_tag: {}",
+        settings.movement_type_tag
+    ));
+    output::primary(format!("  include_pending: {}", settings.include_pending));
+    output::primary(format!("  strict_currency: {}", settings.strict_currency));
+    output::primary(format!("  short_fetch: {:?}", settings.short_fetch));
+    output::primary(format!(
+        "  match_asset_by_name: {}",
+        settings.match_asset_by_name
+    ));
+    output::primary(format!(
+        "  external_id_source: {:?}",
+        settings.external_id_source
+    ));
+
+    if let Some(notify) = &config.notify {
+        output::primary("Notify:");
+        output::primary(format!("  webhook_url: {}", redact(&notify.webhook_url)));
+    }
+
+    if !config.currency_display.is_empty() {
+        output::primary("Currency display:");
+        let mut codes: Vec<_> = config.currency_display.keys().collect();
+        codes.sort();
+        for code in codes {
+            output::primary(format!(
+                "  {}: {}",
+                code.to_uppercase(),
+                currency::format_amount_plain(1234.5, Some(code))
+            ));
+        }
+    }
+
+    output::primary("HTTP:");
+    output::primary(format!(
+        "  pool_idle_timeout: {}",
+        config.http.pool_idle_timeout
+    ));
+    output::primary(format!(
+        "  pool_max_idle_per_host: {}",
+        config.http.pool_max_idle_per_host
+    ));
+    output::primary(format!("  retries: {}", config.http.retries));
+    output::primary(format!("  retry_backoff: {}", config.http.retry_backoff));
+    if let Some(ca_bundle) = &config.http.ca_bundle {
+        output::primary(format!("  ca_bundle: {}", ca_bundle));
+    }
+    if let Some(version) = &config.http.min_tls_version {
+        output::primary(format!("  min_tls_version: {}", version));
+    }
+    if let Some(rate) = config.http.requests_per_second {
+        output::primary(format!("  requests_per_second: {}", rate));
+    }
+
+    for bank in &config.banks {
+        if bank.enabled {
+            output::primary(format!("Bank: {}", bank.name).bold());
+        } else {
+            output::primary(format!("Bank: {} {}", bank.name, "(disabled)".yellow()).bold());
+        }
+        output::primary(format!("  link_token: {}", redact(&bank.link_token)));
+        if let Some(start_from) = &bank.default_start_from {
+            let (start_date, _) = settings.window_for(bank)?;
+            output::primary(format!(
+                "  default_start_from: {} (from {} UTC)",
+                start_from,
+                start_date.format("%Y-%m-%d %H:%M:%S")
+            ));
+        }
+        if let Some(token) = &bank.lunch_money_api_token {
+            output::primary(format!("  lunch_money_api_token: {}", redact(token)));
+        }
+
+        for account in &bank.accounts {
+            if account.enabled {
+                output::primary(format!("  Account: {}", account.name.blue().bold()));
+            } else {
+                output::primary(format!(
+                    "  Account: {} {}",
+                    account.name.blue().bold(),
+                    "(disabled)".yellow()
+                ));
+            }
+            output::primary(format!("    type: {:?}", account.account_type));
+            output::primary(format!(
+                "    fintoc_account_id: {}",
+                account.fintoc_account_id
+            ));
+            if account.lunch_money_asset_id.is_empty() && settings.match_asset_by_name {
+                output::primary("    lunch_money_asset_id: (matched by name)");
+            } else {
+                output::primary(format!(
+                    "    lunch_money_asset_id: {}",
+                    account.lunch_money_asset_id
+                ));
+            }
+            if let Some(token) = &account.lunch_money_api_token {
+                output::primary(format!("    lunch_money_api_token: {}", redact(token)));
+            }
+            if account.skip_movements {
+                output::primary(format!("    skip_movements: {}", "true".yellow()));
+            } else {
+                output::primary("    skip_movements: false");
+            }
+            if let Some(payee) = &account.default_payee {
+                output::primary(format!("    default_payee: {:?}", payee));
+            }
+            if account.flip_sign {
+                output::primary("    flip_sign: true");
+            }
+            if account.preserve_asset_currency {
+                output::primary("    preserve_asset_currency: true");
+            }
+            if let Some(category_id) = account.default_category_id {
+                output::primary(format!("    default_category_id: {}", category_id));
+            }
+            if let Some(pattern) = &account.junk_payee_pattern {
+                output::primary(format!("    junk_payee_pattern: {:?}", pattern));
+            }
+            if let Some(sources) = &account.payee_sources {
+                output::primary(format!("    payee_sources: {:?}", sources));
+            }
+            for insert_override in account.insert_overrides() {
+                output::primary(format!("    {}", insert_override));
+            }
+            if let Some(scale) = account.amount_scale {
+                output::primary(format!("    amount_scale: {}", scale));
+            }
+            if let Some(source) = account.balance_source {
+                output::primary(format!("    balance_source: {:?}", source));
+            }
+            if let Some(min_balance) = account.min_balance {
+                output::primary(format!("    min_balance: {}", min_balance));
+            }
+            if let Some(max_balance) = account.max_balance {
+                output::primary(format!("    max_balance: {}", max_balance));
+            }
+            for (currency, asset_id) in &account.currency_assets {
+                output::primary(format!("    currency_assets.{}: {}", currency, asset_id));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn cmd_list_currencies(format: OutputFormat) -> Result<()> {
+    let currencies = currency::SUPPORTED_CURRENCIES;
+
+    match format {
+        OutputFormat::Line => {
+            for spec in currencies {
+                output::primary(format!(
+                    "{} - exponent {}, shown as {}",
+                    spec.code.blue().bold(),
+                    spec.exponent,
+                    currency::format_amount_plain(1234.5, Some(spec.code))
+                ));
+            }
+            output::status("Other currencies need `amount_scale` on the account.".to_string());
+        }
+        OutputFormat::Table => output::primary(table::currencies_table(currencies)),
+        OutputFormat::Json => output::primary(serde_json::to_string_pretty(currencies)?),
+    }
+
+    Ok(())
+}
+
+fn cmd_self_check() -> Result<()> {
+    let results = selfcheck::run()?;
+
+    for result in &results {
+        match &result.failure {
+            None => output::primary(format!("{} {}", "PASS".green().bold(), result.name)),
+            Some(failure) => output::primary(format!(
+                "{} {}: {}",
+                "FAIL".red().bold(),
+                result.name,
+                failure
+            )),
+        }
+    }
+
+    let failed = results.iter().filter(|result| !result.passed()).count();
+    if failed > 0 {
+        bail!("{} of {} self-checks failed", failed, results.len());
+    }
+    output::status(format!("All {} self-checks passed.", results.len()).green());
+    Ok(())
+}
+
+fn cmd_show_build_info() {
+    output::primary(format!(
+        "{} {}",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION")
+    ));
+    output::primary(format!("  git commit: {}", env!("BUILD_GIT_COMMIT")));
+    output::primary(format!("  rustc: {}", env!("BUILD_RUSTC_VERSION")));
+    output::primary(format!(
+        "  target: {} ({})",
+        env!("BUILD_TARGET"),
+        env!("BUILD_PROFILE")
+    ));
+    // No optional cargo features are defined yet
+    output::primary("  features: none");
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cmd = Cmd::parse();
+    debug::set_enabled(cmd.debug);
+    if cmd.quiet {
+        output::set_quiet();
+    } else if cmd.no_progress || !std::io::stderr().is_terminal() {
+        output::hide_progress();
+    }
+
+    // Doesn't need a config, so it works even when loading it fails
+    if let Verb::BuildInfo = cmd.verb {
+        cmd_show_build_info();
+        return Ok(());
+    }
+    if let Verb::Currencies = cmd.verb {
+        return cmd_list_currencies(cmd.format);
+    }
+    if let Verb::SelfCheck = cmd.verb {
+        return cmd_self_check();
+    }
+
+    let json = json_output(&cmd);
+    let config_paths = AppConfig::locate(&cmd.config);
+    let config_path = config_paths.join(", ");
+
+    let mut syncer = match load_syncer(&cmd, &config_paths) {
+        Ok(syncer) => syncer,
+        Err(err) => return fail(err, None, json),
+    };
+    // Showing the config or the window doesn't need Lunch Money
+    if !matches!(cmd.verb, Verb::Config | Verb::Window { .. }) {
+        if let Err(err) = syncer.resolve_asset_ids().await {
+            return fail(err, Some(syncer.config()), json);
+        }
+    }
+
+    match run(&syncer, cmd, &config_path).await {
+        Ok(()) => Ok(()),
+        Err(err) => fail(err, Some(syncer.config()), json),
+    }
+}
+
+fn json_output(cmd: &Cmd) -> bool {
+    cmd.format == OutputFormat::Json
+        || matches!(
+            cmd.verb,
+            Verb::Assets { json: true } | Verb::Verify { json: true, .. }
+        )
+}
+
+fn load_syncer(cmd: &Cmd, config_paths: &[String]) -> Result<Syncer> {
+    if let Some(path) = &cmd.output_file {
+        output::set_file(path)?;
+    }
+
+    let mut config = AppConfig::from_files_with_overrides(config_paths, &cmd.overrides)?;
+    currency::set_display_formats(&config.currency_display);
+    if cmd.redact_output && !json_output(cmd) {
+        output::set_redaction(
+            config
+                .banks
+                .iter()
+                .flat_map(|bank| &bank.accounts)
+                .map(|account| account.name.clone()),
+        );
+    }
+    if let Some(path) = &cmd.dump_requests {
+        http::dump_requests(path, config.secrets().cloned().collect())?;
+    }
+    if let Some(max_pages) = cmd.max_pages {
+        config.sync_settings.max_pages = max_pages;
+    }
+    if let Some(max_movements) = cmd.max_movements {
+        config.sync_settings.max_movements = Some(max_movements);
+    }
+    if let Some(ca_bundle) = &cmd.ca_bundle {
+        config.http.ca_bundle = Some(ca_bundle.clone());
+    }
+    if let Some(concurrency) = cmd.concurrency {
+        config.sync_settings.concurrency = Some(concurrency);
+    }
+    if let Verb::Movements { pending, .. } | Verb::Sync { pending, .. } = &cmd.verb {
+        if let Some(include_pending) = pending.include_pending() {
+            config.sync_settings.include_pending = include_pending;
+        }
+    }
+
+    let mut syncer = Syncer::new(config)?;
+    if let Verb::Sync {
+        from_file: Some(path),
+        ..
+    } = &cmd.verb
+    {
+        syncer.read_movements_from(path.clone());
+    }
+    if let Verb::Sync {
+        only_balances: true,
+        ..
+    } = &cmd.verb
+    {
+        syncer.only_balances();
+    }
+    if let Verb::Sync { force: true, .. } = &cmd.verb {
+        syncer.force_balance();
+    }
+    if cmd.allow_duplicates {
+        syncer.allow_duplicates();
+    }
+
+    Ok(syncer)
+}
+
+/// Prints the error, as an `ErrorReport` with JSON output, and exits with
+/// status 1, or 2 when only some accounts of a sync failed.
+fn fail(err: anyhow::Error, config: Option<&AppConfig>, json: bool) -> Result<()> {
+    let code = err
+        .downcast_ref::<PartialSync>()
+        .map_or(1, PartialSync::exit_code);
+
+    if !json {
+        output::diagnostic(format!("Error: {:?}", err));
+        std::process::exit(code);
+    }
+
+    let message = format!("{:#}", err);
+    let message = match config {
+        Some(config) => config.redact_secrets(&message),
+        None => message,
+    };
+    output::primary(ErrorReport::new(&err, message).to_json());
+    std::process::exit(code);
+}
+
+async fn run(syncer: &Syncer, cmd: Cmd, config_path: &str) -> Result<()> {
     match cmd.verb {
         Verb::Movements {
             bank_name,
             account_name,
+            ..
+        } => cmd_list_fintoc_transactions(syncer, &bank_name, &account_name, cmd.format).await,
+        Verb::Assets { json } => {
+            let format = if json { OutputFormat::Json } else { cmd.format };
+            cmd_list_lunch_money_assets(syncer, format).await
+        }
+        Verb::Export {
+            output,
+            bank_name,
+            account_name,
+        } => cmd_export_fintoc_movements(syncer, &output, &bank_name, &account_name).await,
+        Verb::TestConvert {
+            bank_name,
+            account_name,
+            file,
+            asset_id,
+            currency,
+        } => cmd_test_convert(
+            syncer,
+            &bank_name,
+            &account_name,
+            file.as_deref(),
+            asset_id,
+            currency,
+        ),
+        Verb::Accounts { bank_name } => cmd_list_fintoc_accounts(syncer, &bank_name).await,
+        Verb::Discover { bank_name } => cmd_discover_fintoc_accounts(syncer, &bank_name).await,
+        Verb::Sync {
+            bank_name,
+            account_name,
+            dry_run: true,
+            since_last,
+            ..
         } => {
-            cmd_list_fintoc_transactions(&client, &config, &bank_name, &account_name, cmd.debug)
+            cmd_plan_fintoc_movements(syncer, &bank_name, &account_name, since_last, cmd.format)
                 .await
         }
-        Verb::Assets => cmd_list_lunch_money_assets(&client, &config).await,
-        Verb::Accounts { bank_name } => {
-            cmd_list_fintoc_accounts(&client, &config, &bank_name).await
-        }
         Verb::Sync {
             bank_name,
             account_name,
-        } => cmd_sync_fintoc_movements(&client, &config, &bank_name, &account_name).await,
+            dry_run: false,
+            yes,
+            since_last,
+            ..
+        } => cmd_sync_fintoc_movements(syncer, &bank_name, &account_name, yes, since_last).await,
+        Verb::Backfill {
+            since,
+            step_months,
+            yes,
+            bank_name,
+            account_name,
+        } => {
+            cmd_backfill_fintoc_movements(
+                syncer,
+                since,
+                step_months,
+                &bank_name,
+                &account_name,
+                yes,
+            )
+            .await
+        }
+        Verb::Retry => cmd_retry_failed_transactions(syncer).await,
+        Verb::PruneState {
+            bank_name,
+            account_name,
+            yes,
+        } => cmd_prune_state(syncer, &bank_name, &account_name, yes),
+        Verb::Delete {
+            asset_id,
+            since,
+            until,
+            external_ids,
+            tag,
+            dry_run,
+        } => {
+            cmd_delete_transactions(
+                syncer,
+                asset_id,
+                since,
+                until,
+                external_ids.as_deref(),
+                &tag,
+                dry_run,
+            )
+            .await
+        }
+        Verb::Verify {
+            bank_name,
+            account_name,
+            json,
+            fix,
+            dry_run,
+            yes,
+        } => {
+            let format = if json { OutputFormat::Json } else { cmd.format };
+            let fix = fix.then_some(GapFix { dry_run, yes });
+            cmd_verify_fintoc_movements(syncer, &bank_name, &account_name, format, fix).await
+        }
+        Verb::Stats {
+            bank_name,
+            account_name,
+        } => cmd_show_movement_stats(syncer, &bank_name, &account_name).await,
+        Verb::Window {
+            bank_name,
+            account_name,
+            since_last,
+        } => cmd_show_sync_window(syncer, &bank_name, &account_name, since_last, cmd.format),
+        Verb::Doctor => cmd_check_config(syncer).await,
+        Verb::Audit {
+            bank_name,
+            account_name,
+        } => cmd_audit_config(syncer, &bank_name, &account_name, cmd.format).await,
+        Verb::Config => cmd_show_config(syncer.config(), config_path),
+        Verb::BuildInfo => {
+            cmd_show_build_info();
+            Ok(())
+        }
+        Verb::Currencies => cmd_list_currencies(cmd.format),
+        Verb::SelfCheck => cmd_self_check(),
     }
 }
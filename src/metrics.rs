@@ -0,0 +1,119 @@
+//! Sync metrics in the Prometheus text format, for node-exporter's textfile
+//! collector.
+
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+
+use crate::report::{AccountReport, SyncReport};
+
+/// Reads one per-account count from a report.
+type Count = fn(&AccountReport) -> u64;
+
+/// Escapes a label value as the text format requires.
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn account_labels(report: &AccountReport) -> String {
+    format!(
+        "bank=\"{}\",account=\"{}\"",
+        label(&report.bank),
+        label(&report.account)
+    )
+}
+
+/// Renders the metrics of a `sync` that finished at `finished_at`.
+pub fn render(report: &SyncReport, succeeded: bool, finished_at: DateTime<Utc>) -> String {
+    let mut out = String::new();
+
+    let _ = writeln!(
+        out,
+        "# HELP lunchmoney_fintoc_last_sync_timestamp_seconds When the last sync finished."
+    );
+    let _ = writeln!(
+        out,
+        "# TYPE lunchmoney_fintoc_last_sync_timestamp_seconds gauge"
+    );
+    let _ = writeln!(
+        out,
+        "lunchmoney_fintoc_last_sync_timestamp_seconds {}",
+        finished_at.timestamp()
+    );
+
+    let _ = writeln!(
+        out,
+        "# HELP lunchmoney_fintoc_last_sync_success Whether the last sync finished without errors."
+    );
+    let _ = writeln!(out, "# TYPE lunchmoney_fintoc_last_sync_success gauge");
+    let _ = writeln!(
+        out,
+        "lunchmoney_fintoc_last_sync_success {}",
+        u8::from(succeeded)
+    );
+
+    let counts: [(&str, &str, Count); 4] = [
+        ("inserted", "Transactions inserted", |r| r.inserted as u64),
+        ("existing", "Transactions already in Lunch Money", |r| {
+            r.existing
+        }),
+        ("skipped", "Movements skipped", |r| {
+            (r.skipped + r.zero_amount) as u64
+        }),
+        ("failed", "Transactions that failed to insert", |r| {
+            r.failed as u64
+        }),
+    ];
+    for (name, help, value) in counts {
+        let _ = writeln!(
+            out,
+            "# HELP lunchmoney_fintoc_transactions_{} {} by the last sync.",
+            name, help
+        );
+        let _ = writeln!(out, "# TYPE lunchmoney_fintoc_transactions_{} gauge", name);
+        for account in &report.accounts {
+            let _ = writeln!(
+                out,
+                "lunchmoney_fintoc_transactions_{}{{{}}} {}",
+                name,
+                account_labels(account),
+                value(account)
+            );
+        }
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP lunchmoney_fintoc_balance Account balance reported by Fintoc, in major units."
+    );
+    let _ = writeln!(out, "# TYPE lunchmoney_fintoc_balance gauge");
+    for account in &report.accounts {
+        if let (Some(balance), Some(currency)) = (account.balance, &account.currency) {
+            let _ = writeln!(
+                out,
+                "lunchmoney_fintoc_balance{{{},currency=\"{}\"}} {}",
+                account_labels(account),
+                label(currency),
+                balance
+            );
+        }
+    }
+
+    out
+}
+
+/// Writes the metrics file through a temporary file and a rename, so the
+/// collector never reads a partial file.
+pub fn write(path: &Path, contents: &str) -> Result<()> {
+    let temp_path = path.with_extension("prom.tmp");
+    fs::write(&temp_path, contents)
+        .with_context(|| format!("Failed to write metrics file {}", temp_path.display()))?;
+    fs::rename(&temp_path, path)
+        .with_context(|| format!("Failed to write metrics file {}", path.display()))
+}
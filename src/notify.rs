@@ -0,0 +1,62 @@
+//! Webhook notification sent when a `sync` finishes or fails.
+
+use anyhow::{bail, Result};
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Method, Request};
+use serde::Serialize;
+
+use crate::http::{self, ApiClient};
+use crate::report::SyncReport;
+
+/// Body POSTed to the webhook. `text` makes it display as-is in Slack
+/// incoming webhooks; other receivers can use the structured fields.
+#[derive(Debug, Serialize)]
+pub struct SyncNotification<'a> {
+    pub text: String,
+    /// "ok", "partial", "interrupted" or "failed"
+    pub status: &'static str,
+    pub report: &'a SyncReport,
+    pub error: Option<String>,
+}
+
+impl<'a> SyncNotification<'a> {
+    pub fn new(report: &'a SyncReport, error: Option<String>) -> Self {
+        let status = report.status(error.is_some());
+
+        let mut text = format!("Lunch Money sync {}: {}", status, report.totals_line());
+        if let Some(error) = &error {
+            text.push_str(&format!("\nError: {}", error));
+        }
+
+        Self {
+            text,
+            status,
+            report,
+            error,
+        }
+    }
+}
+
+pub async fn send_webhook(
+    client: &ApiClient,
+    url: &str,
+    notification: &SyncNotification<'_>,
+) -> Result<()> {
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(url)
+        .header(CONTENT_TYPE, "application/json; charset=utf-8")
+        .body(Body::from(serde_json::to_vec(notification)?))?;
+
+    let (status, bytes) = http::send(client, request).await?;
+
+    if !status.is_success() {
+        bail!(
+            "Webhook responded with code {}: {}",
+            status,
+            String::from_utf8_lossy(&bytes)
+        );
+    }
+
+    Ok(())
+}
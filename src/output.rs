@@ -0,0 +1,192 @@
+//! Where command output goes.
+//!
+//! A command's primary output (listings, the sync summary, the verify diff) is
+//! written to stdout, or to the `--output-file` with colors stripped. Status
+//! and progress messages go to stdout too, but move to stderr when an output
+//! file is set so they don't end up in it.
+//!
+//! Progress bars are drawn on stderr through a shared `MultiProgress`, so each
+//! account in flight gets its own line. Output printed while they are shown is
+//! written above them. They are hidden with `--no-progress` or without a
+//! terminal. `--quiet` hides them along with status messages, leaving only
+//! primary output and errors.
+//!
+//! With `--redact-output`, both are passed through `redact` first, so runs can
+//! be shared without account names, amounts or account numbers.
+//!
+//! Every line, including errors and debug messages on stderr, is written whole
+//! under one lock, so output from accounts synced concurrently never mixes
+//! within a line.
+
+use std::borrow::Cow;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, RwLock};
+
+use anyhow::{Context, Result};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget};
+use lazy_static::lazy_static;
+use regex::Regex;
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref OUTPUT_FILE: Mutex<Option<File>> = Mutex::new(None);
+    static ref WRITE_LOCK: Mutex<()> = Mutex::new(());
+    static ref ANSI_ESCAPE: Regex = Regex::new(r"\x1b\[[0-9;]*m").unwrap();
+    static ref PROGRESS: MultiProgress = MultiProgress::new();
+    static ref REDACTED_NAMES: RwLock<Option<Vec<String>>> = RwLock::new(None);
+    /// Amounts with a currency symbol or followed by a supported currency code
+    static ref AMOUNT: Regex = Regex::new(&format!(
+        r"-?[$€]\s?-?\d[\d,]*(?:\.\d+)?|-?\d[\d,]*(?:\.\d+)?\s?(?i:{})\b",
+        crate::currency::SUPPORTED_CURRENCIES
+            .iter()
+            .map(|spec| spec.code)
+            .collect::<Vec<_>>()
+            .join("|")
+    ))
+    .unwrap();
+    /// Account numbers and Chilean RUTs
+    static ref ACCOUNT_NUMBER: Regex =
+        Regex::new(r"\b\d{1,2}\.\d{3}\.\d{3}-[\dkK]\b|\b\d{7,}\b").unwrap();
+}
+
+/// Turns on redaction of human-readable output, masking `names` (the
+/// configured account names) along with amounts and account numbers.
+pub fn set_redaction(names: impl IntoIterator<Item = String>) {
+    *REDACTED_NAMES.write().unwrap() = Some(Vec::new());
+    for name in names {
+        add_redacted_name(name);
+    }
+}
+
+/// Masks another name, e.g. one returned by Fintoc, when redacting.
+pub fn add_redacted_name(name: impl Into<String>) {
+    let name = name.into();
+    if let Some(names) = REDACTED_NAMES.write().unwrap().as_mut() {
+        if !name.is_empty() && !names.contains(&name) {
+            names.push(name);
+            // Longest first, so a name containing another is masked whole
+            names.sort_by_key(|name| std::cmp::Reverse(name.chars().count()));
+        }
+    }
+}
+
+/// Keeps the first and last characters of a name.
+fn mask_name(name: &str) -> String {
+    let chars: Vec<char> = name.chars().collect();
+    match chars.as_slice() {
+        [first, middle @ .., last] if !middle.is_empty() => {
+            format!("{}{}{}", first, "*".repeat(middle.len()), last)
+        }
+        _ => "*".repeat(chars.len()),
+    }
+}
+
+/// Masks names, amounts and account numbers in `text` when `--redact-output`
+/// is set, and returns it unchanged otherwise.
+pub fn redact(text: &str) -> Cow<'_, str> {
+    let names = REDACTED_NAMES.read().unwrap();
+    let Some(names) = names.as_ref() else {
+        return Cow::Borrowed(text);
+    };
+
+    let mut text = text.to_string();
+    for name in names {
+        text = text.replace(name.as_str(), &mask_name(name));
+    }
+    let text = AMOUNT.replace_all(&text, "***");
+    let text = ACCOUNT_NUMBER.replace_all(&text, "****");
+    Cow::Owned(text.into_owned())
+}
+
+/// Hides progress bars, e.g. for `--no-progress`.
+pub fn hide_progress() {
+    PROGRESS.set_draw_target(ProgressDrawTarget::hidden());
+}
+
+/// Hides progress bars and drops status lines, for `--quiet`.
+pub fn set_quiet() {
+    hide_progress();
+    QUIET.store(true, Ordering::Relaxed);
+}
+
+/// Adds a bar to the shared progress display.
+pub fn progress_bar(bar: ProgressBar) -> ProgressBar {
+    PROGRESS.add(bar)
+}
+
+/// Sends primary output to `path` (truncating it) instead of stdout.
+pub fn set_file(path: &Path) -> Result<()> {
+    let file = File::create(path)
+        .with_context(|| format!("Failed to create output file {}", path.display()))?;
+    *OUTPUT_FILE.lock().unwrap() = Some(file);
+    Ok(())
+}
+
+fn writing_to_file() -> bool {
+    OUTPUT_FILE.lock().unwrap().is_some()
+}
+
+/// Writes a line of the command's primary output.
+pub fn primary(line: impl Display) {
+    let line = line.to_string();
+    let line = redact(&line);
+    match OUTPUT_FILE.lock().unwrap().as_mut() {
+        Some(file) => {
+            writeln!(file, "{}", ANSI_ESCAPE.replace_all(&line, ""))
+                .expect("failed writing to output file");
+        }
+        None => write_line(Stream::Stdout, &line),
+    }
+}
+
+/// Writes a status line, which never goes to the output file.
+pub fn status(line: impl Display) {
+    if QUIET.load(Ordering::Relaxed) {
+        return;
+    }
+    let line = line.to_string();
+    let line = redact(&line);
+    if writing_to_file() {
+        write_line(Stream::Stderr, &line);
+    } else {
+        write_line(Stream::Stdout, &line);
+    }
+}
+
+/// Writes an error or debug line to stderr.
+pub fn diagnostic(line: impl Display) {
+    let line = line.to_string();
+    write_line(Stream::Stderr, &redact(&line));
+}
+
+#[derive(Clone, Copy)]
+enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// Writes `line` and its newline in one go, above any progress bars. Write
+/// errors (e.g. a closed pipe) are ignored.
+fn write_line(stream: Stream, line: &str) {
+    let mut buffer = String::with_capacity(line.len() + 1);
+    buffer.push_str(line);
+    buffer.push('\n');
+
+    let _guard = WRITE_LOCK.lock().unwrap();
+    PROGRESS.suspend(|| {
+        let _ = match stream {
+            Stream::Stdout => {
+                let mut stdout = io::stdout().lock();
+                stdout
+                    .write_all(buffer.as_bytes())
+                    .and_then(|_| stdout.flush())
+            }
+            Stream::Stderr => io::stderr().lock().write_all(buffer.as_bytes()),
+        };
+    });
+}
@@ -0,0 +1,37 @@
+//! What a `sync` would change in Lunch Money, computed by `sync --dry-run`.
+
+use serde::Serialize;
+
+use crate::types::lunchmoney::Transaction;
+
+/// Planned changes for a single account.
+#[derive(Debug, Serialize)]
+pub struct SyncPlan {
+    pub bank: String,
+    pub account: String,
+    /// Transactions a sync would insert
+    pub new_transactions: Vec<Transaction>,
+    /// Movements already in Lunch Money
+    pub existing: usize,
+    /// Movements a sync would leave out (other currencies without an asset,
+    /// zero amounts)
+    pub skipped: usize,
+    pub balance: BalanceChange,
+}
+
+/// Asset balance before and after a sync.
+#[derive(Debug, Serialize)]
+pub struct BalanceChange {
+    /// Current Lunch Money balance, if the asset was found
+    pub current: Option<f64>,
+    /// Balance reported by Fintoc, which a sync would set
+    pub new: f64,
+    pub currency: String,
+}
+
+impl BalanceChange {
+    /// Whether a sync would change the balance.
+    pub fn changes(&self) -> bool {
+        self.current != Some(self.new)
+    }
+}
@@ -0,0 +1,221 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use colored::*;
+use serde::Serialize;
+
+use crate::fintoc::ShortFetch;
+use crate::output;
+
+/// Outcome of syncing a single account.
+#[derive(Debug, Default, Serialize)]
+pub struct AccountReport {
+    pub bank: String,
+    pub account: String,
+    pub inserted: usize,
+    pub existing: u64,
+    /// Existing transactions updated because their Fintoc data changed
+    pub updated: usize,
+    pub failed: usize,
+    /// Movements skipped because their currency didn't match the account's
+    pub skipped: usize,
+    /// Zero-amount movements skipped because of `skip_zero_amount`
+    pub zero_amount: usize,
+    /// Inserted transactions still pending at the bank
+    pub pending: usize,
+    /// Inserted transactions that have posted
+    pub posted: usize,
+    pub balance_updated: bool,
+    /// Balance left alone because the asset isn't a manual one, e.g. it's
+    /// linked through Plaid
+    pub balance_not_manual: bool,
+    /// Balance left alone because it was outside the account's bounds
+    pub balance_implausible: bool,
+    /// Balance left alone because the asset already had it
+    pub balance_unchanged: bool,
+    /// Balance reported by Fintoc, in major units
+    pub balance: Option<f64>,
+    pub currency: Option<String>,
+    /// Insert options overridden for this account, e.g. `apply_rules off`
+    pub insert_overrides: Vec<String>,
+    /// Set when fetching movements ended on an empty page after a full one,
+    /// so Fintoc may have stopped early
+    pub short_fetch: Option<ShortFetch>,
+    /// Movements dropped because their currency isn't supported, by currency
+    /// code
+    pub unsupported_currencies: BTreeMap<String, usize>,
+    /// Movements skipped because Fintoc sent them without a date
+    pub undated: usize,
+    /// Error that stopped this account's sync, e.g. an expired link
+    pub error: Option<String>,
+}
+
+impl AccountReport {
+    /// Counts and balance outcome, e.g. "3 inserted (2 posted, 1 pending), 2
+    /// existing, balance updated".
+    pub fn summary(&self) -> String {
+        let mut line = format!("{} inserted", self.inserted);
+        if self.pending + self.posted > 0 {
+            line.push_str(&format!(
+                " ({} posted, {} pending)",
+                self.posted, self.pending
+            ));
+        }
+        line.push_str(&format!(", {} existing", self.existing));
+        if self.updated > 0 {
+            line.push_str(&format!(", {} updated", self.updated));
+        }
+        if self.skipped > 0 {
+            line.push_str(&format!(", {} skipped", self.skipped));
+        }
+        if self.zero_amount > 0 {
+            line.push_str(&format!(", {} zero-amount skipped", self.zero_amount));
+        }
+        if self.failed > 0 {
+            line.push_str(&format!(", {} failed", self.failed));
+        }
+        if !self.unsupported_currencies.is_empty() {
+            line.push_str(&format!(
+                ", {} in unsupported currencies",
+                self.unsupported_currencies.values().sum::<usize>()
+            ));
+        }
+        if self.undated > 0 {
+            line.push_str(&format!(", {} without a date skipped", self.undated));
+        }
+        if let Some(short) = self.short_fetch {
+            line.push_str(&format!(
+                ", fetch ended on an empty page after {} movements (possibly incomplete)",
+                short.fetched
+            ));
+        }
+        if !self.insert_overrides.is_empty() {
+            line.push_str(&format!(
+                " [overrides: {}]",
+                self.insert_overrides.join(", ")
+            ));
+        }
+        if self.balance_updated {
+            line.push_str(", balance updated");
+        } else if self.balance_unchanged {
+            line.push_str(", balance unchanged");
+        } else if self.balance_implausible {
+            line.push_str(", balance refused (outside min/max)");
+        } else if self.balance_not_manual {
+            line.push_str(", balance skipped (not a manual asset)");
+        } else if self.failed > 0 {
+            line.push_str(", balance not updated");
+        }
+        line
+    }
+}
+
+/// Outcome of a whole `sync` run.
+#[derive(Debug, Default, Serialize)]
+pub struct SyncReport {
+    pub accounts: Vec<AccountReport>,
+    /// Set when the run was stopped early by an interrupt.
+    pub interrupted: bool,
+    /// Sync window, once it was computed
+    pub window_start: Option<DateTime<Utc>>,
+    pub window_end: Option<DateTime<Utc>>,
+}
+
+impl SyncReport {
+    /// Accounts whose sync stopped with an error, or left transactions in the
+    /// dead-letter file (which also kept their balance from being updated).
+    pub fn failed_accounts(&self) -> usize {
+        self.accounts
+            .iter()
+            .filter(|account| account.error.is_some() || account.failed > 0)
+            .count()
+    }
+
+    /// "ok", "partial", "interrupted" or "failed", for notifications and
+    /// history records.
+    pub fn status(&self, failed: bool) -> &'static str {
+        if failed {
+            "failed"
+        } else if self.failed_accounts() > 0 {
+            "partial"
+        } else if self.interrupted {
+            "interrupted"
+        } else {
+            "ok"
+        }
+    }
+
+    /// One-line totals over every account, e.g. for notifications.
+    pub fn totals_line(&self) -> String {
+        let inserted: usize = self.accounts.iter().map(|a| a.inserted).sum();
+        let existing: u64 = self.accounts.iter().map(|a| a.existing).sum();
+        let skipped: usize = self.accounts.iter().map(|a| a.skipped).sum();
+        let failed: usize = self.accounts.iter().map(|a| a.failed).sum();
+
+        format!(
+            "{} accounts synced, {} inserted, {} existing, {} skipped, {} failed",
+            self.accounts.len(),
+            inserted,
+            existing,
+            skipped,
+            failed
+        )
+    }
+
+    pub fn print(&self) {
+        output::primary("Summary:".bold());
+
+        if self.accounts.is_empty() {
+            output::primary("  No accounts were synced.");
+        }
+
+        for report in &self.accounts {
+            if let Some(error) = &report.error {
+                output::primary(
+                    format!("  {} - {}: failed: {}", report.bank, report.account, error).red(),
+                );
+                continue;
+            }
+            let line = format!(
+                "  {} - {}: {}",
+                report.bank,
+                report.account,
+                report.summary()
+            );
+            if (report.failed > 0 && !report.balance_updated) || report.balance_implausible {
+                output::primary(line.red());
+            } else {
+                output::primary(line);
+            }
+        }
+
+        let mut unsupported = BTreeMap::new();
+        for report in &self.accounts {
+            for (currency, count) in &report.unsupported_currencies {
+                *unsupported.entry(currency.clone()).or_default() += count;
+            }
+        }
+        if !unsupported.is_empty() {
+            output::primary(
+                format!(
+                    "Movements in unsupported currencies were not synced: {}. Set amount_scale on their accounts, or ask for the currencies to be supported.",
+                    currency_counts(&unsupported)
+                )
+                .yellow(),
+            );
+        }
+
+        if self.interrupted {
+            output::primary("Sync was interrupted, remaining accounts were not synced.".yellow());
+        }
+    }
+}
+
+/// Movement counts by currency, e.g. "ARS (2), BRL (1)".
+pub fn currency_counts(counts: &BTreeMap<String, usize>) -> String {
+    counts
+        .iter()
+        .map(|(currency, count)| format!("{} ({})", currency, count))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
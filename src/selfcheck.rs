@@ -0,0 +1,128 @@
+//! Offline checks of the conversion pipeline, run by `self-check`.
+//!
+//! Fixture movements and balances bundled with the binary are converted the
+//! same way a sync would, and the payees, amounts and balances compared with
+//! known outputs. Nothing is sent over the network, so no credentials are
+//! needed.
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::config::{AccountType, BalanceSource};
+use crate::currency;
+use crate::fintoc;
+use crate::types::fintoc::{Balance, ConversionOptions, Movement};
+
+const FIXTURES: &str = include_str!("../fixtures/selfcheck.json");
+
+/// Asset id given to converted fixture movements.
+const ASSET_ID: u64 = 1;
+
+#[derive(Debug, Deserialize)]
+struct Fixtures {
+    movements: Vec<MovementFixture>,
+    balances: Vec<BalanceFixture>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MovementFixture {
+    name: String,
+    movement: Movement,
+    payee: String,
+    amount: f64,
+}
+
+#[derive(Debug, Deserialize)]
+struct BalanceFixture {
+    name: String,
+    account_type: AccountType,
+    balance_source: Option<BalanceSource>,
+    currency: String,
+    balance: Balance,
+    expected: f64,
+}
+
+/// Outcome of one check. `failure` describes what didn't match.
+#[derive(Debug)]
+pub struct CheckResult {
+    pub name: String,
+    pub failure: Option<String>,
+}
+
+impl CheckResult {
+    pub fn passed(&self) -> bool {
+        self.failure.is_none()
+    }
+}
+
+/// Runs every bundled check.
+pub fn run() -> Result<Vec<CheckResult>> {
+    let fixtures: Fixtures =
+        serde_json::from_str(FIXTURES).context("Failed to parse self-check fixtures")?;
+
+    let mut results = Vec::new();
+    for fixture in &fixtures.movements {
+        results.push(CheckResult {
+            name: fixture.name.clone(),
+            failure: check_movement(fixture),
+        });
+    }
+    for fixture in &fixtures.balances {
+        results.push(CheckResult {
+            name: fixture.name.clone(),
+            failure: check_balance(fixture),
+        });
+    }
+    Ok(results)
+}
+
+fn check_movement(fixture: &MovementFixture) -> Option<String> {
+    let transaction = match fixture
+        .movement
+        .to_lunchmoney_transaction(ASSET_ID, &ConversionOptions::default())
+    {
+        Ok(transaction) => transaction,
+        Err(err) => return Some(format!("conversion failed: {}", err)),
+    };
+
+    let payee = transaction.payee.unwrap_or_default();
+    if payee != fixture.payee {
+        return Some(format!(
+            "payee is {:?}, expected {:?}",
+            payee, fixture.payee
+        ));
+    }
+    if !same_amount(transaction.amount.0, fixture.amount) {
+        return Some(format!(
+            "amount is {}, expected {}",
+            transaction.amount.0, fixture.amount
+        ));
+    }
+    None
+}
+
+fn check_balance(fixture: &BalanceFixture) -> Option<String> {
+    let Some(spec) = currency::find(&fixture.currency) else {
+        return Some(format!("currency {} is not supported", fixture.currency));
+    };
+    let minor = match fintoc::reported_balance(
+        fixture.account_type,
+        fixture.balance_source,
+        &fixture.balance,
+    ) {
+        Ok(minor) => minor,
+        Err(err) => return Some(format!("{:#}", err)),
+    };
+    let balance = currency::to_major_units(minor.0, spec.exponent);
+    if !same_amount(balance, fixture.expected) {
+        return Some(format!(
+            "balance is {}, expected {}",
+            balance, fixture.expected
+        ));
+    }
+    None
+}
+
+fn same_amount(a: f64, b: f64) -> bool {
+    (a - b).abs() < 0.00005
+}
@@ -0,0 +1,84 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{Account, Bank};
+
+/// What the tool remembers about an account between runs.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct AccountState {
+    /// End of the window of the last successful sync
+    pub last_sync: Option<DateTime<Utc>>,
+    /// End of the last backfill window that completed, along with every window
+    /// before it
+    pub backfill_cursor: Option<DateTime<Utc>>,
+}
+
+/// State persisted in the state file, keyed by `"<bank>/<account>"`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SyncState {
+    #[serde(default)]
+    pub accounts: BTreeMap<String, AccountState>,
+    /// Lunch Money assets the user agreed to write to
+    #[serde(default)]
+    pub confirmed_assets: BTreeSet<u64>,
+}
+
+impl SyncState {
+    /// Reads the state file. A missing file means nothing was synced yet.
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("Failed to read state file {}", path.display()))
+            }
+        };
+
+        serde_json::from_str(&contents)
+            .with_context(|| format!("Invalid state file {}", path.display()))
+    }
+
+    /// Writes the state file, replacing it atomically so an interrupted run
+    /// can't leave it half-written.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let temp_path = path.with_extension("json.tmp");
+        fs::write(&temp_path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("Failed to write state file {}", temp_path.display()))?;
+        fs::rename(&temp_path, path)
+            .with_context(|| format!("Failed to write state file {}", path.display()))
+    }
+
+    pub fn account(&self, bank: &Bank, account: &Account) -> AccountState {
+        self.accounts
+            .get(&Self::key(bank, account))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn account_mut(&mut self, bank: &Bank, account: &Account) -> &mut AccountState {
+        self.accounts.entry(Self::key(bank, account)).or_default()
+    }
+
+    /// Forgets an account's last sync and backfill progress.
+    pub fn clear_account(&mut self, bank: &Bank, account: &Account) {
+        self.accounts.remove(&Self::key(bank, account));
+    }
+
+    /// Whether the user agreed to write to the asset. An account's earlier
+    /// syncs don't count, since its `lunch_money_asset_id` may have been
+    /// changed since.
+    pub fn knows_asset(&self, asset_id: u64) -> bool {
+        self.confirmed_assets.contains(&asset_id)
+    }
+
+    fn key(bank: &Bank, account: &Account) -> String {
+        format!("{}/{}", bank.name, account.name)
+    }
+}
@@ -0,0 +1,82 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::types::fintoc::Movement;
+use crate::types::lunchmoney::Transaction;
+
+/// Number of payees listed in `MovementStats::top_payees`.
+const TOP_PAYEES: usize = 5;
+
+/// Movements and total amount for a single payee.
+#[derive(Debug)]
+pub struct PayeeStats {
+    pub payee: String,
+    pub count: usize,
+    pub total: f64,
+}
+
+/// Figures about an account's movements over a window.
+#[derive(Debug, Default)]
+pub struct MovementStats {
+    pub count: usize,
+    /// Sum of positive amounts, in major units
+    pub inflow: f64,
+    /// Sum of negative amounts, in major units
+    pub outflow: f64,
+    /// Movement count per movement type
+    pub by_type: BTreeMap<String, usize>,
+    /// Payees with the most movements, then the largest absolute total
+    pub top_payees: Vec<PayeeStats>,
+    /// Lowercase ISO code of the movements' currency
+    pub currency: Option<String>,
+}
+
+impl MovementStats {
+    /// Aggregates movements along with the transactions they convert to.
+    pub fn new(movements: &[(&Movement, Transaction)]) -> Self {
+        let mut stats = MovementStats::default();
+        let mut payees: HashMap<String, PayeeStats> = HashMap::new();
+
+        for (movement, transaction) in movements {
+            let amount = transaction.amount.0;
+            stats.count += 1;
+            if amount >= 0.0 {
+                stats.inflow += amount;
+            } else {
+                stats.outflow += amount;
+            }
+
+            *stats
+                .by_type
+                .entry(movement.movement_type.to_string())
+                .or_default() += 1;
+
+            let payee = transaction
+                .payee
+                .clone()
+                .unwrap_or_else(|| "Unknown".to_string());
+            let entry = payees.entry(payee.clone()).or_insert(PayeeStats {
+                payee,
+                count: 0,
+                total: 0.0,
+            });
+            entry.count += 1;
+            entry.total += amount;
+
+            if stats.currency.is_none() {
+                stats.currency = transaction.currency.clone();
+            }
+        }
+
+        let mut top_payees = payees.into_values().collect::<Vec<_>>();
+        top_payees.sort_by(|a, b| {
+            b.count
+                .cmp(&a.count)
+                .then(b.total.abs().total_cmp(&a.total.abs()))
+                .then(a.payee.cmp(&b.payee))
+        });
+        top_payees.truncate(TOP_PAYEES);
+        stats.top_payees = top_payees;
+
+        stats
+    }
+}
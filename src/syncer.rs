@@ -0,0 +1,2067 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, NaiveDate, Utc};
+use colored::*;
+use futures::stream::{self, StreamExt};
+use indicatif::{ProgressBar, ProgressStyle};
+use itertools::Itertools;
+
+use crate::audit::{AssetAudit, AuditStatus};
+use crate::config::{Account, AppConfig, Bank, ShortFetchHandling};
+use crate::deadletter;
+use crate::debug::debug_log;
+use crate::dedup::ExistingTransactions;
+use crate::dump::MovementDump;
+use crate::fintoc::{
+    fetch_fintoc_balance, fetch_fintoc_movements, fetch_link_accounts, FetchedMovements,
+    FintocBalance, ShortFetch,
+};
+use crate::history::SyncRecord;
+use crate::http::{build_client, ApiClient};
+use crate::lunchmoney::{
+    get_all_assets, get_transactions, insert_transactions, update_asset_balance,
+    update_transaction, InsertOptions, InsertOutcome, LunchMoneyCache,
+};
+use crate::metrics;
+use crate::notify::{send_webhook, SyncNotification};
+use crate::output;
+use crate::plan::{BalanceChange, SyncPlan};
+use crate::report::{self, AccountReport, SyncReport};
+use crate::stats::MovementStats;
+use crate::types::fintoc::Movement;
+use crate::types::lunchmoney::{Amount, Asset, FetchedTransaction, Transaction, TransactionUpdate};
+use crate::types::HttpsClient;
+use crate::verify::{self, Reconciliation};
+use crate::written::{self, WrittenFields, WrittenLedger};
+
+/// Transactions sent to Lunch Money per insert request.
+const INSERT_CHUNK_SIZE: usize = 50;
+
+/// An account's movements in a window, converted to Lunch Money transactions.
+struct ListedMovements {
+    transactions: Vec<Transaction>,
+    short_fetch: Option<ShortFetch>,
+    /// Movements dropped because of their currency, by currency code
+    unsupported: BTreeMap<String, usize>,
+    /// Movements dropped because they have no date
+    undated: usize,
+}
+
+/// Syncs the accounts of an `AppConfig` from Fintoc into Lunch Money.
+///
+/// Lunch Money data that only needs to be fetched once (like categories) is
+/// cached for the lifetime of the `Syncer`, per Lunch Money token.
+pub struct Syncer {
+    client: ApiClient,
+    config: AppConfig,
+    caches: HashMap<String, LunchMoneyCache>,
+    /// Movements file read instead of calling Fintoc, set by `sync --from-file`
+    movements_file: Option<PathBuf>,
+    /// Skip movements for every account, set by `sync --only-balances`
+    only_balances: bool,
+    /// Only warn about ids shared by several accounts, set by
+    /// `--allow-duplicates`
+    allow_duplicates: bool,
+    /// Update balances outside an account's bounds, set by `sync --force`
+    force_balance: bool,
+    /// Balances fetched ahead by `prefetch_balances`, by bank and account name
+    prefetched_balances: Mutex<HashMap<(String, String), Result<FintocBalance>>>,
+}
+
+impl Syncer {
+    /// Creates a syncer with an HTTP client built from the config's `http`
+    /// settings.
+    pub fn new(config: AppConfig) -> Result<Self> {
+        let client = build_client(&config.http)?;
+        Self::with_client(client, config)
+    }
+
+    /// Creates a syncer sending its requests through `client`, e.g. one shared
+    /// with other code or set up for a test server. The config's retry and
+    /// throttle settings apply to this syncer's requests only.
+    pub fn with_client(client: HttpsClient, config: AppConfig) -> Result<Self> {
+        let client = ApiClient::new(client, &config.http)?;
+
+        let caches = config
+            .lunch_money_tokens()
+            .into_iter()
+            .map(|token| (token.to_string(), LunchMoneyCache::default()))
+            .collect();
+
+        Ok(Self {
+            client,
+            config,
+            caches,
+            movements_file: None,
+            only_balances: false,
+            allow_duplicates: false,
+            force_balance: false,
+            prefetched_balances: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Reads movements from a file written by `export` instead of fetching
+    /// them from Fintoc. Balances still come from Fintoc.
+    pub fn read_movements_from(&mut self, path: PathBuf) {
+        self.movements_file = Some(path);
+    }
+
+    /// Only updates balances when syncing, as if every account had
+    /// `skip_movements` set.
+    pub fn only_balances(&mut self) {
+        self.only_balances = true;
+    }
+
+    /// Warns about accounts sharing a Fintoc account or Lunch Money asset
+    /// instead of failing validation.
+    pub fn allow_duplicates(&mut self) {
+        self.allow_duplicates = true;
+    }
+
+    /// Updates balances even when they're outside the account's
+    /// `min_balance`/`max_balance`, only warning about them.
+    pub fn force_balance(&mut self) {
+        self.force_balance = true;
+    }
+
+    fn skips_movements(&self, account: &Account) -> bool {
+        self.only_balances || account.skip_movements
+    }
+
+    /// Whether syncing `account` fetches its movements for the window from
+    /// Fintoc and inserts them, so the window can count as synced. Not the
+    /// case with `skip_movements`, `--only-balances` or `--from-file`.
+    pub fn fetches_movements(&self, account: &Account) -> bool {
+        !self.skips_movements(account) && self.movements_file.is_none()
+    }
+
+    pub fn config(&self) -> &AppConfig {
+        &self.config
+    }
+
+    pub fn client(&self) -> &ApiClient {
+        &self.client
+    }
+
+    fn cache(&self, api_token: &str) -> &LunchMoneyCache {
+        self.caches
+            .get(api_token)
+            .expect("every Lunch Money token in the config has a cache")
+    }
+
+    /// With `match_asset_by_name`, sets the asset id of accounts that have
+    /// none to the Lunch Money asset whose name or display name is the
+    /// account's name. Fails when no asset, or more than one, matches.
+    pub async fn resolve_asset_ids(&mut self) -> Result<()> {
+        if !self.config.sync_settings.match_asset_by_name {
+            return Ok(());
+        }
+
+        let mut resolved = Vec::new();
+        for (bank_index, bank) in self.config.banks.iter().enumerate() {
+            for (account_index, account) in bank.accounts.iter().enumerate() {
+                if !account.lunch_money_asset_id.is_empty() {
+                    continue;
+                }
+
+                let api_token = self.config.lunch_money_token(bank, account);
+                let assets = self
+                    .cache(api_token)
+                    .assets(&self.client, api_token)
+                    .await?;
+                let name = account.name.trim();
+                let matches: Vec<u64> = assets
+                    .iter()
+                    .filter(|asset| {
+                        [&asset.name, &asset.display_name]
+                            .into_iter()
+                            .any(|asset_name| {
+                                asset_name.as_deref().is_some_and(|asset_name| {
+                                    asset_name.trim().eq_ignore_ascii_case(name)
+                                })
+                            })
+                    })
+                    .filter_map(|asset| asset.id)
+                    .collect();
+
+                let asset_id = match matches.as_slice() {
+                    [asset_id] => *asset_id,
+                    [] => bail!(
+                        "No Lunch Money asset is named {:?}, for account {} - {}. Set its lunch_money_asset_id.",
+                        account.name,
+                        bank.name,
+                        account.name
+                    ),
+                    _ => bail!(
+                        "Several Lunch Money assets are named {:?} (ids {}), for account {} - {}. Set its lunch_money_asset_id.",
+                        account.name,
+                        matches
+                            .iter()
+                            .map(u64::to_string)
+                            .collect::<Vec<_>>()
+                            .join(", "),
+                        bank.name,
+                        account.name
+                    ),
+                };
+                debug_log!(
+                    "Matched {} - {} to Lunch Money asset {} by name",
+                    bank.name,
+                    account.name,
+                    asset_id
+                );
+                resolved.push((bank_index, account_index, asset_id));
+            }
+        }
+
+        for (bank_index, account_index, asset_id) in resolved {
+            self.config.banks[bank_index].accounts[account_index].lunch_money_asset_id =
+                asset_id.to_string();
+        }
+        Ok(())
+    }
+
+    /// Names of the categories assigned to `transactions`, by id, for
+    /// previews. Lunch Money is only asked when some transaction has one.
+    pub async fn category_names(
+        &self,
+        bank: &Bank,
+        account: &Account,
+        transactions: &[Transaction],
+    ) -> Result<HashMap<u64, String>> {
+        if transactions
+            .iter()
+            .all(|transaction| transaction.category_id.is_none())
+        {
+            return Ok(HashMap::new());
+        }
+
+        let api_token = self.config.lunch_money_token(bank, account);
+        let categories = self
+            .cache(api_token)
+            .categories(&self.client, api_token)
+            .await?;
+        Ok(categories
+            .iter()
+            .map(|category| (category.id, category.name.clone()))
+            .collect())
+    }
+
+    /// Checks the configuration against the user's Lunch Money data, rejecting
+    /// category rules and account default categories that reference categories
+    /// which don't exist, and asset ids that don't point at manual assets.
+    pub async fn validate(&self) -> Result<()> {
+        self.validate_unique_ids()?;
+        self.validate_category_rules().await?;
+        self.validate_assets().await
+    }
+
+    /// Copy-pasted account blocks can sync the same account twice, doubling
+    /// its movements and balance.
+    fn validate_unique_ids(&self) -> Result<()> {
+        let duplicates = self.config.duplicate_ids();
+        if duplicates.is_empty() {
+            return Ok(());
+        }
+
+        if !self.allow_duplicates {
+            bail!(
+                "Duplicate account ids (pass --allow-duplicates to sync anyway):\n{}",
+                duplicates.join("\n")
+            );
+        }
+        for duplicate in duplicates {
+            output::status(format!("Duplicate account id: {}", duplicate).yellow());
+        }
+        Ok(())
+    }
+
+    async fn validate_category_rules(&self) -> Result<()> {
+        let settings = &self.config.sync_settings;
+        let accounts = self.config.select_accounts("", "");
+        if settings.category_rules.is_empty()
+            && accounts
+                .iter()
+                .all(|(_, account)| account.default_category_id.is_none())
+        {
+            return Ok(());
+        }
+
+        // Category ids are per budget, check them in each one in use
+        let mut api_tokens = Vec::new();
+        for (bank, account) in &accounts {
+            let api_token = self.config.lunch_money_token(bank, account);
+            if !api_tokens.contains(&api_token) {
+                api_tokens.push(api_token);
+            }
+        }
+
+        for api_token in api_tokens {
+            let categories = self
+                .cache(api_token)
+                .categories(&self.client, api_token)
+                .await?;
+            let exists = |category_id: u64| categories.iter().any(|c| c.id == category_id);
+
+            let unknown = settings
+                .category_rules
+                .iter()
+                .filter(|rule| !exists(rule.category_id))
+                .map(|rule| format!("{} (pattern {:?})", rule.category_id, rule.pattern))
+                .collect::<Vec<_>>();
+
+            if !unknown.is_empty() {
+                bail!(
+                    "Category rules reference unknown Lunch Money categories: {}",
+                    unknown.join(", ")
+                );
+            }
+
+            let unknown = accounts
+                .iter()
+                .filter(|(bank, account)| self.config.lunch_money_token(bank, account) == api_token)
+                .filter_map(|(bank, account)| {
+                    account
+                        .default_category_id
+                        .filter(|category_id| !exists(*category_id))
+                        .map(|category_id| {
+                            format!("{} ({} - {})", category_id, bank.name, account.name)
+                        })
+                })
+                .collect::<Vec<_>>();
+
+            if !unknown.is_empty() {
+                bail!(
+                    "Account default_category_id references unknown Lunch Money categories: {}",
+                    unknown.join(", ")
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Lunch Money only accepts balance updates on manually-managed assets, so
+    /// every configured asset id must be one of them. Ids of Plaid-linked
+    /// accounts are a common mistake and get a dedicated message.
+    async fn validate_assets(&self) -> Result<()> {
+        let mut problems = Vec::new();
+
+        for (bank, account) in self.config.select_accounts("", "") {
+            let api_token = self.config.lunch_money_token(bank, account);
+            let cache = self.cache(api_token);
+            let assets = cache.assets(&self.client, api_token).await?;
+
+            let mut asset_ids = vec![account.asset_id()?];
+            for currency in account.currency_assets.keys() {
+                asset_ids.extend(account.currency_asset_id(currency)?);
+            }
+
+            for asset_id in asset_ids {
+                if assets.iter().any(|asset| asset.id == Some(asset_id)) {
+                    continue;
+                }
+
+                let plaid_accounts = cache.plaid_accounts(&self.client, api_token).await?;
+                let problem = match plaid_accounts.iter().find(|p| p.id == asset_id) {
+                    Some(plaid_account) => format!(
+                        "{} - {}: {} is the Plaid-linked account {:?}, whose balance Lunch Money manages itself. Point it at a manual asset instead (see `assets`).",
+                        bank.name, account.name, asset_id, plaid_account.name
+                    ),
+                    None => format!(
+                        "{} - {}: {} is not a manual Lunch Money asset (see `assets`).",
+                        bank.name, account.name, asset_id
+                    ),
+                };
+                problems.push(problem);
+            }
+        }
+
+        if !problems.is_empty() {
+            bail!("Invalid Lunch Money asset ids:\n{}", problems.join("\n"));
+        }
+
+        Ok(())
+    }
+
+    /// Checks every selected account's assets: that the Fintoc account is still
+    /// on its bank's link, that the Lunch Money assets exist, and that their
+    /// currencies match. Problems are reported in the result, not as errors.
+    pub async fn audit(&self, bank_name: &str, account_name: &str) -> Result<Vec<AssetAudit>> {
+        let mut audits = Vec::new();
+        // Fetched once per link, errors kept as (redacted) text for the report
+        let mut links = HashMap::new();
+
+        for (bank, account) in self.config.select_accounts(bank_name, account_name) {
+            if !links.contains_key(&bank.link_token) {
+                let link_accounts = fetch_link_accounts(
+                    &self.client,
+                    &self.config.tokens.fintoc_secret_token,
+                    &bank.link_token,
+                )
+                .await
+                .map_err(|err| self.config.redact_secrets(&format!("{:#}", err)));
+                links.insert(bank.link_token.clone(), link_accounts);
+            }
+            let link_accounts = &links[&bank.link_token];
+
+            let api_token = self.config.lunch_money_token(bank, account);
+            let assets = self
+                .cache(api_token)
+                .assets(&self.client, api_token)
+                .await?;
+
+            let (fintoc_currency, link_status) = match link_accounts {
+                Ok(link_accounts) => match link_accounts.iter().find(|link_account| {
+                    link_account.get("id").and_then(|id| id.as_str())
+                        == Some(account.fintoc_account_id.as_str())
+                }) {
+                    Some(link_account) => (
+                        link_account
+                            .get("currency")
+                            .and_then(|currency| currency.as_str())
+                            .map(str::to_uppercase),
+                        AuditStatus::Ok,
+                    ),
+                    None => (None, AuditStatus::AccountNotOnLink),
+                },
+                Err(error) => (None, AuditStatus::LinkError(error.clone())),
+            };
+
+            let mut expected_assets = vec![(
+                fintoc_currency.clone(),
+                account.lunch_money_asset_id.clone(),
+            )];
+            for (currency, asset_id) in &account.currency_assets {
+                expected_assets.push((Some(currency.to_uppercase()), asset_id.clone()));
+            }
+
+            for (expected_currency, asset_id) in expected_assets {
+                let asset_currency = assets
+                    .iter()
+                    .find(|asset| asset.id.map(|id| id.to_string()) == Some(asset_id.clone()))
+                    .map(|asset| asset.currency.to_uppercase());
+
+                let status = if !link_status.is_ok() {
+                    link_status.clone()
+                } else if asset_currency.is_none() {
+                    AuditStatus::AssetMissing
+                } else if expected_currency.is_some() && asset_currency != expected_currency {
+                    AuditStatus::CurrencyMismatch
+                } else {
+                    AuditStatus::Ok
+                };
+
+                audits.push(AssetAudit {
+                    bank: bank.name.clone(),
+                    account: account.name.clone(),
+                    fintoc_currency: fintoc_currency.clone(),
+                    expected_currency,
+                    asset_id,
+                    asset_currency,
+                    status,
+                });
+            }
+        }
+
+        Ok(audits)
+    }
+
+    /// Posts the outcome of a sync to the configured webhook, if any. This is
+    /// best-effort: failures are reported but don't fail the sync.
+    pub async fn notify(&self, report: &SyncReport, error: Option<&anyhow::Error>) {
+        let settings = match &self.config.notify {
+            Some(settings) => settings,
+            None => return,
+        };
+
+        let error = error.map(|err| self.config.redact_secrets(&format!("{:#}", err)));
+        let notification = SyncNotification::new(report, error);
+
+        if let Err(err) = send_webhook(&self.client, &settings.webhook_url, &notification).await {
+            output::status(
+                format!(
+                    "Failed to send sync notification: {}",
+                    self.config.redact_secrets(&format!("{:#}", err))
+                )
+                .yellow(),
+            );
+        }
+    }
+
+    /// Records a sync run in `history_dir`, if configured. Like `notify`,
+    /// failures are reported but don't fail the sync.
+    pub fn write_history(
+        &self,
+        report: &SyncReport,
+        error: Option<&anyhow::Error>,
+        started_at: DateTime<Utc>,
+    ) {
+        let dir = match &self.config.sync_settings.history_dir {
+            Some(dir) => Path::new(dir),
+            None => return,
+        };
+
+        let record = SyncRecord {
+            started_at,
+            finished_at: Utc::now(),
+            command: std::env::args()
+                .map(|arg| self.config.redact_secrets(&arg))
+                .collect(),
+            status: report.status(error.is_some()),
+            window_start: report.window_start,
+            window_end: report.window_end,
+            report,
+            error: error.map(|err| self.config.redact_secrets(&format!("{:#}", err))),
+        };
+
+        match record.write(dir) {
+            Ok(path) => debug_log!("Wrote sync record {}", path.display()),
+            Err(err) => output::status(format!("Failed to write sync record: {:#}", err).yellow()),
+        }
+    }
+
+    /// Writes the metrics of a sync to `metrics_file`, if configured. Like
+    /// `notify`, failures are reported but don't fail the sync.
+    pub fn write_metrics(&self, report: &SyncReport, succeeded: bool) {
+        let path = match &self.config.sync_settings.metrics_file {
+            Some(path) => Path::new(path),
+            None => return,
+        };
+
+        let contents = metrics::render(report, succeeded, Utc::now());
+        if let Err(err) = metrics::write(path, &contents) {
+            output::status(format!("Failed to write metrics: {:#}", err).yellow());
+        }
+    }
+
+    /// Assets of every Lunch Money budget in the config, the global token's
+    /// first. An asset reachable through several tokens is listed once.
+    pub async fn list_assets(&self) -> Result<Vec<Asset>> {
+        let mut assets: Vec<Asset> = Vec::new();
+        for api_token in self.config.lunch_money_tokens() {
+            for asset in get_all_assets(&self.client, api_token).await? {
+                if asset.id.is_none() || assets.iter().all(|listed| listed.id != asset.id) {
+                    assets.push(asset);
+                }
+            }
+        }
+        Ok(assets)
+    }
+
+    /// Fetches the Lunch Money transactions of an asset between two days
+    /// (inclusive).
+    pub async fn list_transactions(
+        &self,
+        asset_id: u64,
+        start_date: NaiveDate,
+        end_date: NaiveDate,
+    ) -> Result<Vec<FetchedTransaction>> {
+        get_transactions(
+            &self.client,
+            self.config.lunch_money_token_for_asset(asset_id),
+            asset_id,
+            start_date,
+            end_date,
+        )
+        .await
+    }
+
+    /// Changes fields of a transaction of the given asset.
+    pub async fn update_transaction(
+        &self,
+        asset_id: u64,
+        transaction_id: u64,
+        update: &TransactionUpdate,
+    ) -> Result<()> {
+        update_transaction(
+            &self.client,
+            self.config.lunch_money_token_for_asset(asset_id),
+            transaction_id,
+            update,
+        )
+        .await
+    }
+
+    /// Spinner line for an account, on the shared progress display.
+    fn account_progress(&self, bank: &Bank, account: &Account) -> ProgressBar {
+        let progress = output::progress_bar(ProgressBar::new_spinner());
+        progress.set_style(
+            ProgressStyle::with_template("{spinner} {prefix:.bold} {msg}")
+                .expect("valid progress template"),
+        );
+        progress.set_prefix(format!("{} - {}", bank.name, account.name));
+        progress.enable_steady_tick(Duration::from_millis(100));
+        progress
+    }
+
+    /// Fetches an account's raw Fintoc movements in the window, showing a
+    /// spinner while paging through them. With a movements file, they're
+    /// read from it as-is instead, whatever the window.
+    pub async fn fetch_movements(
+        &self,
+        bank: &Bank,
+        account: &Account,
+        start_date: DateTime<Utc>,
+        end_date: DateTime<Utc>,
+    ) -> Result<Vec<Movement>> {
+        let progress = self.account_progress(bank, account);
+        let fetched = self
+            .fetch_movements_with(bank, account, start_date, end_date, &progress)
+            .await;
+        progress.finish_and_clear();
+        Ok(fetched?.movements)
+    }
+
+    /// Fetches the movements and checks whether Fintoc may have stopped early,
+    /// as `short_fetch` says.
+    async fn fetch_movements_with(
+        &self,
+        bank: &Bank,
+        account: &Account,
+        start_date: DateTime<Utc>,
+        end_date: DateTime<Utc>,
+        progress: &ProgressBar,
+    ) -> Result<FetchedMovements> {
+        if let Some(path) = &self.movements_file {
+            let movements = MovementDump::load(path)?
+                .take(bank, account)
+                .ok_or_else(|| {
+                    anyhow!(
+                        "No movements for {} - {} in {}",
+                        bank.name,
+                        account.name,
+                        path.display()
+                    )
+                })?;
+            return Ok(FetchedMovements {
+                movements,
+                ended_on_empty_page: false,
+            });
+        }
+
+        let credentials = self.config.credentials(bank, account);
+        let options = self.config.sync_settings.fetch_options();
+        let fetch = || {
+            fetch_fintoc_movements(
+                &self.client,
+                &credentials,
+                start_date,
+                end_date,
+                &options,
+                Some(progress),
+            )
+        };
+
+        progress.set_message("Fetching movements...");
+        let mut fetched = fetch().await?;
+
+        let handling = self.config.sync_settings.short_fetch;
+        if handling == ShortFetchHandling::Ignore {
+            fetched.ended_on_empty_page = false;
+            return Ok(fetched);
+        }
+        if let Some(short) = fetched.short_fetch() {
+            if handling == ShortFetchHandling::Retry {
+                debug_log!(
+                    "Fetching movements for {} - {} ended on an empty page after {} movements, fetching again",
+                    bank.name,
+                    account.name,
+                    short.fetched
+                );
+                progress.set_message("Fetching movements again...");
+                fetched = fetch().await?;
+            }
+        }
+        if let Some(short) = fetched.short_fetch() {
+            output::status(
+                format!(
+                    "Fintoc returned an empty page after {} movements for {} - {}, so it may have stopped early and some may be missing.",
+                    short.fetched, bank.name, account.name
+                )
+                .yellow(),
+            );
+        }
+
+        Ok(fetched)
+    }
+
+    /// Fetches an account's Fintoc movements in the window, converted to Lunch
+    /// Money transactions. Movements that can't be converted are dropped, and
+    /// so are pending ones unless `include_pending` is set.
+    pub async fn list_movements(
+        &self,
+        bank: &Bank,
+        account: &Account,
+        start_date: DateTime<Utc>,
+        end_date: DateTime<Utc>,
+    ) -> Result<Vec<Transaction>> {
+        let progress = self.account_progress(bank, account);
+        let transactions = self
+            .list_movements_with(bank, account, start_date, end_date, &progress)
+            .await;
+        progress.finish_and_clear();
+        Ok(transactions?.transactions)
+    }
+
+    /// Like `list_movements`, also returning how short the fetch was and the
+    /// movements that couldn't be converted.
+    async fn list_movements_with(
+        &self,
+        bank: &Bank,
+        account: &Account,
+        start_date: DateTime<Utc>,
+        end_date: DateTime<Utc>,
+        progress: &ProgressBar,
+    ) -> Result<ListedMovements> {
+        let asset_id = account.asset_id()?;
+        let options = self.config.sync_settings.conversion_options(account)?;
+
+        let fetched = self
+            .fetch_movements_with(bank, account, start_date, end_date, progress)
+            .await?;
+        let short_fetch = fetched.short_fetch();
+
+        let include_pending = self.config.sync_settings.include_pending;
+        let mut transactions = Vec::new();
+        let mut unsupported = BTreeMap::new();
+        let mut undated = Vec::new();
+        for movement in fetched
+            .movements
+            .into_iter()
+            .filter(|movement| include_pending || !movement.pending)
+        {
+            // Conversion only fails for movements without a date (unless
+            // `missing_date` dates them) and currencies without a known
+            // exponent
+            match movement.to_lunchmoney_transaction(asset_id, &options) {
+                Ok(transaction) => transactions.push(transaction),
+                Err(_) if movement.date(options.date_field).is_none() => undated.push(movement.id),
+                Err(_) => {
+                    *unsupported
+                        .entry(movement.currency.to_uppercase())
+                        .or_default() += 1
+                }
+            }
+        }
+
+        if !undated.is_empty() {
+            output::status(
+                format!(
+                    "Skipping {} movements of {} - {} without a date: {}. Set missing_date = \"fetch-time\" to sync them.",
+                    undated.len(),
+                    bank.name,
+                    account.name,
+                    undated.join(", ")
+                )
+                .yellow(),
+            );
+        }
+
+        if !unsupported.is_empty() {
+            output::status(
+                format!(
+                    "Dropping movements of {} - {} in unsupported currencies: {}. Set amount_scale on the account to sync them.",
+                    bank.name,
+                    account.name,
+                    report::currency_counts(&unsupported)
+                )
+                .yellow(),
+            );
+        }
+
+        Ok(ListedMovements {
+            transactions,
+            short_fetch,
+            unsupported,
+            undated: undated.len(),
+        })
+    }
+
+    /// Aggregates an account's Fintoc movements in the window, without
+    /// writing anything.
+    pub async fn movement_stats(
+        &self,
+        bank: &Bank,
+        account: &Account,
+        start_date: DateTime<Utc>,
+        end_date: DateTime<Utc>,
+    ) -> Result<MovementStats> {
+        let asset_id = account.asset_id()?;
+        let options = self.config.sync_settings.conversion_options(account)?;
+
+        let movements = self
+            .fetch_movements(bank, account, start_date, end_date)
+            .await?;
+
+        let converted = movements
+            .iter()
+            .filter_map(|movement| {
+                movement
+                    .to_lunchmoney_transaction(asset_id, &options)
+                    .ok()
+                    .map(|transaction| (movement, transaction))
+            })
+            .collect::<Vec<_>>();
+
+        Ok(MovementStats::new(&converted))
+    }
+
+    /// Fetches the Fintoc balances of `accounts` up to `balance_concurrency`
+    /// at a time, for `sync_account` to use instead of fetching each one when
+    /// its turn comes. Errors are kept, and reported by that account's sync.
+    /// Does nothing without concurrency, where there's nothing to gain.
+    pub async fn prefetch_balances(&self, accounts: &[(&Bank, &Account)]) {
+        let concurrency = self.config.sync_settings.concurrency().balance_requests;
+        if concurrency <= 1 || accounts.len() <= 1 {
+            return;
+        }
+
+        output::status(
+            format!(
+                "Fetching the balances of {} accounts, {} at a time.",
+                accounts.len(),
+                concurrency
+            )
+            .blue(),
+        );
+        let started = Instant::now();
+        let balances = stream::iter(accounts)
+            .map(|(bank, account)| async move {
+                let key = (bank.name.clone(), account.name.clone());
+                (key, self.fetch_balance(bank, account).await)
+            })
+            .buffer_unordered(concurrency)
+            .collect::<Vec<_>>()
+            .await;
+        debug_log!(
+            "Fetched {} balances in {} ms",
+            balances.len(),
+            started.elapsed().as_millis()
+        );
+
+        self.prefetched_balances.lock().unwrap().extend(balances);
+    }
+
+    /// The account's balance, prefetched if it was, or fetched now.
+    async fn take_balance(&self, bank: &Bank, account: &Account) -> Result<FintocBalance> {
+        let prefetched = self
+            .prefetched_balances
+            .lock()
+            .unwrap()
+            .remove(&(bank.name.clone(), account.name.clone()));
+        match prefetched {
+            Some(balance) => balance,
+            None => self.fetch_balance(bank, account).await,
+        }
+    }
+
+    /// Fetches an account's current balance from Fintoc.
+    pub async fn fetch_balance(&self, bank: &Bank, account: &Account) -> Result<FintocBalance> {
+        fetch_fintoc_balance(
+            &self.client,
+            &self.config.credentials(bank, account),
+            account.account_type,
+            account.balance_source,
+            account.amount_scale,
+            self.config.sync_settings.strict_currency,
+        )
+        .await
+    }
+
+    /// Sets the balance of the account's Lunch Money asset. With
+    /// `balance_as_of_refreshed_at`, it's dated when Fintoc last refreshed the
+    /// account rather than now.
+    ///
+    /// With `skip_unchanged_balance`, an asset that already has this balance
+    /// and currency is left alone. Returns whether it was updated.
+    pub async fn update_balance(
+        &self,
+        bank: &Bank,
+        account: &Account,
+        balance: Amount,
+        currency: String,
+        refreshed_at: Option<DateTime<Utc>>,
+    ) -> Result<bool> {
+        let api_token = self.config.lunch_money_token(bank, account);
+        let asset_id = account.asset_id()?;
+        let balance_as_of =
+            refreshed_at.filter(|_| self.config.sync_settings.balance_as_of_refreshed_at);
+
+        let currency = if account.preserve_asset_currency {
+            self.preserved_currency(api_token, asset_id, balance, currency)
+                .await?
+        } else {
+            currency
+        };
+
+        if self.config.sync_settings.skip_unchanged_balance {
+            let assets = self
+                .cache(api_token)
+                .assets(&self.client, api_token)
+                .await?;
+            // Lunch Money keeps balances to 4 decimal places
+            let unchanged = assets.iter().any(|asset| {
+                asset.id == Some(asset_id)
+                    && (asset.balance.0 - balance.0).abs() < 0.00005
+                    && asset.currency.eq_ignore_ascii_case(&currency)
+            });
+            if unchanged {
+                return Ok(false);
+            }
+        }
+
+        update_asset_balance(
+            &self.client,
+            api_token,
+            asset_id,
+            balance,
+            &currency,
+            balance_as_of,
+            self.config.sync_settings.verify_balance_update,
+        )
+        .await?;
+        Ok(true)
+    }
+
+    /// Whether the asset is one of the manually-managed ones, whose balance can
+    /// be updated.
+    async fn is_manual_asset(&self, api_token: &str, asset_id: u64) -> Result<bool> {
+        let assets = self
+            .cache(api_token)
+            .assets(&self.client, api_token)
+            .await?;
+        Ok(assets.iter().any(|asset| asset.id == Some(asset_id)))
+    }
+
+    /// The asset's own currency, for accounts with `preserve_asset_currency`.
+    /// When it differs from the Fintoc one, the balance is sent unconverted, so
+    /// a new balance orders of magnitude away from the current one (e.g. CLP
+    /// into a USD asset) is refused rather than written.
+    async fn preserved_currency(
+        &self,
+        api_token: &str,
+        asset_id: u64,
+        balance: Amount,
+        fintoc_currency: String,
+    ) -> Result<String> {
+        const MAX_MAGNITUDE_CHANGE: f64 = 100.0;
+
+        let assets = self
+            .cache(api_token)
+            .assets(&self.client, api_token)
+            .await?;
+        let Some(asset) = assets.iter().find(|asset| asset.id == Some(asset_id)) else {
+            bail!("Lunch Money asset {} not found", asset_id);
+        };
+        let currency = match rusty_money::iso::find(&asset.currency) {
+            Some(currency) => currency.iso_alpha_code.to_string(),
+            None if self.config.sync_settings.strict_currency => bail!(
+                "Lunch Money asset {} has an unknown currency {}",
+                asset_id,
+                asset.currency
+            ),
+            None => asset.currency.to_uppercase(),
+        };
+
+        if currency != fintoc_currency {
+            let (current, new) = (asset.balance.0.abs(), balance.0.abs());
+            if current > 0.0
+                && new > 0.0
+                && (new / current > MAX_MAGNITUDE_CHANGE || current / new > MAX_MAGNITUDE_CHANGE)
+            {
+                bail!(
+                    "Not updating Lunch Money asset {}: the {} balance {} is too far from its current {} {}, check preserve_asset_currency",
+                    asset_id,
+                    fintoc_currency,
+                    balance,
+                    asset.balance,
+                    currency
+                );
+            }
+        }
+
+        Ok(currency)
+    }
+
+    /// Inserts transactions with the given Lunch Money token, re-attempting the
+    /// ones that fail up to `insert_retries` times and saving those that still
+    /// fail to the dead-letter file.
+    pub async fn insert(
+        &self,
+        api_token: &str,
+        transactions: Vec<Transaction>,
+        options: &InsertOptions,
+    ) -> Result<InsertOutcome> {
+        let mut outcome =
+            insert_transactions(&self.client, api_token, transactions, options).await?;
+
+        for attempt in 1..=self.config.sync_settings.insert_retries {
+            if outcome.failed.is_empty() {
+                break;
+            }
+
+            output::status(
+                format!(
+                    "Retrying {} failed transactions (attempt {})...",
+                    outcome.failed.len(),
+                    attempt
+                )
+                .yellow(),
+            );
+            tokio::time::sleep(Duration::from_secs(attempt as u64)).await;
+
+            let retried = insert_transactions(
+                &self.client,
+                api_token,
+                std::mem::take(&mut outcome.failed),
+                options,
+            )
+            .await?;
+
+            outcome.extend(retried);
+        }
+
+        deadletter::append(
+            Path::new(&self.config.sync_settings.dead_letter_file),
+            &outcome.failed,
+        )?;
+
+        Ok(outcome)
+    }
+
+    /// Re-attempts inserting the transactions in the dead-letter file, keeping
+    /// only those that still fail. Insert options come from `sync_settings`,
+    /// per-account overrides don't apply. Each transaction is inserted with the
+    /// Lunch Money token of the account owning its asset.
+    pub async fn retry_failed(&self) -> Result<InsertOutcome> {
+        let path = Path::new(&self.config.sync_settings.dead_letter_file);
+        let transactions = deadletter::read(path)?;
+
+        if transactions.is_empty() {
+            return Ok(InsertOutcome::default());
+        }
+
+        let by_token = transactions
+            .into_iter()
+            .into_group_map_by(|transaction| match transaction.asset_id {
+                Some(asset_id) => self.config.lunch_money_token_for_asset(asset_id),
+                None => &self.config.tokens.lunch_money_api_token,
+            });
+
+        let mut outcome = InsertOutcome::default();
+        for (api_token, transactions) in by_token {
+            let retried = insert_transactions(
+                &self.client,
+                api_token,
+                transactions,
+                &self.config.sync_settings.insert_options(None),
+            )
+            .await?;
+
+            outcome.extend(retried);
+        }
+
+        deadletter::replace(path, &outcome.failed)?;
+
+        Ok(outcome)
+    }
+
+    /// Compares an account's Fintoc movements with the transactions of its
+    /// Lunch Money assets over the same window. Only the transactions a sync
+    /// would write are expected (see `expected_transactions`), and with
+    /// `composite_dedup` those found by composite key count as present.
+    pub async fn verify_account(
+        &self,
+        bank: &Bank,
+        account: &Account,
+        start_date: DateTime<Utc>,
+        end_date: DateTime<Utc>,
+    ) -> Result<Reconciliation> {
+        let balance = self.fetch_balance(bank, account).await?;
+        let transactions = self
+            .list_movements(bank, account, start_date, end_date)
+            .await?;
+        let (expected, asset_ids) =
+            self.expected_transactions(account, transactions, &balance.currency)?;
+
+        let existing = self
+            .fetch_existing(
+                self.config.lunch_money_token(bank, account),
+                asset_ids,
+                &expected,
+                start_date,
+                end_date,
+            )
+            .await?;
+
+        Ok(verify::reconcile(
+            expected,
+            existing,
+            self.config.sync_settings.composite_dedup,
+        ))
+    }
+
+    /// The transactions a sync of `account` writes out of its converted
+    /// movements: zero amounts are skipped and other currencies routed like
+    /// `sync_account` does. Returned along with the assets they land on, the
+    /// account's own first.
+    fn expected_transactions(
+        &self,
+        account: &Account,
+        mut transactions: Vec<Transaction>,
+        account_currency: &str,
+    ) -> Result<(Vec<Transaction>, Vec<u64>)> {
+        self.skip_zero_amounts(&mut transactions);
+        let transactions = self.route_by_currency(account, transactions, account_currency)?;
+
+        let asset_ids = asset_ids(account, &transactions)?;
+        Ok((transactions, asset_ids))
+    }
+
+    /// The Lunch Money transactions of `asset_ids` that `expected` may
+    /// already be among, from the start of the window (or the earliest
+    /// expected date, see `ExistingQuery`) to `end_date`.
+    async fn fetch_existing(
+        &self,
+        api_token: &str,
+        asset_ids: Vec<u64>,
+        expected: &[Transaction],
+        start_date: DateTime<Utc>,
+        end_date: DateTime<Utc>,
+    ) -> Result<Vec<FetchedTransaction>> {
+        let query = ExistingQuery::new(
+            expected,
+            start_date.date_naive(),
+            self.config.sync_settings.composite_dedup,
+        );
+
+        let mut existing = Vec::new();
+        for asset_id in asset_ids {
+            let fetched = get_transactions(
+                &self.client,
+                api_token,
+                asset_id,
+                query.start,
+                end_date.date_naive(),
+            )
+            .await?;
+            existing.extend(
+                fetched
+                    .into_iter()
+                    .filter(|transaction| query.keeps(transaction)),
+            );
+        }
+        Ok(existing)
+    }
+
+    /// Inserts transactions `verify_account` found missing, with the account's
+    /// insert options. Nothing else is touched, and transactions Lunch Money
+    /// already has under the same external id are skipped by it.
+    pub async fn fill_gaps(
+        &self,
+        bank: &Bank,
+        account: &Account,
+        missing: Vec<Transaction>,
+    ) -> Result<InsertOutcome> {
+        let insert_options = self.config.sync_settings.insert_options(Some(account));
+        let mut outcome = InsertOutcome::default();
+        for chunk in &missing.into_iter().chunks(INSERT_CHUNK_SIZE) {
+            let inserted = self
+                .insert(
+                    self.config.lunch_money_token(bank, account),
+                    chunk.collect(),
+                    &insert_options,
+                )
+                .await?;
+            outcome.extend(inserted);
+        }
+        Ok(outcome)
+    }
+
+    /// Current balance of a Lunch Money asset, if the token can see it.
+    async fn asset_balance(&self, api_token: &str, asset_id: u64) -> Result<Option<f64>> {
+        Ok(self
+            .cache(api_token)
+            .assets(&self.client, api_token)
+            .await?
+            .iter()
+            .find(|asset| asset.id == Some(asset_id))
+            .map(|asset| asset.balance.0))
+    }
+
+    /// The balance a sync would set on the account's asset, next to its
+    /// current one.
+    pub async fn balance_change(&self, bank: &Bank, account: &Account) -> Result<BalanceChange> {
+        let balance = self.fetch_balance(bank, account).await?;
+        let current = self
+            .asset_balance(
+                self.config.lunch_money_token(bank, account),
+                account.asset_id()?,
+            )
+            .await?;
+
+        Ok(BalanceChange {
+            current,
+            new: balance.amount.0,
+            currency: balance.currency,
+        })
+    }
+
+    /// Computes what syncing an account would change, without writing
+    /// anything: the transactions that aren't in Lunch Money yet and the
+    /// balance update.
+    pub async fn plan_account(
+        &self,
+        bank: &Bank,
+        account: &Account,
+        start_date: DateTime<Utc>,
+        end_date: DateTime<Utc>,
+    ) -> Result<SyncPlan> {
+        let api_token = self.config.lunch_money_token(bank, account);
+        let asset_id = account.asset_id()?;
+
+        let balance = self.fetch_balance(bank, account).await?;
+        let current = self.asset_balance(api_token, asset_id).await?;
+
+        let mut plan = SyncPlan {
+            bank: bank.name.clone(),
+            account: account.name.clone(),
+            new_transactions: Vec::new(),
+            existing: 0,
+            skipped: 0,
+            balance: BalanceChange {
+                current,
+                new: balance.amount.0,
+                currency: balance.currency.clone(),
+            },
+        };
+
+        if self.skips_movements(account) {
+            return Ok(plan);
+        }
+
+        let transactions = self
+            .list_movements(bank, account, start_date, end_date)
+            .await?;
+        let before = transactions.len();
+        // Movements routed through `currency_assets` land on other assets
+        let (transactions, asset_ids) =
+            self.expected_transactions(account, transactions, &balance.currency)?;
+        plan.skipped = before - transactions.len();
+
+        let fetched = self
+            .fetch_existing(api_token, asset_ids, &transactions, start_date, end_date)
+            .await?;
+
+        let existing = ExistingTransactions::new(&fetched);
+        let duplicates = if self.config.sync_settings.composite_dedup {
+            existing.duplicates(&transactions)
+        } else {
+            transactions
+                .iter()
+                .map(|transaction| existing.contains_external_id(transaction))
+                .collect()
+        };
+        let mut duplicates = duplicates.into_iter();
+        let (present, new): (Vec<_>, Vec<_>) = transactions
+            .into_iter()
+            .partition(|_| duplicates.next().unwrap_or_default());
+        plan.existing = present.len();
+        plan.new_transactions = new;
+
+        Ok(plan)
+    }
+
+    /// Drops zero-amount transactions when `skip_zero_amount` is set, returning
+    /// how many were dropped.
+    fn skip_zero_amounts(&self, transactions: &mut Vec<Transaction>) -> usize {
+        if !self.config.sync_settings.skip_zero_amount {
+            return 0;
+        }
+
+        // Amounts are rounded to Lunch Money's decimals, so zero is exact
+        let before = transactions.len();
+        transactions.retain(|transaction| transaction.amount.0 != 0.0);
+        before - transactions.len()
+    }
+
+    /// Handles movements whose currency differs from the account's: they are
+    /// moved to the asset configured for their currency in `currency_assets`,
+    /// or dropped with a warning.
+    fn route_by_currency(
+        &self,
+        account: &Account,
+        transactions: Vec<Transaction>,
+        account_currency: &str,
+    ) -> Result<Vec<Transaction>> {
+        let mut routed = Vec::with_capacity(transactions.len());
+
+        for mut transaction in transactions {
+            let currency = transaction.currency.clone().unwrap_or_default();
+            if currency.eq_ignore_ascii_case(account_currency) {
+                routed.push(transaction);
+                continue;
+            }
+
+            match account.currency_asset_id(&currency)? {
+                Some(asset_id) => {
+                    transaction.asset_id = Some(asset_id);
+                    routed.push(transaction);
+                }
+                None => output::status(
+                    format!(
+                        "Skipping movement {}: its currency {} differs from the account currency {}. Add it to currency_assets to sync it to another asset.",
+                        transaction.external_id.as_deref().unwrap_or("unknown"),
+                        currency.to_uppercase(),
+                        account_currency
+                    )
+                    .yellow(),
+                ),
+            }
+        }
+
+        Ok(routed)
+    }
+
+    /// Removes the transactions that already exist in Lunch Money (matched by
+    /// external id) from `transactions`, updating the payee, notes and status
+    /// of those whose Fintoc data changed since they were written. Returns how
+    /// many were updated.
+    ///
+    /// A field is only overwritten while it still has the value the tool last
+    /// wrote, as recorded in `written`, so manual edits are kept. Transactions
+    /// the ledger doesn't know yet are left alone, and start being tracked once
+    /// they match their conversion.
+    async fn update_existing(
+        &self,
+        api_token: &str,
+        transactions: &mut Vec<Transaction>,
+        existing: &[FetchedTransaction],
+        written: &mut WrittenLedger,
+    ) -> Result<usize> {
+        let by_external_id = existing
+            .iter()
+            .filter_map(|t| t.external_id.as_deref().map(|id| (id, t)))
+            .collect::<HashMap<_, _>>();
+
+        let mut updated = 0;
+        let mut new_transactions = Vec::with_capacity(transactions.len());
+
+        for transaction in transactions.drain(..) {
+            let (external_id, current) = match transaction
+                .external_id
+                .as_deref()
+                .and_then(|id| by_external_id.get(id).map(|current| (id, current)))
+            {
+                Some(found) => found,
+                None => {
+                    new_transactions.push(transaction);
+                    continue;
+                }
+            };
+            // The ledger is kept per asset, and routed movements have their own
+            let Some(asset_id) = transaction.asset_id else {
+                continue;
+            };
+
+            let converted = WrittenFields::of(&transaction);
+            let current_fields = WrittenFields::of_fetched(current);
+            let Some(last_written) = written.get(asset_id, external_id).cloned() else {
+                if current_fields == converted {
+                    written.record(asset_id, external_id, converted);
+                } else {
+                    debug_log!(
+                        "Not updating transaction {} ({}): not tracked in {}",
+                        current.id,
+                        external_id,
+                        self.config.sync_settings.written_fields_file
+                    );
+                }
+                continue;
+            };
+
+            let update = written::update_for(&converted, &current_fields, &last_written);
+            if update.is_empty() {
+                continue;
+            }
+
+            debug_log!(
+                "Updating transaction {} ({:?}): {:?}",
+                current.id,
+                current.external_id,
+                update
+            );
+            update_transaction(&self.client, api_token, current.id, &update).await?;
+            written.record(asset_id, external_id, last_written.updated(&update));
+            updated += 1;
+        }
+
+        *transactions = new_transactions;
+        Ok(updated)
+    }
+
+    /// Syncs one account: inserts its movements in the window (unless
+    /// `skip_movements` or `--only-balances` is set) and updates the asset
+    /// balance.
+    ///
+    /// Progress is shown on a line of its own, which is left with the outcome.
+    pub async fn sync_account(
+        &self,
+        bank: &Bank,
+        account: &Account,
+        start_date: DateTime<Utc>,
+        end_date: DateTime<Utc>,
+    ) -> Result<AccountReport> {
+        let progress = self.account_progress(bank, account);
+        let result = self
+            .sync_account_with(bank, account, start_date, end_date, &progress)
+            .await;
+
+        progress.set_style(
+            ProgressStyle::with_template("{prefix:.bold}: {msg}").expect("valid progress template"),
+        );
+        match &result {
+            Ok(report) => progress.finish_with_message(report.summary()),
+            Err(_) => progress.abandon_with_message("failed".red().to_string()),
+        }
+        result
+    }
+
+    async fn sync_account_with(
+        &self,
+        bank: &Bank,
+        account: &Account,
+        start_date: DateTime<Utc>,
+        end_date: DateTime<Utc>,
+        progress: &ProgressBar,
+    ) -> Result<AccountReport> {
+        let started = Instant::now();
+        let mut report = AccountReport {
+            bank: bank.name.clone(),
+            account: account.name.clone(),
+            ..Default::default()
+        };
+
+        if progress.is_hidden() {
+            step(
+                progress,
+                format!("Syncing {} - {}", bank.name, account.name).bold(),
+            );
+        }
+        progress.set_message("Fetching balance...");
+
+        let balance = self.take_balance(bank, account).await?;
+        debug_log!(
+            "Fintoc balance for {} - {}: {:?}",
+            bank.name,
+            account.name,
+            balance.raw
+        );
+        let (balance_amount, balance_currency) = (balance.amount, balance.currency);
+        report.balance = Some(balance_amount.0);
+        report.currency = Some(balance_currency.clone());
+
+        step(
+            progress,
+            format!(
+                "Found current account balance: {} {}",
+                balance_amount, balance_currency
+            )
+            .blue(),
+        );
+
+        if !self.skips_movements(account) {
+            let listed = self
+                .list_movements_with(bank, account, start_date, end_date, progress)
+                .await?;
+            let mut lunchmoney_transactions = listed.transactions;
+            report.short_fetch = listed.short_fetch;
+            report.unsupported_currencies = listed.unsupported;
+            report.undated = listed.undated;
+
+            step(
+                progress,
+                format!(
+                    "Fetched a total of {} movements.",
+                    lunchmoney_transactions.len()
+                )
+                .blue(),
+            );
+
+            report.zero_amount = self.skip_zero_amounts(&mut lunchmoney_transactions);
+
+            let before = lunchmoney_transactions.len();
+            lunchmoney_transactions =
+                self.route_by_currency(account, lunchmoney_transactions, &balance_currency)?;
+            report.skipped = before - lunchmoney_transactions.len();
+
+            let api_token = self.config.lunch_money_token(bank, account);
+            let settings = &self.config.sync_settings;
+            let written_path = Path::new(&settings.written_fields_file);
+            let mut written = if settings.update_existing {
+                Some(WrittenLedger::load(written_path)?)
+            } else {
+                None
+            };
+            if settings.composite_dedup || settings.update_existing {
+                let fetched = self
+                    .fetch_existing(
+                        api_token,
+                        asset_ids(account, &lunchmoney_transactions)?,
+                        &lunchmoney_transactions,
+                        start_date,
+                        end_date,
+                    )
+                    .await?;
+
+                // Worked out before `update_existing` takes out the
+                // transactions matched by external id, since those use up
+                // the existing transactions they match
+                let duplicates: HashSet<String> = if settings.composite_dedup {
+                    let existing = ExistingTransactions::new(&fetched);
+                    lunchmoney_transactions
+                        .iter()
+                        .zip(existing.duplicates(&lunchmoney_transactions))
+                        .filter(|(_, duplicate)| *duplicate)
+                        .filter_map(|(transaction, _)| transaction.external_id.clone())
+                        .collect()
+                } else {
+                    HashSet::new()
+                };
+
+                if let Some(written) = written.as_mut() {
+                    let before = lunchmoney_transactions.len();
+                    let result = self
+                        .update_existing(api_token, &mut lunchmoney_transactions, &fetched, written)
+                        .await;
+                    // Keep what was updated before a failure
+                    written.save(written_path)?;
+                    report.updated = result?;
+                    report.existing += (before - lunchmoney_transactions.len()) as u64;
+                }
+
+                if settings.composite_dedup {
+                    let before = lunchmoney_transactions.len();
+                    lunchmoney_transactions.retain(|transaction| {
+                        !transaction
+                            .external_id
+                            .as_ref()
+                            .is_some_and(|external_id| duplicates.contains(external_id))
+                    });
+                    report.existing += (before - lunchmoney_transactions.len()) as u64;
+                }
+            }
+
+            let insert_options = self.config.sync_settings.insert_options(Some(account));
+            report.insert_overrides = account.insert_overrides();
+
+            progress.set_length(lunchmoney_transactions.len() as u64);
+            progress.set_position(0);
+            progress.set_style(
+                ProgressStyle::with_template(
+                    "{spinner} {prefix:.bold} {bar:30} {pos}/{len} {msg}",
+                )?
+                .progress_chars("=>-"),
+            );
+            progress.set_message("inserting");
+
+            // Fields to record once inserted, with the asset they land on
+            let converted: HashMap<String, (u64, WrittenFields)> = match &written {
+                Some(_) => lunchmoney_transactions
+                    .iter()
+                    .filter_map(|transaction| {
+                        let external_id = transaction.external_id.clone()?;
+                        let asset_id = transaction.asset_id?;
+                        Some((external_id, (asset_id, WrittenFields::of(transaction))))
+                    })
+                    .collect(),
+                None => HashMap::new(),
+            };
+
+            // To count the inserted transactions still pending at the bank
+            let pending_ids: HashSet<String> = lunchmoney_transactions
+                .iter()
+                .filter(|transaction| transaction.is_pending == Some(true))
+                .filter_map(|transaction| transaction.external_id.clone())
+                .collect();
+
+            for transaction_chunk in &lunchmoney_transactions
+                .into_iter()
+                .chunks(INSERT_CHUNK_SIZE)
+            {
+                let transaction_chunk: Vec<Transaction> = transaction_chunk.collect();
+                let chunk_len = transaction_chunk.len() as u64;
+                let outcome = self
+                    .insert(api_token, transaction_chunk, &insert_options)
+                    .await?;
+
+                if let Some(written) = written.as_mut() {
+                    for external_id in &outcome.inserted_external_ids {
+                        if let Some((asset_id, fields)) = converted.get(external_id) {
+                            written.record(*asset_id, external_id, fields.clone());
+                        }
+                    }
+                    written.save(written_path)?;
+                }
+
+                let pending = outcome
+                    .inserted_external_ids
+                    .iter()
+                    .filter(|external_id| pending_ids.contains(*external_id))
+                    .count();
+                report.pending += pending;
+                report.posted += outcome.inserted_ids.len() - pending;
+                report.existing += outcome.existing_count;
+                report.inserted += outcome.inserted_ids.len();
+                report.failed += outcome.failed.len();
+                progress.inc(chunk_len);
+            }
+
+            if report.failed > 0 {
+                output::status(
+                    format!(
+                        "{} transactions failed to insert and were saved to {}. Run `retry` to re-attempt them, then sync again to update the balance.",
+                        report.failed, self.config.sync_settings.dead_letter_file
+                    )
+                    .yellow(),
+                );
+            }
+
+            if report.updated > 0 {
+                step(
+                    progress,
+                    format!(
+                        "Updated {} existing transactions with changed Fintoc data.",
+                        report.updated
+                    )
+                    .blue(),
+                );
+            }
+
+            if report.existing > 0 {
+                step(
+                    progress,
+                    format!(
+                        "Finished syncing movements for {} - {} with {} existing transactions.",
+                        bank.name, account.name, report.existing
+                    )
+                    .blue(),
+                );
+            } else {
+                step(
+                    progress,
+                    format!(
+                        "Finished syncing movements for {} - {}.",
+                        bank.name, account.name
+                    )
+                    .blue(),
+                );
+            }
+        } else if account.skip_movements {
+            output::status(
+                format!(
+                    "Skipping movements sync for {} - {} per configuration.",
+                    bank.name, account.name
+                )
+                .yellow(),
+            );
+        }
+
+        if self.force_balance {
+            if let Some(problem) = account.implausible_balance(balance_amount.0) {
+                output::status(
+                    format!(
+                        "Updating the asset balance of {} - {} despite --force: Fintoc balance {}.",
+                        bank.name, account.name, problem
+                    )
+                    .yellow(),
+                );
+            }
+        }
+
+        if report.failed > 0 {
+            // The balance would include movements that are missing in Lunch
+            // Money, leave it until they are retried
+            output::status(
+                format!(
+                    "Not updating the asset balance of {} - {} because its movements are incomplete.",
+                    bank.name, account.name
+                )
+                .yellow(),
+            );
+        } else if let Some(problem) = account
+            .implausible_balance(balance_amount.0)
+            .filter(|_| !self.force_balance)
+        {
+            // Likely a corrupted Fintoc response, don't overwrite a good balance
+            output::status(
+                format!(
+                    "Refusing to update the asset balance of {} - {}: Fintoc balance {}. Pass --force to update it anyway.",
+                    bank.name, account.name, problem
+                )
+                .red()
+                .bold(),
+            );
+            report.balance_implausible = true;
+        } else if !self
+            .is_manual_asset(
+                self.config.lunch_money_token(bank, account),
+                account.asset_id()?,
+            )
+            .await?
+        {
+            // Lunch Money rejects balance updates on Plaid-linked accounts
+            output::status(
+                format!(
+                    "Not updating the asset balance of {} - {} because its asset {} is not a manual Lunch Money asset.",
+                    bank.name,
+                    account.name,
+                    account.asset_id()?
+                )
+                .yellow(),
+            );
+            report.balance_not_manual = true;
+        } else if self
+            .update_balance(
+                bank,
+                account,
+                balance_amount,
+                balance_currency.clone(),
+                balance.refreshed_at,
+            )
+            .await?
+        {
+            report.balance_updated = true;
+
+            step(
+                progress,
+                format!(
+                    "Updated asset balance successfully to {} {}",
+                    balance_amount, balance_currency
+                )
+                .blue(),
+            );
+        } else {
+            report.balance_unchanged = true;
+
+            step(
+                progress,
+                format!(
+                    "Asset balance is already {} {}, not updating it.",
+                    balance_amount, balance_currency
+                )
+                .blue(),
+            );
+        }
+
+        debug_log!(
+            "Synced {} - {} in {} ms",
+            bank.name,
+            account.name,
+            started.elapsed().as_millis()
+        );
+
+        // Finished sync! (either with or without movements)
+        if report.existing > 0 {
+            step(
+                progress,
+                format!(
+                    "Finished sync for {} - {} with {} existing transactions.",
+                    bank.name, account.name, report.existing
+                )
+                .bold(),
+            );
+        } else {
+            step(
+                progress,
+                format!("Finished sync for {} - {}.", bank.name, account.name).bold(),
+            );
+        }
+
+        Ok(report)
+    }
+}
+
+/// Assets `transactions` land on, the account's own first, which includes the
+/// ones movements are routed to through `currency_assets`.
+fn asset_ids(account: &Account, transactions: &[Transaction]) -> Result<Vec<u64>> {
+    Ok(std::iter::once(account.asset_id()?)
+        .chain(
+            transactions
+                .iter()
+                .filter_map(|transaction| transaction.asset_id),
+        )
+        .unique()
+        .collect())
+}
+
+/// Which Lunch Money transactions to look at to find those `expected` may
+/// already be among. Fintoc selects movements by post date, so with the
+/// transaction date some are dated before the window. The query starts early
+/// enough to find them, and what it finds before the window is only kept when
+/// it matches one of them, by external id or with `composite_dedup` by
+/// composite key.
+struct ExistingQuery<'a> {
+    /// First day to fetch, the window's or the earliest expected one
+    start: NaiveDate,
+    window_start: NaiveDate,
+    expected_ids: HashSet<&'a str>,
+    expected_keys: HashSet<String>,
+}
+
+impl<'a> ExistingQuery<'a> {
+    fn new(expected: &'a [Transaction], window_start: NaiveDate, composite_dedup: bool) -> Self {
+        let start = expected
+            .iter()
+            .map(|transaction| transaction.date.date_naive())
+            .min()
+            .map_or(window_start, |earliest| earliest.min(window_start));
+        let expected_ids = expected
+            .iter()
+            .filter_map(|transaction| transaction.external_id.as_deref())
+            .collect();
+        let expected_keys = if composite_dedup {
+            expected.iter().map(Transaction::composite_key).collect()
+        } else {
+            HashSet::new()
+        };
+
+        Self {
+            start,
+            window_start,
+            expected_ids,
+            expected_keys,
+        }
+    }
+
+    fn keeps(&self, transaction: &FetchedTransaction) -> bool {
+        transaction.date >= self.window_start
+            || transaction
+                .external_id
+                .as_deref()
+                .is_some_and(|external_id| self.expected_ids.contains(external_id))
+            || self.expected_keys.contains(&transaction.composite_key())
+    }
+}
+
+/// Shows a sync step on the account's progress line, or as a status line when
+/// progress bars are hidden.
+fn step(progress: &ProgressBar, message: ColoredString) {
+    if progress.is_hidden() {
+        output::status(message);
+    } else {
+        progress.set_message(message.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::fintoc::{ConversionOptions, DateField, MovementType};
+
+    fn syncer() -> Syncer {
+        let config = serde_json::from_value(serde_json::json!({
+            "tokens": {
+                "fintoc_secret_token": "sk_test",
+                "lunch_money_api_token": "lm_test",
+            },
+            "banks": [{
+                "name": "Banco",
+                "link_token": "link_test",
+                "accounts": [{
+                    "name": "Cuenta Corriente",
+                    "fintoc_account_id": "acc_1",
+                    "lunch_money_asset_id": "1",
+                    "type": "Checking",
+                    "currency_assets": {"USD": "2"},
+                }],
+            }],
+            "sync_settings": {
+                "default_start_from": "30d",
+                "composite_dedup": true,
+            },
+        }))
+        .unwrap();
+        Syncer::new(config).unwrap()
+    }
+
+    fn transaction(id: &str, amount: i64, currency: &str) -> Transaction {
+        Movement {
+            id: id.to_string(),
+            object: "movement".to_string(),
+            amount,
+            post_date: Some("2024-05-01T12:00:00Z".parse().unwrap()),
+            description: "COMPRA NACIONAL".to_string(),
+            transaction_date: None,
+            currency: currency.to_string(),
+            reference_id: None,
+            movement_type: MovementType::Other,
+            pending: false,
+            recipient_account: None,
+            sender_account: None,
+            comment: None,
+        }
+        .to_lunchmoney_transaction(1, &ConversionOptions::default())
+        .unwrap()
+    }
+
+    fn fetched(id: u64, asset_id: u64, external_id: &str, amount: &str) -> FetchedTransaction {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "date": "2024-05-01",
+            "payee": "COMPRA NACIONAL",
+            "amount": amount,
+            "asset_id": asset_id,
+            "external_id": external_id,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn verify_expects_what_sync_writes() {
+        let syncer = syncer();
+        let account = &syncer.config().banks[0].accounts[0];
+        let transactions = vec![
+            // In Lunch Money under the id it had before the bank changed it
+            transaction("mov_new", -1500, "CLP"),
+            // Routed to the USD asset
+            transaction("mov_usd", -1000, "USD"),
+            // Skipped, no asset for its currency
+            transaction("mov_eur", -1000, "EUR"),
+        ];
+
+        let (expected, asset_ids) = syncer
+            .expected_transactions(account, transactions, "CLP")
+            .unwrap();
+        assert_eq!(asset_ids, [1, 2]);
+        assert_eq!(
+            expected
+                .iter()
+                .map(|transaction| transaction.external_id.as_deref().unwrap())
+                .collect::<Vec<_>>(),
+            ["mov_new", "mov_usd"]
+        );
+
+        let existing = vec![
+            fetched(10, 1, "mov_old", "-1500.0000"),
+            fetched(11, 2, "mov_usd", "-10.0000"),
+        ];
+        let reconciliation = verify::reconcile(expected, existing, true);
+        assert!(reconciliation.is_clean(), "{:?}", reconciliation);
+    }
+
+    /// A movement posted on the window's first day but made two days earlier,
+    /// converted with `date_field = transaction_date`.
+    fn made_before_the_window(id: &str) -> Transaction {
+        Movement {
+            id: id.to_string(),
+            object: "movement".to_string(),
+            amount: -1500,
+            post_date: Some("2024-05-01T12:00:00Z".parse().unwrap()),
+            description: "COMPRA NACIONAL".to_string(),
+            transaction_date: Some("2024-04-29T12:00:00Z".parse().unwrap()),
+            currency: "CLP".to_string(),
+            reference_id: None,
+            movement_type: MovementType::Other,
+            pending: false,
+            recipient_account: None,
+            sender_account: None,
+            comment: None,
+        }
+        .to_lunchmoney_transaction(
+            1,
+            &ConversionOptions {
+                date_field: DateField::TransactionDate,
+                ..Default::default()
+            },
+        )
+        .unwrap()
+    }
+
+    fn fetched_on(id: u64, external_id: &str, date: &str) -> FetchedTransaction {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "date": date,
+            "payee": "COMPRA NACIONAL",
+            "amount": "-1500.0000",
+            "asset_id": 1,
+            "external_id": external_id,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn existing_transactions_dated_before_the_window_are_fetched() {
+        let window_start = NaiveDate::from_ymd_opt(2024, 5, 1).unwrap();
+        let expected = vec![made_before_the_window("mov_1")];
+
+        let query = ExistingQuery::new(&expected, window_start, false);
+        assert_eq!(query.start, NaiveDate::from_ymd_opt(2024, 4, 29).unwrap());
+
+        let fetched = vec![
+            fetched_on(10, "mov_1", "2024-04-29"),
+            // Before the window and not expected
+            fetched_on(11, "mov_other", "2024-04-30"),
+        ]
+        .into_iter()
+        .filter(|transaction| query.keeps(transaction))
+        .collect::<Vec<_>>();
+        assert_eq!(
+            fetched
+                .iter()
+                .map(|transaction| transaction.id)
+                .collect::<Vec<_>>(),
+            [10]
+        );
+
+        // So a dry run doesn't list it as new
+        let existing = ExistingTransactions::new(&fetched);
+        assert!(existing.contains_external_id(&expected[0]));
+    }
+
+    #[test]
+    fn changed_ids_dated_before_the_window_are_deduped() {
+        let window_start = NaiveDate::from_ymd_opt(2024, 5, 1).unwrap();
+        let expected = vec![made_before_the_window("mov_new")];
+        let fetched = vec![fetched_on(10, "mov_old", "2024-04-29")];
+
+        let query = ExistingQuery::new(&expected, window_start, true);
+        assert!(query.keeps(&fetched[0]));
+        assert_eq!(
+            ExistingTransactions::new(&fetched).duplicates(&expected),
+            [true]
+        );
+
+        // Without composite_dedup nothing else would match it
+        assert!(!ExistingQuery::new(&expected, window_start, false).keeps(&fetched[0]));
+    }
+
+    #[tokio::test]
+    async fn existing_transactions_dated_before_the_window_are_updated() {
+        let syncer = syncer();
+        let window_start = NaiveDate::from_ymd_opt(2024, 5, 1).unwrap();
+        let mut transactions = vec![made_before_the_window("mov_1")];
+        let mut current = fetched_on(10, "mov_1", "2024-04-29");
+        current.status = Some(transactions[0].status.as_str().to_string());
+
+        let query = ExistingQuery::new(&transactions, window_start, false);
+        let existing = std::iter::once(current)
+            .filter(|transaction| query.keeps(transaction))
+            .collect::<Vec<_>>();
+
+        // Found, so it's left out of the inserts and starts being tracked
+        let mut written = WrittenLedger::default();
+        let updated = syncer
+            .update_existing("lm_test", &mut transactions, &existing, &mut written)
+            .await
+            .unwrap();
+        assert_eq!(updated, 0);
+        assert!(transactions.is_empty());
+        assert!(written.get(1, "mov_1").is_some());
+    }
+}
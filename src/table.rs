@@ -0,0 +1,132 @@
+//! Aligned table rendering for listings (`--format table`).
+
+use std::collections::HashMap;
+use std::fmt::Display;
+
+use comfy_table::presets::UTF8_FULL;
+use comfy_table::{Cell, CellAlignment, Color, ContentArrangement, Table};
+
+use crate::audit::AssetAudit;
+use crate::currency::{self, CurrencySpec};
+use crate::output;
+use crate::types::lunchmoney::{Asset, Transaction};
+
+/// Cell with its text redacted up front, so columns are sized for what's shown.
+fn cell(content: impl Display) -> Cell {
+    Cell::new(output::redact(&content.to_string()))
+}
+
+fn new_table(header: &[&str]) -> Table {
+    let mut table = Table::new();
+    table
+        .load_preset(UTF8_FULL)
+        .set_content_arrangement(ContentArrangement::Dynamic)
+        .set_header(header.to_vec());
+    table
+}
+
+/// Right-aligned amount, green when positive and red when negative.
+fn amount_cell(amount: f64, code: Option<&str>) -> Cell {
+    let color = if amount >= 0.0 {
+        Color::Green
+    } else {
+        Color::Red
+    };
+    cell(currency::format_amount_plain(amount, code))
+        .fg(color)
+        .set_alignment(CellAlignment::Right)
+}
+
+/// Transactions, with category and tags columns when any transaction has
+/// them.
+pub fn transactions_table(
+    transactions: &[Transaction],
+    category_names: &HashMap<u64, String>,
+) -> Table {
+    let details = transactions.iter().any(|transaction| {
+        transaction.category_id.is_some()
+            || transaction.tags.as_ref().is_some_and(|t| !t.is_empty())
+    });
+
+    let mut header = vec!["Date", "Payee", "Amount", "Currency"];
+    if details {
+        header.extend(["Category", "Tags"]);
+    }
+    let mut table = new_table(&header);
+    for transaction in transactions {
+        let currency = transaction.currency.as_deref();
+        let mut row = vec![
+            cell(transaction.date.format("%Y-%m-%d")),
+            cell(transaction.payee.as_deref().unwrap_or("Unknown")),
+            amount_cell(transaction.amount.0, currency),
+            cell(currency.unwrap_or("unk").to_uppercase()),
+        ];
+        if details {
+            row.push(cell(
+                transaction
+                    .category_label(category_names)
+                    .unwrap_or_default(),
+            ));
+            row.push(cell(
+                transaction.tags.as_deref().unwrap_or_default().join(", "),
+            ));
+        }
+        table.add_row(row);
+    }
+    table
+}
+
+pub fn currencies_table(currencies: &[CurrencySpec]) -> Table {
+    let mut table = new_table(&["Currency", "Exponent", "Symbol", "Precision", "Example"]);
+    for spec in currencies {
+        table.add_row(vec![
+            cell(spec.code),
+            cell(spec.exponent).set_alignment(CellAlignment::Right),
+            cell(spec.symbol),
+            cell(spec.precision).set_alignment(CellAlignment::Right),
+            cell(currency::format_amount_plain(1234.5, Some(spec.code)))
+                .set_alignment(CellAlignment::Right),
+        ]);
+    }
+    table
+}
+
+pub fn assets_table(assets: &[Asset]) -> Table {
+    let mut table = new_table(&["ID", "Name", "Balance", "Currency"]);
+    for asset in assets {
+        table.add_row(vec![
+            cell(asset.id.map(|id| id.to_string()).unwrap_or_default()),
+            cell(asset.display_name.as_deref().unwrap_or("Unnamed")),
+            amount_cell(asset.balance.0, Some(&asset.currency)),
+            cell(asset.currency.to_uppercase()),
+        ]);
+    }
+    table
+}
+
+pub fn audit_table(audits: &[AssetAudit]) -> Table {
+    let mut table = new_table(&[
+        "Bank",
+        "Account",
+        "Fintoc currency",
+        "Asset",
+        "Asset currency",
+        "Status",
+    ]);
+    for audit in audits {
+        let color = if audit.status.is_ok() {
+            Color::Green
+        } else {
+            Color::Red
+        };
+        table.add_row(vec![
+            cell(&audit.bank),
+            cell(&audit.account),
+            cell(audit.fintoc_currency.as_deref().unwrap_or("-")),
+            cell(&audit.asset_id),
+            cell(audit.asset_currency.as_deref().unwrap_or("-")),
+            cell(audit.status.describe()).fg(color),
+        ]);
+    }
+    table
+}
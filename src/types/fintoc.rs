@@ -1,11 +1,16 @@
 #![allow(dead_code)]
 
-use chrono::{DateTime, Utc};
-use serde::Deserialize;
+use std::fmt;
+
+use chrono::{DateTime, Months, Utc};
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
 
 use super::lunchmoney;
+use crate::currency;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Institution {
     pub id: String,
     pub name: String,
@@ -14,9 +19,12 @@ pub struct Institution {
 
 #[derive(Debug, Deserialize)]
 pub struct Balance {
-    pub available: i128,
+    // Some credit products omit `available` and `limit`
+    #[serde(default)]
+    pub available: Option<i128>,
     pub current: i128,
-    pub limit: i128,
+    #[serde(default)]
+    pub limit: Option<i128>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -35,7 +43,7 @@ pub struct Account {
     pub refreshed_at: Option<DateTime<Utc>>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct TransferAccount {
     pub holder_id: String,
     pub holder_name: String,
@@ -43,7 +51,7 @@ pub struct TransferAccount {
     pub institution: Option<Institution>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum MovementType {
     Transfer,
@@ -51,12 +59,26 @@ pub enum MovementType {
     Other,
 }
 
-#[derive(Debug, Deserialize)]
+impl fmt::Display for MovementType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            MovementType::Transfer => "transfer",
+            MovementType::Check => "check",
+            MovementType::Other => "other",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Movement {
     pub id: String,
     pub object: String,
-    pub amount: i32,
-    pub post_date: DateTime<Utc>,
+    /// In minor units. CLP amounts are unscaled, so large ones don't fit an i32
+    pub amount: i64,
+    /// Normally always set, but incomplete movements shouldn't fail the whole
+    /// page
+    pub post_date: Option<DateTime<Utc>>,
     pub description: String,
     pub transaction_date: Option<DateTime<Utc>>,
     pub currency: String,
@@ -72,10 +94,245 @@ pub struct Movement {
 // Strings for now
 type Error = String;
 
+lazy_static! {
+    static ref TEMPLATE_PLACEHOLDER: Regex = Regex::new(r"\{(\w+)\}").unwrap();
+}
+
+/// Which movement field populates the Lunch Money `external_id`, used to
+/// detect duplicates.
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExternalIdSource {
+    /// Fintoc's movement id
+    #[default]
+    Id,
+    /// The bank's own reference, falling back to the movement id when missing
+    ReferenceId,
+    /// The date/amount/description key described in `crate::dedup`
+    Composite,
+}
+
+/// Movement field a payee can be taken from.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PayeeSource {
+    /// Fintoc's `comment`
+    Comment,
+    /// The bank description, with common prefixes stripped
+    Description,
+}
+
+/// Movement field joined into the notes by `notes_fields`.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum NotesField {
+    /// Fintoc's `comment`
+    Comment,
+    /// The bank's reference
+    ReferenceId,
+    /// The raw bank description
+    Description,
+    /// The bank description, with common prefixes stripped
+    CleanDescription,
+    /// The movement type, e.g. "transfer"
+    Type,
+}
+
+/// When the raw bank description is sent as the transaction's
+/// `original_name`.
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OriginalName {
+    /// On every transaction
+    #[default]
+    Always,
+    /// Only when it differs from the payee
+    WhenDifferent,
+    /// Never
+    Never,
+}
+
+/// Case applied to the account holder names used as transfer payees.
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum PayeeCase {
+    /// Keep the name as Fintoc sends it
+    #[default]
+    None,
+    /// "JUAN DE LA FUENTE" becomes "Juan de la Fuente"
+    TitleCase,
+    /// "JUAN DE LA FUENTE" becomes "juan de la fuente"
+    Lower,
+}
+
+/// Spanish particles kept lowercase inside title-cased names.
+const NAME_PARTICLES: &[&str] = &["de", "del", "la", "las", "los", "y", "e"];
+
+impl PayeeCase {
+    pub fn apply(self, name: &str) -> String {
+        match self {
+            PayeeCase::None => name.to_string(),
+            PayeeCase::Lower => name.to_lowercase(),
+            PayeeCase::TitleCase => name
+                .split(' ')
+                .enumerate()
+                .map(|(index, word)| {
+                    let word = word.to_lowercase();
+                    if index > 0 && NAME_PARTICLES.contains(&word.as_str()) {
+                        word
+                    } else {
+                        capitalize(&word)
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join(" "),
+        }
+    }
+}
+
+/// Uppercases the first letter of `word` and of each part after a hyphen or
+/// apostrophe, e.g. "pérez-o'higgins" becomes "Pérez-O'Higgins". Works on
+/// chars, so accented letters are handled.
+fn capitalize(word: &str) -> String {
+    let mut capitalized = String::with_capacity(word.len());
+    let mut start = true;
+    for c in word.chars() {
+        if start {
+            capitalized.extend(c.to_uppercase());
+        } else {
+            capitalized.push(c);
+        }
+        start = c == '-' || c == '\'';
+    }
+    capitalized
+}
+
+/// Which movement date becomes the Lunch Money transaction date.
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DateField {
+    /// When the purchase happened, falling back to the post date for movements
+    /// without one
+    #[default]
+    TransactionDate,
+    /// When the bank posted the movement
+    PostDate,
+}
+
+/// What to do with movements that have neither a post nor a transaction date.
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum MissingDate {
+    /// Leave them out, with a warning
+    #[default]
+    Skip,
+    /// Date them when they were fetched
+    FetchTime,
+}
+
+/// Split of movements whose description matches `pattern` into monthly
+/// installments.
+#[derive(Debug, Clone)]
+pub struct InstallmentSplit {
+    pub pattern: Regex,
+    pub installments: u32,
+    /// Tag added to split transactions
+    pub tag: Option<String>,
+}
+
+/// Settings that tweak how a `Movement` is turned into a Lunch Money `Transaction`.
+#[derive(Debug, Default, Clone)]
+pub struct ConversionOptions {
+    /// Template for the transaction notes, e.g. `"{type} | ref {reference_id}"`.
+    /// When unset, the notes are the Fintoc `comment`.
+    pub notes_template: Option<String>,
+    /// Fields joined with `notes_separator` into the notes, when there's no
+    /// template. Empty or missing fields are skipped.
+    pub notes_fields: Vec<NotesField>,
+    pub notes_separator: String,
+    /// Rules assigning a category to movements whose description matches.
+    /// The first matching rule wins.
+    pub category_rules: Vec<(Regex, u64)>,
+    /// Rules splitting movements into installments. The first matching rule
+    /// wins.
+    pub split_rules: Vec<InstallmentSplit>,
+    /// Category for movements no rule matched.
+    pub default_category_id: Option<u64>,
+    /// Minor-unit exponent overriding the currency's default, for accounts
+    /// whose amounts Fintoc already scales differently.
+    pub amount_scale: Option<u32>,
+    /// Rounding applied when scaling puts more decimals in an amount than
+    /// Lunch Money keeps.
+    pub rounding: currency::RoundingMode,
+    /// Field the transaction's `external_id` is taken from.
+    pub external_id_source: ExternalIdSource,
+    /// Movement date used as the transaction date.
+    pub date_field: DateField,
+    /// Handling of movements without any date.
+    pub missing_date: MissingDate,
+    /// Case applied to holder names used as transfer payees.
+    pub payee_case: PayeeCase,
+    /// Use `"Check #<reference_id>"` as the payee of check movements that have
+    /// a reference.
+    pub check_payee: bool,
+    /// Negate amounts, for banks whose movements come with the opposite sign
+    /// of Fintoc's usual convention (outflows negative). Transfers take their
+    /// counterparty from the negated amount too.
+    pub flip_sign: bool,
+    /// Payee used when the derived one is blank, "Unknown" when unset.
+    pub empty_payee: Option<String>,
+    /// Payee for movements without a usable description. Transfers with a
+    /// known counterparty and numbered checks keep their derived payee.
+    pub default_payee: Option<String>,
+    /// Descriptions considered junk, replaced by `default_payee`. When unset,
+    /// every description is.
+    pub junk_payee_pattern: Option<Regex>,
+    /// Fields tried in order for the payee of movements without a derived
+    /// one, before falling back to `default_payee`. Empty means description
+    /// only.
+    pub payee_sources: Vec<PayeeSource>,
+    /// When to set `original_name` to the bank description.
+    pub original_name: OriginalName,
+    /// Tags added to every transaction.
+    pub tags: Vec<String>,
+    /// Also tag transactions with their movement type, e.g. "transfer".
+    pub movement_type_tag: bool,
+}
+
+/// Fewest characters of an institution name kept when shortening a transfer
+/// payee. With less room, the institution is left out.
+const MIN_INSTITUTION_CHARS: usize = 3;
+
+/// `"Holder (Institution)"`, shortening the institution first when it doesn't
+/// fit Lunch Money's payee limit, so the holder name is kept whole.
+fn transfer_payee(holder: &str, institution: &str) -> String {
+    let payee = format!("{} ({})", holder, institution);
+    if payee.chars().count() <= lunchmoney::MAX_PAYEE_LEN {
+        return payee;
+    }
+
+    // Room left once the holder, " (" and "…)" are in
+    let room = lunchmoney::MAX_PAYEE_LEN.saturating_sub(holder.chars().count() + 4);
+    if room < MIN_INSTITUTION_CHARS {
+        return lunchmoney::fit_payee(holder);
+    }
+    let institution: String = institution.chars().take(room).collect();
+    format!("{} ({}…)", holder, institution.trim_end())
+}
+
 impl Movement {
+    /// The configured tags, plus the movement type with `movement_type_tag`.
+    fn tags(&self, options: &ConversionOptions) -> Option<Vec<String>> {
+        let mut tags = options.tags.clone();
+        if options.movement_type_tag {
+            tags.push(self.movement_type.to_string());
+        }
+        (!tags.is_empty()).then_some(tags)
+    }
+
     pub fn clean_description(&self) -> String {
         // Strip common prefixes if present
-        // TODO: Expand this list, or map these to pretty names 
+        // TODO: Expand this list, or map these to pretty names
         let re = regex::Regex::new(
             r#"^(?i)(COMPRA INTERNACIONAL|COMPRA NACIONAL|PAGO RECURRENTE|COMPRA INTER.)\s"#,
         )
@@ -83,26 +340,178 @@ impl Movement {
         re.replace(&self.description, "").to_string()
     }
 
+    /// Value of a movement field as used by notes templates. Returns `None` for
+    /// unknown field names; optional fields that are unset render as empty.
+    fn template_field(&self, name: &str) -> Option<String> {
+        let value = match name {
+            "id" => self.id.clone(),
+            "type" => self.movement_type.to_string(),
+            "amount" => self.amount.to_string(),
+            "currency" => self.currency.clone(),
+            "description" => self.description.clone(),
+            "comment" => self.comment.clone().unwrap_or_default(),
+            "reference_id" => self.reference_id.clone().unwrap_or_default(),
+            "post_date" => self
+                .post_date
+                .map(|date| date.format("%Y-%m-%d").to_string())
+                .unwrap_or_default(),
+            "transaction_date" => self
+                .transaction_date
+                .map(|date| date.format("%Y-%m-%d").to_string())
+                .unwrap_or_default(),
+            "pending" => self.pending.to_string(),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /// Fills `{field}` placeholders in `template` with this movement's values.
+    /// Unknown placeholders are left as-is.
+    pub fn render_template(&self, template: &str) -> String {
+        TEMPLATE_PLACEHOLDER
+            .replace_all(template, |caps: &Captures| {
+                self.template_field(&caps[1])
+                    .unwrap_or_else(|| caps[0].to_string())
+            })
+            .into_owned()
+    }
+
+    /// Payee from the first usable source in `payee_sources`, or the account's
+    /// default payee when none is. Without a default payee the cleaned
+    /// description is used as a last resort, even if empty.
+    fn description_payee(&self, options: &ConversionOptions) -> String {
+        let description = self.clean_description();
+        let sources = if options.payee_sources.is_empty() {
+            &[PayeeSource::Description][..]
+        } else {
+            &options.payee_sources[..]
+        };
+
+        for source in sources {
+            match source {
+                PayeeSource::Comment => {
+                    if let Some(comment) = self
+                        .comment
+                        .as_deref()
+                        .map(str::trim)
+                        .filter(|comment| !comment.is_empty())
+                    {
+                        return comment.to_string();
+                    }
+                }
+                PayeeSource::Description => {
+                    // With a default payee, descriptions matching the junk
+                    // pattern (or any, without one) are not usable
+                    let junk = options.default_payee.is_some()
+                        && options
+                            .junk_payee_pattern
+                            .as_ref()
+                            .is_none_or(|pattern| pattern.is_match(&description));
+                    if !description.trim().is_empty() && !junk {
+                        return description;
+                    }
+                }
+            }
+        }
+
+        options.default_payee.clone().unwrap_or(description)
+    }
+
+    fn category_id(&self, options: &ConversionOptions) -> Option<u64> {
+        options
+            .category_rules
+            .iter()
+            .find(|(pattern, _)| pattern.is_match(&self.description))
+            .map(|(_, category_id)| *category_id)
+            .or(options.default_category_id)
+    }
+
+    fn notes(&self, options: &ConversionOptions) -> Option<String> {
+        match &options.notes_template {
+            Some(template) => {
+                let notes = self.render_template(template);
+                if notes.trim().is_empty() {
+                    None
+                } else {
+                    Some(notes)
+                }
+            }
+            None if !options.notes_fields.is_empty() => {
+                let parts: Vec<String> = options
+                    .notes_fields
+                    .iter()
+                    .filter_map(|field| self.notes_field(*field))
+                    .collect();
+                (!parts.is_empty()).then(|| parts.join(&options.notes_separator))
+            }
+            None => self.comment.clone(),
+        }
+    }
+
+    /// Trimmed value of a `notes_fields` entry, `None` when unset or empty.
+    fn notes_field(&self, field: NotesField) -> Option<String> {
+        let value = match field {
+            NotesField::Comment => self.comment.clone()?,
+            NotesField::ReferenceId => self.reference_id.clone()?,
+            NotesField::Description => self.description.clone(),
+            NotesField::CleanDescription => self.clean_description(),
+            NotesField::Type => self.movement_type.to_string(),
+        };
+        let value = value.trim();
+        (!value.is_empty()).then(|| value.to_string())
+    }
+
+    /// The date `field` picks, falling back to the other one. `None` when
+    /// Fintoc sent neither.
+    pub fn date(&self, field: DateField) -> Option<DateTime<Utc>> {
+        match field {
+            DateField::TransactionDate => self.transaction_date.or(self.post_date),
+            DateField::PostDate => self.post_date.or(self.transaction_date),
+        }
+    }
+
     pub fn to_lunchmoney_transaction(
         &self,
         asset_id: u64,
+        options: &ConversionOptions,
     ) -> Result<lunchmoney::Transaction, Error> {
-        let amount = match self.currency.to_uppercase().as_str() {
-            "CLP" => lunchmoney::Amount(self.amount as f64),
-            "USD" => lunchmoney::Amount(self.amount as f64 / 100.0),
-            "EUR" => lunchmoney::Amount(self.amount as f64 / 100.0),
-            _ => {
+        let date = match (self.date(options.date_field), options.missing_date) {
+            (Some(date), _) => date,
+            (None, MissingDate::FetchTime) => Utc::now(),
+            (None, MissingDate::Skip) => {
+                return Err(format!("Movement {} has no date.", self.id));
+            }
+        };
+        let scale = match options
+            .amount_scale
+            .or_else(|| currency::find(&self.currency).map(|spec| spec.exponent))
+        {
+            Some(scale) => scale,
+            None => {
                 return Err(format!(
                     "Currency {} is not supported.",
                     self.currency.to_uppercase(),
                 ));
             }
         };
-        
+        // Inserts use `debit_as_negative`, so negative amounts are expenses.
+        // `flip_sign` corrects a bank's inverted signs, so the flipped amount
+        // is also the one telling deposits from withdrawals below.
+        let minor_units = if options.flip_sign {
+            -self.amount
+        } else {
+            self.amount
+        };
+        let amount = lunchmoney::Amount(currency::to_major_units_rounded(
+            minor_units,
+            scale,
+            currency::LUNCH_MONEY_DECIMALS,
+            options.rounding,
+        ));
 
         let payee = match &self.movement_type {
             MovementType::Transfer => {
-                let payee = if self.amount > 0 {
+                let payee = if minor_units > 0 {
                     // Deposit by another account
                     &self.sender_account
                 } else {
@@ -112,38 +521,680 @@ impl Movement {
                 match payee {
                     Some(account) => match &account.institution {
                         // Add institution name if available
-                        Some(institution) => {
-                            format!("{} ({})", account.holder_name, institution.name)
-                        }
+                        Some(institution) => transfer_payee(
+                            &options.payee_case.apply(&account.holder_name),
+                            &institution.name,
+                        ),
                         // Otherwise, just use the account holder name
-                        None => account.holder_name.clone(),
+                        None => options.payee_case.apply(&account.holder_name),
                     },
-                    None => self.clean_description(),
+                    None => self.description_payee(options),
                 }
             }
+            MovementType::Check if options.check_payee => match &self.reference_id {
+                Some(reference_id) if !reference_id.trim().is_empty() => {
+                    format!("Check #{}", reference_id.trim())
+                }
+                _ => self.description_payee(options),
+            },
             // If it's not a transfer, just clean the movement description
             // provided by the bank
-            _ => self.clean_description(),
+            _ => self.description_payee(options),
+        };
+        // Descriptions can clean down to nothing, which shows as a blank payee
+        let payee = if payee.trim().is_empty() {
+            options
+                .empty_payee
+                .clone()
+                .unwrap_or_else(|| "Unknown".to_string())
+        } else {
+            lunchmoney::fit_payee(&payee)
         };
 
-        Ok(lunchmoney::Transaction {
-            date: self.transaction_date.unwrap_or(self.post_date),
+        let mut transaction = lunchmoney::Transaction {
+            date,
             payee: Some(payee),
             amount,
             currency: Some(self.currency.to_lowercase()),
             asset_id: Some(asset_id),
-            notes: self.comment.clone(),
-            external_id: Some(self.id.clone()),
+            notes: self.notes(options),
+            category_id: self.category_id(options),
+            external_id: None,
             status: lunchmoney::TransactionStatus::Uncleared,
             original_name: Some(self.description.clone()),
             is_pending: Some(self.pending),
+            tags: self.tags(options),
             ..Default::default()
-        })
+        };
+
+        let external_id = match options.external_id_source {
+            ExternalIdSource::Id => self.id.clone(),
+            ExternalIdSource::ReferenceId => match &self.reference_id {
+                Some(reference_id) if !reference_id.trim().is_empty() => {
+                    reference_id.trim().to_string()
+                }
+                _ => self.id.clone(),
+            },
+            ExternalIdSource::Composite => match self.date(options.date_field) {
+                Some(_) => transaction.composite_key(),
+                // A fetch-time date changes from one run to the next, so it
+                // can't identify the movement
+                None => self.id.clone(),
+            },
+        };
+        transaction.external_id = Some(lunchmoney::fit_external_id(&external_id));
+
+        // After the external id, so composite ids don't depend on the setting
+        let keep_original_name = match options.original_name {
+            OriginalName::Always => true,
+            OriginalName::WhenDifferent => transaction.payee.as_deref() != Some(&self.description),
+            OriginalName::Never => false,
+        };
+        if !keep_original_name {
+            transaction.original_name = None;
+        }
+
+        transaction.sanitize();
+
+        if let Some(rule) = options
+            .split_rules
+            .iter()
+            .find(|rule| rule.pattern.is_match(&self.description))
+        {
+            transaction.split = Some(installments(&transaction, rule.installments, scale));
+            if let Some(tag) = &rule.tag {
+                transaction
+                    .tags
+                    .get_or_insert_with(Vec::new)
+                    .push(tag.clone());
+            }
+        }
+
+        Ok(transaction)
     }
 }
 
+/// Splits `transaction` into `count` equal parts a month apart, starting on its
+/// date. Amounts are divided in the currency's minor units (or Lunch Money's
+/// smallest decimal, when finer), and the first part takes the remainder so
+/// the parts add up exactly.
+fn installments(
+    transaction: &lunchmoney::Transaction,
+    count: u32,
+    scale: u32,
+) -> Vec<lunchmoney::SplitPart> {
+    let factor = 10f64.powi(scale.min(currency::LUNCH_MONEY_DECIMALS) as i32);
+    let total = (transaction.amount.0 * factor).round() as i64;
+    let share = total / count as i64;
+    let remainder = total - share * count as i64;
+    let date = transaction.date.date_naive();
+
+    (0..count)
+        .map(|i| {
+            let units = if i == 0 { share + remainder } else { share };
+            lunchmoney::SplitPart {
+                date: date.checked_add_months(Months::new(i)).unwrap_or(date),
+                payee: transaction.payee.clone(),
+                amount: lunchmoney::Amount(units as f64 / factor),
+                category_id: transaction.category_id,
+                notes: Some(format!("Installment {}/{}", i + 1, count)),
+            }
+        })
+        .collect()
+}
+
 pub struct AccountCredentials {
     pub secret_token: String,
     pub link_token: String,
     pub account_id: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn movement(movement_type: MovementType, amount: i64) -> Movement {
+        Movement {
+            id: "mov_1".to_string(),
+            object: "movement".to_string(),
+            amount,
+            post_date: Some("2024-05-01T12:00:00Z".parse().unwrap()),
+            description: "COMPRA NACIONAL LIDER".to_string(),
+            transaction_date: None,
+            currency: "CLP".to_string(),
+            reference_id: None,
+            movement_type,
+            pending: false,
+            recipient_account: None,
+            sender_account: None,
+            comment: None,
+        }
+    }
+
+    fn convert(movement: &Movement, options: &ConversionOptions) -> lunchmoney::Transaction {
+        movement.to_lunchmoney_transaction(1, options).unwrap()
+    }
+
+    fn transfer(amount: i64) -> Movement {
+        let account = |holder_name: &str| TransferAccount {
+            holder_id: format!("{}_id", holder_name),
+            holder_name: holder_name.to_string(),
+            number: None,
+            institution: None,
+        };
+        Movement {
+            sender_account: Some(account("SENDER")),
+            recipient_account: Some(account("RECIPIENT")),
+            ..movement(MovementType::Transfer, amount)
+        }
+    }
+
+    fn split_rule(installments: u32) -> ConversionOptions {
+        ConversionOptions {
+            split_rules: vec![InstallmentSplit {
+                pattern: Regex::new("LIDER").unwrap(),
+                installments,
+                tag: Some("cuotas".to_string()),
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn over_length_movement_ids_give_a_stable_external_id() {
+        let long_id = format!("mov_{}", "a".repeat(lunchmoney::MAX_EXTERNAL_ID_LEN));
+        let movement = Movement {
+            id: long_id,
+            ..movement(MovementType::Other, -1_000)
+        };
+
+        let first = convert(&movement, &ConversionOptions::default()).external_id;
+        let second = convert(&movement, &ConversionOptions::default()).external_id;
+
+        let external_id = first.unwrap();
+        assert!(external_id.len() <= lunchmoney::MAX_EXTERNAL_ID_LEN);
+        assert_eq!(Some(external_id), second);
+    }
+
+    #[test]
+    fn title_case_handles_accents_and_particles() {
+        assert_eq!(
+            PayeeCase::TitleCase.apply("JOSÉ ÑUÑEZ DE LA FUENTE"),
+            "José Ñuñez de la Fuente"
+        );
+        assert_eq!(
+            PayeeCase::TitleCase.apply("MARÍA PÉREZ-O'HIGGINS"),
+            "María Pérez-O'Higgins"
+        );
+        assert_eq!(
+            PayeeCase::Lower.apply("ÁLVARO ÉRRAZURIZ"),
+            "álvaro érrazuriz"
+        );
+        assert_eq!(PayeeCase::None.apply("ÁLVARO"), "ÁLVARO");
+    }
+
+    #[test]
+    fn payee_case_applies_to_transfer_holders() {
+        let options = ConversionOptions {
+            payee_case: PayeeCase::TitleCase,
+            ..Default::default()
+        };
+
+        let transaction = convert(&transfer(1_000), &options);
+        assert_eq!(transaction.payee.as_deref(), Some("Sender"));
+
+        // Descriptions are left alone
+        let transaction = convert(&movement(MovementType::Other, -1_000), &options);
+        assert_eq!(transaction.payee.as_deref(), Some("LIDER"));
+    }
+
+    fn with_comment(movement: Movement, comment: Option<&str>) -> Movement {
+        Movement {
+            comment: comment.map(str::to_string),
+            ..movement
+        }
+    }
+
+    #[test]
+    fn payee_sources_are_tried_in_order() {
+        let comment_first = ConversionOptions {
+            payee_sources: vec![PayeeSource::Comment, PayeeSource::Description],
+            default_payee: Some("Sin descripción".to_string()),
+            junk_payee_pattern: Some(Regex::new("^$").unwrap()),
+            ..Default::default()
+        };
+        let commented = with_comment(movement(MovementType::Other, -1_000), Some(" Almuerzo "));
+        let blank_comment = with_comment(movement(MovementType::Other, -1_000), Some("  "));
+
+        assert_eq!(
+            convert(&commented, &comment_first).payee.as_deref(),
+            Some("Almuerzo")
+        );
+        assert_eq!(
+            convert(&blank_comment, &comment_first).payee.as_deref(),
+            Some("LIDER")
+        );
+
+        // Description first only falls to the comment for junk descriptions
+        let description_first = ConversionOptions {
+            payee_sources: vec![PayeeSource::Description, PayeeSource::Comment],
+            ..comment_first
+        };
+        assert_eq!(
+            convert(&commented, &description_first).payee.as_deref(),
+            Some("LIDER")
+        );
+    }
+
+    #[test]
+    fn default_payee_is_the_last_resort() {
+        let options = ConversionOptions {
+            payee_sources: vec![PayeeSource::Comment, PayeeSource::Description],
+            default_payee: Some("Sin descripción".to_string()),
+            junk_payee_pattern: Some(Regex::new("LIDER").unwrap()),
+            ..Default::default()
+        };
+        let transaction = convert(&movement(MovementType::Other, -1_000), &options);
+
+        assert_eq!(transaction.payee.as_deref(), Some("Sin descripción"));
+    }
+
+    #[test]
+    fn transfer_counterparty_takes_precedence_over_payee_sources() {
+        let options = ConversionOptions {
+            payee_sources: vec![PayeeSource::Comment],
+            ..Default::default()
+        };
+        let transaction = convert(&with_comment(transfer(1_000), Some("Almuerzo")), &options);
+
+        assert_eq!(transaction.payee.as_deref(), Some("SENDER"));
+    }
+
+    #[test]
+    fn control_characters_are_stripped_from_converted_text() {
+        let movement = Movement {
+            description: "COMPRA NACIONAL LIDER\t\u{7}\r\n  EXPRESS\n".to_string(),
+            comment: Some("cuota\u{0} 1\t".to_string()),
+            ..movement(MovementType::Other, -1_000)
+        };
+        let transaction = convert(&movement, &ConversionOptions::default());
+
+        assert_eq!(transaction.payee.as_deref(), Some("LIDER EXPRESS"));
+        assert_eq!(transaction.notes.as_deref(), Some("cuota 1"));
+        assert_eq!(
+            transaction.original_name.as_deref(),
+            Some("COMPRA NACIONAL LIDER EXPRESS")
+        );
+    }
+
+    #[test]
+    fn amounts_beyond_i32_deserialize_and_convert() {
+        let movement: Movement = serde_json::from_str(
+            r#"{"id": "mov_1", "object": "movement", "amount": -3000000000,
+                "post_date": "2024-05-01T12:00:00Z", "description": "COMPRA NACIONAL LIDER",
+                "transaction_date": null, "currency": "CLP", "reference_id": null,
+                "type": "other", "pending": false, "recipient_account": null,
+                "sender_account": null, "comment": null}"#,
+        )
+        .unwrap();
+        assert!(movement.amount < i64::from(i32::MIN));
+
+        let transaction = convert(&movement, &ConversionOptions::default());
+        assert_eq!(transaction.amount.0, -3_000_000_000.0);
+    }
+
+    #[test]
+    fn descriptions_cleaning_to_nothing_get_a_placeholder() {
+        let movement = Movement {
+            description: "COMPRA NACIONAL   ".to_string(),
+            ..movement(MovementType::Other, -1_000)
+        };
+
+        let transaction = convert(&movement, &ConversionOptions::default());
+        assert_eq!(transaction.payee.as_deref(), Some("Unknown"));
+
+        let options = ConversionOptions {
+            empty_payee: Some("Sin nombre".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            convert(&movement, &options).payee.as_deref(),
+            Some("Sin nombre")
+        );
+    }
+
+    #[test]
+    fn movement_type_tag_joins_the_static_tags() {
+        let options = ConversionOptions {
+            movement_type_tag: true,
+            ..Default::default()
+        };
+        let transaction = convert(&transfer(1_000), &options);
+        assert_eq!(transaction.tags, Some(vec!["transfer".to_string()]));
+
+        let options = ConversionOptions {
+            tags: vec!["fintoc".to_string()],
+            ..options
+        };
+        let transaction = convert(&transfer(1_000), &options);
+        assert_eq!(
+            transaction.tags,
+            Some(vec!["fintoc".to_string(), "transfer".to_string()])
+        );
+        let json = serde_json::to_value(&transaction).unwrap();
+        assert_eq!(json["tags"], serde_json::json!(["fintoc", "transfer"]));
+    }
+
+    #[test]
+    fn no_tags_without_settings() {
+        let transaction = convert(&transfer(1_000), &ConversionOptions::default());
+        assert_eq!(transaction.tags, None);
+    }
+
+    #[test]
+    fn long_transfer_payees_shorten_the_institution_first() {
+        let holder = "JUAN ANDRÉS DE LA FUENTE";
+        let institution = format!("Banco {} S.A.", "Muy Largo ".repeat(20));
+        let payee = transfer_payee(holder, &institution);
+
+        assert_eq!(payee.chars().count(), lunchmoney::MAX_PAYEE_LEN);
+        assert!(payee.starts_with("JUAN ANDRÉS DE LA FUENTE (Banco Muy Largo"));
+        assert!(payee.ends_with("…)"));
+    }
+
+    #[test]
+    fn transfer_payees_that_fit_are_kept() {
+        assert_eq!(
+            transfer_payee("JUAN PÉREZ", "Banco de Chile"),
+            "JUAN PÉREZ (Banco de Chile)"
+        );
+    }
+
+    #[test]
+    fn institution_is_dropped_when_the_holder_leaves_no_room() {
+        let holder = "A".repeat(lunchmoney::MAX_PAYEE_LEN - 5);
+        assert_eq!(transfer_payee(&holder, "Banco de Chile"), holder);
+
+        let holder = "A".repeat(lunchmoney::MAX_PAYEE_LEN + 5);
+        let payee = transfer_payee(&holder, "Banco de Chile");
+        assert_eq!(payee.chars().count(), lunchmoney::MAX_PAYEE_LEN);
+        assert!(payee.ends_with('…'));
+    }
+
+    #[test]
+    fn installments_keep_the_sign_of_an_expense() {
+        let transaction = convert(&movement(MovementType::Other, -1_000), &split_rule(3));
+        let parts = transaction.split.unwrap();
+
+        let amounts: Vec<f64> = parts.iter().map(|part| part.amount.0).collect();
+        assert_eq!(amounts, vec![-334.0, -333.0, -333.0]);
+        assert_eq!(amounts.iter().sum::<f64>(), transaction.amount.0);
+    }
+
+    #[test]
+    fn installments_keep_the_sign_of_income() {
+        let transaction = convert(&movement(MovementType::Other, 1_000), &split_rule(3));
+        let amounts: Vec<f64> = transaction
+            .split
+            .unwrap()
+            .iter()
+            .map(|part| part.amount.0)
+            .collect();
+
+        assert_eq!(amounts, vec![334.0, 333.0, 333.0]);
+    }
+
+    #[test]
+    fn installments_follow_flip_sign() {
+        let options = ConversionOptions {
+            flip_sign: true,
+            ..split_rule(2)
+        };
+        let transaction = convert(&movement(MovementType::Other, -1_000), &options);
+
+        assert!(transaction
+            .split
+            .unwrap()
+            .iter()
+            .all(|part| part.amount.0 == 500.0));
+    }
+
+    #[test]
+    fn signs_with_and_without_flip_sign() {
+        // (movement, flip_sign, expected amount, expected payee)
+        let cases = [
+            (transfer(-1_000), false, -1_000.0, "RECIPIENT"),
+            (transfer(1_000), false, 1_000.0, "SENDER"),
+            (transfer(-1_000), true, 1_000.0, "SENDER"),
+            (transfer(1_000), true, -1_000.0, "RECIPIENT"),
+            (
+                movement(MovementType::Other, -1_000),
+                false,
+                -1_000.0,
+                "LIDER",
+            ),
+            (
+                movement(MovementType::Other, 1_000),
+                false,
+                1_000.0,
+                "LIDER",
+            ),
+            (
+                movement(MovementType::Other, -1_000),
+                true,
+                1_000.0,
+                "LIDER",
+            ),
+            (
+                movement(MovementType::Other, 1_000),
+                true,
+                -1_000.0,
+                "LIDER",
+            ),
+            (
+                movement(MovementType::Check, -1_000),
+                false,
+                -1_000.0,
+                "LIDER",
+            ),
+            (
+                movement(MovementType::Check, -1_000),
+                true,
+                1_000.0,
+                "LIDER",
+            ),
+        ];
+
+        for (movement, flip_sign, amount, payee) in cases {
+            let options = ConversionOptions {
+                flip_sign,
+                ..Default::default()
+            };
+            let transaction = convert(&movement, &options);
+
+            let case = format!(
+                "{} {} flip_sign={}",
+                movement.movement_type, movement.amount, flip_sign
+            );
+            assert_eq!(transaction.amount.0, amount, "{}", case);
+            assert_eq!(transaction.payee.as_deref(), Some(payee), "{}", case);
+        }
+    }
+
+    #[test]
+    fn installments_are_a_month_apart_and_numbered() {
+        let transaction = convert(&movement(MovementType::Other, -1_000), &split_rule(3));
+        let parts = transaction.split.unwrap();
+
+        let dates: Vec<String> = parts.iter().map(|part| part.date.to_string()).collect();
+        assert_eq!(dates, vec!["2024-05-01", "2024-06-01", "2024-07-01"]);
+        assert_eq!(parts[2].notes.as_deref(), Some("Installment 3/3"));
+        assert_eq!(transaction.tags, Some(vec!["cuotas".to_string()]));
+    }
+
+    #[test]
+    fn installments_split_in_minor_units() {
+        let mut usd = movement(MovementType::Other, -10_000);
+        usd.currency = "USD".to_string();
+        let amounts: Vec<f64> = convert(&usd, &split_rule(3))
+            .split
+            .unwrap()
+            .iter()
+            .map(|part| part.amount.0)
+            .collect();
+
+        assert_eq!(amounts, vec![-33.34, -33.33, -33.33]);
+    }
+
+    #[test]
+    fn movements_no_rule_matches_are_not_split() {
+        let mut other = movement(MovementType::Other, -1_000);
+        other.description = "PAGO RECURRENTE NETFLIX".to_string();
+
+        assert!(convert(&other, &split_rule(3)).split.is_none());
+    }
+
+    #[test]
+    fn check_with_reference_is_named_after_it() {
+        let mut check = movement(MovementType::Check, -50_000);
+        check.reference_id = Some(" 000123 ".to_string());
+        let options = ConversionOptions {
+            check_payee: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            convert(&check, &options).payee.as_deref(),
+            Some("Check #000123")
+        );
+    }
+
+    #[test]
+    fn check_without_reference_uses_the_cleaned_description() {
+        let check = movement(MovementType::Check, -50_000);
+        let options = ConversionOptions {
+            check_payee: true,
+            ..Default::default()
+        };
+
+        assert_eq!(convert(&check, &options).payee.as_deref(), Some("LIDER"));
+    }
+
+    #[test]
+    fn check_payee_is_off_by_default() {
+        let mut check = movement(MovementType::Check, -50_000);
+        check.reference_id = Some("000123".to_string());
+
+        assert_eq!(
+            convert(&check, &ConversionOptions::default())
+                .payee
+                .as_deref(),
+            Some("LIDER")
+        );
+    }
+
+    #[test]
+    fn undated_movements_get_their_fintoc_id_as_composite_external_id() {
+        let undated = Movement {
+            post_date: None,
+            ..movement(MovementType::Other, -1_000)
+        };
+        let options = ConversionOptions {
+            external_id_source: ExternalIdSource::Composite,
+            missing_date: MissingDate::FetchTime,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            convert(&undated, &options).external_id.as_deref(),
+            Some("mov_1")
+        );
+        assert_eq!(
+            convert(&movement(MovementType::Other, -1_000), &options)
+                .external_id
+                .as_deref(),
+            Some("2024-05-01|-1000.0000|compra nacional lider")
+        );
+    }
+
+    #[test]
+    fn templates_are_filled_with_movement_fields() {
+        let movement = Movement {
+            reference_id: Some("REF9".to_string()),
+            ..with_comment(movement(MovementType::Other, -1_500), Some("cuota 1"))
+        };
+        assert_eq!(
+            movement.render_template(
+                "{type} {amount} {currency}: {comment} ({reference_id}, {post_date})"
+            ),
+            "other -1500 CLP: cuota 1 (REF9, 2024-05-01)"
+        );
+    }
+
+    #[test]
+    fn unset_template_fields_render_empty() {
+        let movement = with_comment(movement(MovementType::Other, -1_500), Some("cuota 1"));
+        assert_eq!(
+            movement.render_template("{comment}|{reference_id}|{transaction_date}"),
+            "cuota 1||"
+        );
+    }
+
+    #[test]
+    fn unknown_template_placeholders_are_left_as_is() {
+        let movement = movement(MovementType::Other, -1_500);
+        assert_eq!(movement.render_template("{id} {nope}"), "mov_1 {nope}");
+    }
+
+    #[test]
+    fn notes_template_sets_the_notes_unless_blank() {
+        let movement = with_comment(movement(MovementType::Other, -1_500), Some("cuota 1"));
+        let options = |template: &str| ConversionOptions {
+            notes_template: Some(template.to_string()),
+            ..Default::default()
+        };
+
+        let transaction = convert(&movement, &options("{comment} - {id}"));
+        assert_eq!(transaction.notes.as_deref(), Some("cuota 1 - mov_1"));
+
+        let transaction = convert(&movement, &options(" {reference_id} "));
+        assert_eq!(transaction.notes, None);
+    }
+
+    #[test]
+    fn notes_fields_are_joined_skipping_empty_ones() {
+        let movement = Movement {
+            reference_id: Some("  ".to_string()),
+            ..movement(MovementType::Other, -1_500)
+        };
+        let options = ConversionOptions {
+            notes_fields: vec![
+                NotesField::Type,
+                NotesField::Comment,
+                NotesField::ReferenceId,
+                NotesField::Description,
+            ],
+            notes_separator: " | ".to_string(),
+            ..Default::default()
+        };
+
+        let transaction = convert(&movement, &options);
+        assert_eq!(
+            transaction.notes.as_deref(),
+            Some("other | COMPRA NACIONAL LIDER")
+        );
+    }
+
+    #[test]
+    fn notes_template_takes_precedence_over_notes_fields() {
+        let movement = with_comment(movement(MovementType::Other, -1_500), Some("cuota 1"));
+        let options = ConversionOptions {
+            notes_template: Some("{id}".to_string()),
+            notes_fields: vec![NotesField::Comment, NotesField::Type],
+            ..Default::default()
+        };
+
+        let transaction = convert(&movement, &options);
+        assert_eq!(transaction.notes.as_deref(), Some("mov_1"));
+    }
+}
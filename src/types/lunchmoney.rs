@@ -1,24 +1,30 @@
+use std::collections::HashMap;
 use std::fmt;
 use std::num::ParseFloatError;
 use std::str::FromStr;
 use std::time::UNIX_EPOCH;
 
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, NaiveDate, Utc};
 use colored::*;
 use serde::{Deserialize, Serialize};
-use serde_with::{serde_as, skip_serializing_none, DisplayFromStr};
+use serde_with::{serde_as, skip_serializing_none, DisplayFromStr, OneOrMany, PickFirst, Same};
+use sha2::{Digest, Sha256};
 
-use currency_rs::{Currency, CurrencyOpts};
+/// Lunch Money id, which some responses send as a string. Either is read,
+/// and ids are written back as numbers.
+type LenientId = PickFirst<(Same, DisplayFromStr)>;
 
 /// Tag object as described in https://lunchmoney.dev/#tags-object.
-#[derive(Debug, Serialize)]
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Tag {
+    #[serde_as(as = "LenientId")]
     pub id: u64,
     pub name: String,
     pub description: String,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 #[allow(dead_code)]
 pub enum TransactionStatus {
@@ -26,6 +32,16 @@ pub enum TransactionStatus {
     Uncleared,
 }
 
+impl TransactionStatus {
+    /// The status as Lunch Money names it.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransactionStatus::Cleared => "cleared",
+            TransactionStatus::Uncleared => "uncleared",
+        }
+    }
+}
+
 /// An f64 that serializes to a float up to 4 decimal places, as specified in the `Transaction`
 /// amount field description in https://lunchmoney.dev/#transaction-object.
 /// TODO: Verify the sanity of using floats over decimals for currency amounts.
@@ -52,10 +68,43 @@ impl From<f64> for Amount {
     }
 }
 
+/// Longest `external_id` Lunch Money accepts.
+pub const MAX_EXTERNAL_ID_LEN: usize = 75;
+
+/// Makes `id` usable as an `external_id`: ids that fit are kept as-is, longer
+/// ones are replaced by a SHA-256 hash, which is stable across runs and fits
+/// the limit.
+pub fn fit_external_id(id: &str) -> String {
+    if id.len() <= MAX_EXTERNAL_ID_LEN {
+        return id.to_string();
+    }
+
+    let digest = Sha256::digest(id.as_bytes());
+    let hex = digest
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+    format!("sha256:{}", hex)
+}
+
+/// Longest `payee` Lunch Money accepts.
+pub const MAX_PAYEE_LEN: usize = 140;
+
+/// Cuts `payee` down to `MAX_PAYEE_LEN` characters, ending it with an ellipsis
+/// when it was shortened.
+pub fn fit_payee(payee: &str) -> String {
+    if payee.chars().count() <= MAX_PAYEE_LEN {
+        return payee.to_string();
+    }
+
+    let kept: String = payee.chars().take(MAX_PAYEE_LEN - 1).collect();
+    format!("{}…", kept.trim_end())
+}
+
 /// Transaction object as defined in https://lunchmoney.dev/#transaction-object
 #[serde_as]
 #[skip_serializing_none]
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Transaction {
     pub id: Option<u64>,
     pub date: DateTime<Utc>,
@@ -72,11 +121,17 @@ pub struct Transaction {
     pub parent_id: Option<u64>,
     pub is_group: Option<bool>,
     pub group_id: Option<u64>,
-    pub tags: Option<Vec<Tag>>,
+    /// Tag names. Lunch Money creates the ones that don't exist yet.
+    pub tags: Option<Vec<String>>,
     pub external_id: Option<String>,
     pub notes: Option<String>,
     pub original_name: Option<String>,
     pub is_pending: Option<bool>,
+    /// Parts to split the transaction into once it's inserted. Kept in the
+    /// dead-letter file, but not sent with the insert, as Lunch Money only
+    /// splits existing transactions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub split: Option<Vec<SplitPart>>,
 }
 
 impl Default for Transaction {
@@ -98,42 +153,49 @@ impl Default for Transaction {
             external_id: None,
             original_name: None,
             is_pending: None,
+            split: None,
         }
     }
 }
 
+/// Replaces control characters (tabs, newlines, stray bytes) and the
+/// replacement characters left by invalid UTF-8 with spaces, then collapses
+/// runs of whitespace and trims the ends.
+pub fn sanitize_text(text: &str) -> String {
+    text.chars()
+        .map(|c| {
+            if c.is_control() || c == char::REPLACEMENT_CHARACTER {
+                ' '
+            } else {
+                c
+            }
+        })
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
 impl Transaction {
+    /// Cleans up `payee`, `notes` and `original_name` with `sanitize_text`,
+    /// as bank descriptions can carry characters that render badly in Lunch
+    /// Money or get the insert rejected.
+    pub fn sanitize(&mut self) {
+        for text in [&mut self.payee, &mut self.notes, &mut self.original_name]
+            .into_iter()
+            .flatten()
+        {
+            *text = sanitize_text(text);
+        }
+    }
+
     pub fn to_colored_string(&self) -> ColoredString {
         let payee = match &self.payee {
             Some(payee) => payee.clone(),
             None => "Unknown".to_string(),
         };
 
-        let opt = match &self.currency {
-            Some(currency) => match currency.to_uppercase().as_str() {
-                "USD" => CurrencyOpts::new()
-                    .set_symbol("$")
-                    .set_precision(2)
-                    .set_from_cents(false),
-                "EUR" => CurrencyOpts::new()
-                    .set_symbol("€")
-                    .set_precision(2)
-                    .set_from_cents(false),
-                "CLP" => CurrencyOpts::new()
-                    .set_symbol("$")
-                    .set_precision(0)
-                    .set_from_cents(false),
-                _ => CurrencyOpts::default(),
-            },
-            None => CurrencyOpts::default(),
-        };
-
-        let currency: Currency = Currency::new_float(self.amount.0, Some(opt));
-
-        let amount = match self.amount.0 {
-            amount if amount >= 0.0 => currency.format().green(),
-            _ => currency.format().red(),
-        };
+        let amount = crate::currency::format_amount(self.amount.0, self.currency.as_deref());
 
         let currency_unit = &self
             .currency
@@ -149,11 +211,83 @@ impl Transaction {
         )
         .normal()
     }
+
+    /// `to_colored_string` followed by the category and tags the transaction
+    /// would be inserted with, to check category rules and tags before
+    /// syncing. Categories missing from `category_names` are shown by id.
+    pub fn to_preview_string(&self, category_names: &HashMap<u64, String>) -> ColoredString {
+        let mut line = self.to_colored_string().to_string();
+        if let Some(category) = self.category_label(category_names) {
+            line.push_str(&format!(" [category: {}]", category));
+        }
+        if let Some(tags) = self.tags.as_ref().filter(|tags| !tags.is_empty()) {
+            line.push_str(&format!(" [tags: {}]", tags.join(", ")));
+        }
+        if let Some(split) = &self.split {
+            line.push_str(&format!(" [split into {} installments]", split.len()));
+        }
+        line.normal()
+    }
+
+    /// The category's name (with its id), or `#id` when the name isn't known.
+    pub fn category_label(&self, category_names: &HashMap<u64, String>) -> Option<String> {
+        let category_id = self.category_id?;
+        Some(match category_names.get(&category_id) {
+            Some(name) => format!("{} (#{})", name, category_id),
+            None => format!("#{}", category_id),
+        })
+    }
+}
+
+/// Transaction as returned by https://lunchmoney.dev/#get-all-transactions.
+/// Unlike `Transaction`, which is what we insert, dates are plain days and the
+/// status may be any of the values Lunch Money uses.
+#[serde_as]
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[allow(dead_code)]
+pub struct FetchedTransaction {
+    #[serde_as(as = "LenientId")]
+    pub id: u64,
+    pub date: NaiveDate,
+    pub payee: Option<String>,
+    #[serde_as(as = "DisplayFromStr")]
+    pub amount: Amount,
+    pub currency: Option<String>,
+    #[serde_as(as = "Option<LenientId>")]
+    pub category_id: Option<u64>,
+    #[serde_as(as = "Option<LenientId>")]
+    pub asset_id: Option<u64>,
+    pub status: Option<String>,
+    /// Set on the parts of a split transaction, to the transaction they were
+    /// split from
+    #[serde_as(as = "Option<LenientId>")]
+    #[serde(default)]
+    pub parent_id: Option<u64>,
+    pub external_id: Option<String>,
+    pub notes: Option<String>,
+    pub original_name: Option<String>,
+    pub is_pending: Option<bool>,
+    #[serde(default)]
+    pub tags: Option<Vec<TransactionTag>>,
+}
+
+/// Tag as embedded in a fetched transaction. Only the name is read.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TransactionTag {
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GetAllTransactionsResponse {
+    pub transactions: Vec<FetchedTransaction>,
+    #[serde(default)]
+    pub has_more: bool,
 }
 
 #[serde_as]
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Asset {
+    #[serde_as(as = "Option<LenientId>")]
     pub id: Option<u64>,
     #[serde(rename = "type_name")]
     pub type_: Option<String>,
@@ -195,6 +329,47 @@ pub struct GetAllAssetsResponse {
     pub assets: Vec<Asset>,
 }
 
+/// Plaid-linked account as returned by https://lunchmoney.dev/#get-all-plaid-accounts
+#[serde_as]
+#[derive(Debug, Clone, Deserialize)]
+#[allow(dead_code)]
+pub struct PlaidAccount {
+    #[serde_as(as = "LenientId")]
+    pub id: u64,
+    pub name: String,
+    pub display_name: Option<String>,
+    pub institution_name: Option<String>,
+    pub status: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GetAllPlaidAccountsResponse {
+    pub plaid_accounts: Vec<PlaidAccount>,
+}
+
+/// Category object as defined in https://lunchmoney.dev/#category-object
+#[serde_as]
+#[derive(Debug, Clone, Deserialize)]
+#[allow(dead_code)]
+pub struct Category {
+    #[serde_as(as = "LenientId")]
+    pub id: u64,
+    pub name: String,
+    pub description: Option<String>,
+    pub is_income: bool,
+    pub exclude_from_budget: bool,
+    pub exclude_from_totals: bool,
+    pub is_group: bool,
+    #[serde_as(as = "Option<LenientId>")]
+    pub group_id: Option<u64>,
+    pub archived: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GetAllCategoriesResponse {
+    pub categories: Vec<Category>,
+}
+
 #[skip_serializing_none]
 #[derive(Debug, Serialize)]
 pub struct InsertTransactionRequest<'a> {
@@ -206,8 +381,285 @@ pub struct InsertTransactionRequest<'a> {
     pub skip_balance_update: Option<bool>,
 }
 
+#[serde_as]
 #[derive(Debug, Deserialize)]
 pub struct InsertTransactionResponse {
+    #[serde_as(as = "Option<Vec<LenientId>>")]
+    #[serde(default)]
     pub ids: Option<Vec<u64>>,
+    // Usually a list, but single errors can come as a plain string
+    #[serde_as(as = "Option<OneOrMany<_>>")]
+    #[serde(default)]
     pub error: Option<Vec<String>>,
 }
+
+/// Fields of an existing transaction to change, as accepted by
+/// https://lunchmoney.dev/#update-transaction. Unset fields are left as they are.
+#[skip_serializing_none]
+#[derive(Debug, Default, Serialize)]
+pub struct TransactionUpdate {
+    pub payee: Option<String>,
+    pub notes: Option<String>,
+    /// "cleared" or "uncleared"
+    pub status: Option<String>,
+    /// Tag names, replacing the transaction's tags. Tags that don't exist yet
+    /// are created.
+    pub tags: Option<Vec<String>>,
+}
+
+impl TransactionUpdate {
+    pub fn is_empty(&self) -> bool {
+        self.payee.is_none() && self.notes.is_none() && self.status.is_none() && self.tags.is_none()
+    }
+}
+
+impl FetchedTransaction {
+    /// The update adding `tag` to the transaction, keeping the tags it
+    /// already has. `None` when it's already tagged with it.
+    pub fn tag_update(&self, tag: &str) -> Option<TransactionUpdate> {
+        let mut tags = self
+            .tags
+            .iter()
+            .flatten()
+            .map(|existing| existing.name.clone())
+            .collect::<Vec<_>>();
+        if tags.iter().any(|name| name == tag) {
+            return None;
+        }
+        tags.push(tag.to_string());
+
+        Some(TransactionUpdate {
+            tags: Some(tags),
+            ..Default::default()
+        })
+    }
+}
+
+#[skip_serializing_none]
+#[derive(Debug, Serialize)]
+pub struct UpdateTransactionRequest<'a> {
+    pub transaction: &'a TransactionUpdate,
+    pub split: Option<&'a [SplitPart]>,
+    pub debit_as_negative: Option<bool>,
+}
+
+#[serde_as]
+#[derive(Debug, Deserialize)]
+pub struct UpdateTransactionResponse {
+    pub updated: Option<bool>,
+    /// Ids of the transactions created by a split
+    #[serde_as(as = "Option<Vec<LenientId>>")]
+    #[serde(default)]
+    pub split: Option<Vec<u64>>,
+    pub error: Option<serde_json::Value>,
+}
+
+/// One part of a split transaction, as accepted by
+/// https://lunchmoney.dev/#update-transaction. Lunch Money creates a child
+/// transaction per part, with `parent_id` set to the split one, and the parts
+/// must add up to its amount.
+#[serde_as]
+#[skip_serializing_none]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SplitPart {
+    pub date: NaiveDate,
+    pub payee: Option<String>,
+    #[serde_as(as = "DisplayFromStr")]
+    pub amount: Amount,
+    pub category_id: Option<u64>,
+    pub notes: Option<String>,
+}
+
+/// Body of https://lunchmoney.dev/#create-transaction-group
+#[skip_serializing_none]
+#[derive(Debug, Serialize)]
+pub struct CreateTransactionGroupRequest {
+    pub date: NaiveDate,
+    pub payee: String,
+    pub category_id: Option<u64>,
+    pub notes: Option<String>,
+    /// Ids of the transactions to group
+    pub transactions: Vec<u64>,
+}
+
+/// Lunch Money answers a group creation with the bare id of the new group, or
+/// an error object.
+#[serde_as]
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum CreateTransactionGroupResponse {
+    Id(#[serde_as(as = "LenientId")] u64),
+    Error { error: serde_json::Value },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_external_ids_are_kept() {
+        assert_eq!(fit_external_id("mov_123"), "mov_123");
+        let at_limit = "x".repeat(MAX_EXTERNAL_ID_LEN);
+        assert_eq!(fit_external_id(&at_limit), at_limit);
+    }
+
+    #[test]
+    fn long_external_ids_are_hashed_deterministically() {
+        let long = format!("mov_{}", "9".repeat(MAX_EXTERNAL_ID_LEN));
+        let fitted = fit_external_id(&long);
+
+        assert!(fitted.len() <= MAX_EXTERNAL_ID_LEN);
+        assert!(fitted.starts_with("sha256:"));
+        assert_eq!(fitted, fit_external_id(&long));
+        assert_ne!(fitted, fit_external_id(&format!("{}0", long)));
+    }
+
+    #[test]
+    fn sanitize_text_strips_control_characters_and_collapses_whitespace() {
+        assert_eq!(
+            sanitize_text("  COMPRA\tNACIONAL\r\n\u{0}LIDER\u{1b}  \u{fffd}EXPRESS "),
+            "COMPRA NACIONAL LIDER EXPRESS"
+        );
+        assert_eq!(sanitize_text("Pérez  Ñuñoa"), "Pérez Ñuñoa");
+    }
+
+    #[test]
+    fn short_payees_are_kept() {
+        assert_eq!(fit_payee("Lider"), "Lider");
+        let at_limit = "ñ".repeat(MAX_PAYEE_LEN);
+        assert_eq!(fit_payee(&at_limit), at_limit);
+    }
+
+    #[test]
+    fn long_payees_end_with_an_ellipsis() {
+        let payee = fit_payee(&"ñ".repeat(MAX_PAYEE_LEN + 10));
+        assert_eq!(payee.chars().count(), MAX_PAYEE_LEN);
+        assert!(payee.ends_with('…'));
+    }
+
+    #[test]
+    fn asset_ids_read_as_numbers_or_strings() {
+        for id in [serde_json::json!(42), serde_json::json!("42")] {
+            let asset: Asset = serde_json::from_value(serde_json::json!({
+                "id": id,
+                "balance": "1500.0000",
+                "currency": "clp",
+            }))
+            .unwrap();
+            assert_eq!(asset.id, Some(42));
+        }
+    }
+
+    #[test]
+    fn insert_response_ids_read_as_numbers_or_strings() {
+        for ids in [r#"[1, 2]"#, r#"["1", "2"]"#, r#"[1, "2"]"#] {
+            let response: InsertTransactionResponse =
+                serde_json::from_str(&format!(r#"{{"ids": {}}}"#, ids)).unwrap();
+            assert_eq!(response.ids, Some(vec![1, 2]));
+        }
+    }
+
+    #[test]
+    fn fetched_transaction_ids_read_as_numbers_or_strings() {
+        let numbers: FetchedTransaction = serde_json::from_str(
+            r#"{"id": 12, "date": "2024-05-01", "amount": "-1.0000", "category_id": 3, "asset_id": 4}"#,
+        )
+        .unwrap();
+        let strings: FetchedTransaction = serde_json::from_str(
+            r#"{"id": "12", "date": "2024-05-01", "amount": "-1.0000", "category_id": "3", "asset_id": "4"}"#,
+        )
+        .unwrap();
+
+        for transaction in [numbers, strings] {
+            assert_eq!(transaction.id, 12);
+            assert_eq!(transaction.category_id, Some(3));
+            assert_eq!(transaction.asset_id, Some(4));
+        }
+    }
+
+    #[test]
+    fn non_numeric_ids_are_rejected() {
+        let asset = serde_json::from_value::<Asset>(serde_json::json!({
+            "id": "abc",
+            "balance": "1500.0000",
+            "currency": "clp",
+        }));
+        assert!(asset.is_err());
+    }
+
+    #[test]
+    fn split_survives_a_json_round_trip() {
+        let transaction = Transaction {
+            amount: Amount(-1000.0),
+            split: Some(vec![SplitPart {
+                date: NaiveDate::from_ymd_opt(2024, 5, 1).unwrap(),
+                payee: Some("Lider".to_string()),
+                amount: Amount(-1000.0),
+                category_id: None,
+                notes: Some("Installment 1/1".to_string()),
+            }]),
+            ..Default::default()
+        };
+
+        let json = serde_json::to_string(&transaction).unwrap();
+        let read: Transaction = serde_json::from_str(&json).unwrap();
+
+        let split = read.split.unwrap();
+        assert_eq!(split.len(), 1);
+        assert_eq!(split[0].amount, Amount(-1000.0));
+        assert_eq!(split[0].notes.as_deref(), Some("Installment 1/1"));
+    }
+
+    #[test]
+    fn unsplit_transactions_have_no_split_field() {
+        let json = serde_json::to_value(Transaction::default()).unwrap();
+        assert!(json.get("split").is_none());
+
+        // Dead-letter lines written before splits existed still read
+        let read: Transaction = serde_json::from_value(json).unwrap();
+        assert!(read.split.is_none());
+    }
+
+    #[test]
+    fn split_parts_read_parent_id() {
+        let part: FetchedTransaction = serde_json::from_str(
+            r#"{"id": "12", "date": "2024-05-01", "payee": "Lider", "amount": "-333.0000",
+                "currency": "clp", "parent_id": "11", "external_id": null}"#,
+        )
+        .unwrap();
+        assert_eq!(part.parent_id, Some(11));
+    }
+
+    #[test]
+    fn tag_update_keeps_the_existing_tags() {
+        let fetched = |tags: serde_json::Value| -> FetchedTransaction {
+            serde_json::from_value(serde_json::json!({
+                "id": 10,
+                "date": "2024-05-01",
+                "payee": "LIDER",
+                "amount": "-1500.0000",
+                "currency": "clp",
+                "external_id": "mov_1",
+                "tags": tags,
+            }))
+            .unwrap()
+        };
+
+        let update = fetched(serde_json::json!([{"id": 1, "name": "groceries"}]))
+            .tag_update("fintoc-sync-duplicate")
+            .unwrap();
+        assert_eq!(
+            serde_json::to_value(&update).unwrap(),
+            serde_json::json!({"tags": ["groceries", "fintoc-sync-duplicate"]})
+        );
+
+        let update = fetched(serde_json::Value::Null).tag_update("fintoc-sync-duplicate");
+        assert_eq!(
+            update.and_then(|update| update.tags),
+            Some(vec!["fintoc-sync-duplicate".to_string()])
+        );
+
+        let tagged = fetched(serde_json::json!([{"id": 2, "name": "fintoc-sync-duplicate"}]));
+        assert!(tagged.tag_update("fintoc-sync-duplicate").is_none());
+    }
+}
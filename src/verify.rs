@@ -0,0 +1,311 @@
+use std::collections::{HashMap, HashSet};
+
+use colored::*;
+use serde::Serialize;
+
+use crate::currency;
+use crate::types::lunchmoney::{FetchedTransaction, Transaction};
+
+/// Amounts closer than this are considered equal (Lunch Money keeps 4 decimals).
+const AMOUNT_TOLERANCE: f64 = 0.00005;
+
+/// Differences between the transactions expected from Fintoc and the ones
+/// present in Lunch Money for the same asset and window.
+#[derive(Debug, Default, Serialize)]
+pub struct Reconciliation {
+    /// Fintoc movements with no Lunch Money transaction sharing their external
+    /// id (or, with `composite_dedup`, their composite key).
+    pub missing: Vec<Transaction>,
+    /// Lunch Money transactions that don't match any Fintoc movement.
+    pub orphans: Vec<FetchedTransaction>,
+    /// Transactions present on both sides whose amounts or dates disagree.
+    pub mismatched: Vec<(Transaction, FetchedTransaction)>,
+}
+
+impl Reconciliation {
+    pub fn is_clean(&self) -> bool {
+        self.missing.is_empty() && self.orphans.is_empty() && self.mismatched.is_empty()
+    }
+
+    /// One line per difference, with aligned columns: green `+` for
+    /// transactions missing in Lunch Money, red `-` for ones not in Fintoc and
+    /// yellow `~` for mismatches.
+    pub fn diff_lines(&self) -> Vec<ColoredString> {
+        // (marker, date, payee, amount, detail, color)
+        let mut rows = Vec::new();
+
+        for transaction in &self.missing {
+            rows.push((
+                "+",
+                transaction.date.format("%Y-%m-%d").to_string(),
+                transaction.payee.clone().unwrap_or_default(),
+                currency::format_amount_plain(
+                    transaction.amount.0,
+                    transaction.currency.as_deref(),
+                ),
+                "missing in Lunch Money".to_string(),
+                Color::Green,
+            ));
+        }
+        for transaction in &self.orphans {
+            rows.push((
+                "-",
+                transaction.date.format("%Y-%m-%d").to_string(),
+                transaction.payee.clone().unwrap_or_default(),
+                currency::format_amount_plain(
+                    transaction.amount.0,
+                    transaction.currency.as_deref(),
+                ),
+                format!("not in Fintoc (id {})", transaction.id),
+                Color::Red,
+            ));
+        }
+        for (expected, existing) in &self.mismatched {
+            rows.push((
+                "~",
+                expected.date.format("%Y-%m-%d").to_string(),
+                expected.payee.clone().unwrap_or_default(),
+                currency::format_amount_plain(expected.amount.0, expected.currency.as_deref()),
+                format!(
+                    "Lunch Money has {} on {} (id {})",
+                    currency::format_amount_plain(existing.amount.0, expected.currency.as_deref()),
+                    existing.date.format("%Y-%m-%d"),
+                    existing.id
+                ),
+                Color::Yellow,
+            ));
+        }
+
+        let payee_width = rows
+            .iter()
+            .map(|row| row.2.chars().count())
+            .max()
+            .unwrap_or(0);
+        let amount_width = rows
+            .iter()
+            .map(|row| row.3.chars().count())
+            .max()
+            .unwrap_or(0);
+
+        rows.into_iter()
+            .map(|(marker, date, payee, amount, detail, color)| {
+                format!(
+                    "{} {}  {:<payee_width$}  {:>amount_width$}  {}",
+                    marker, date, payee, amount, detail
+                )
+                .color(color)
+            })
+            .collect()
+    }
+}
+
+/// Matches expected transactions against existing ones by `external_id`, and
+/// compares the amounts and dates of those found on both sides.
+///
+/// With `composite_dedup`, expected transactions left unmatched are then
+/// matched by composite key against the existing ones left over, each of
+/// those matching at most once, like `ExistingTransactions::duplicates`
+/// does when syncing. A pair matched that way shares its date and amount, so
+/// it's neither missing nor an orphan.
+pub fn reconcile(
+    expected: Vec<Transaction>,
+    existing: Vec<FetchedTransaction>,
+    composite_dedup: bool,
+) -> Reconciliation {
+    let mut reconciliation = Reconciliation::default();
+
+    let expected_ids = expected
+        .iter()
+        .filter_map(|transaction| transaction.external_id.clone())
+        .collect::<HashSet<_>>();
+
+    let mut existing_by_id = HashMap::new();
+    for transaction in existing {
+        match &transaction.external_id {
+            Some(external_id) if expected_ids.contains(external_id) => {
+                existing_by_id.insert(external_id.clone(), transaction);
+            }
+            _ => reconciliation.orphans.push(transaction),
+        }
+    }
+
+    for transaction in expected {
+        let found = transaction
+            .external_id
+            .as_ref()
+            .and_then(|external_id| existing_by_id.get(external_id));
+
+        match found {
+            None => reconciliation.missing.push(transaction),
+            Some(existing)
+                if (existing.amount.0 - transaction.amount.0).abs() > AMOUNT_TOLERANCE
+                    || existing.date != transaction.date.date_naive() =>
+            {
+                let existing = existing.clone();
+                reconciliation.mismatched.push((transaction, existing));
+            }
+            Some(_) => {}
+        }
+    }
+
+    if composite_dedup {
+        let orphans = &mut reconciliation.orphans;
+        reconciliation.missing.retain(|transaction| {
+            let key = transaction.composite_key();
+            match orphans
+                .iter()
+                .position(|orphan| orphan.composite_key() == key)
+            {
+                Some(index) => {
+                    orphans.remove(index);
+                    false
+                }
+                None => true,
+            }
+        });
+    }
+
+    reconciliation
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::fintoc::{ConversionOptions, Movement, MovementType};
+
+    fn expected(id: &str, amount: i64) -> Transaction {
+        Movement {
+            id: id.to_string(),
+            object: "movement".to_string(),
+            amount,
+            post_date: Some("2024-05-01T12:00:00Z".parse().unwrap()),
+            description: "COMPRA NACIONAL".to_string(),
+            transaction_date: None,
+            currency: "CLP".to_string(),
+            reference_id: None,
+            movement_type: MovementType::Other,
+            pending: false,
+            recipient_account: None,
+            sender_account: None,
+            comment: None,
+        }
+        .to_lunchmoney_transaction(1, &ConversionOptions::default())
+        .unwrap()
+    }
+
+    fn existing(id: u64, external_id: &str, date: &str, amount: &str) -> FetchedTransaction {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "date": date,
+            "payee": "COMPRA NACIONAL",
+            "amount": amount,
+            "currency": "clp",
+            "external_id": external_id,
+        }))
+        .unwrap()
+    }
+
+    fn external_ids(transactions: &[Transaction]) -> Vec<&str> {
+        transactions
+            .iter()
+            .filter_map(|transaction| transaction.external_id.as_deref())
+            .collect()
+    }
+
+    #[test]
+    fn matching_transactions_are_clean() {
+        let reconciliation = reconcile(
+            vec![expected("mov_1", -1500)],
+            vec![existing(10, "mov_1", "2024-05-01", "-1500.0000")],
+            false,
+        );
+        assert!(reconciliation.is_clean());
+    }
+
+    #[test]
+    fn movements_without_a_transaction_are_missing() {
+        let reconciliation = reconcile(
+            vec![expected("mov_1", -1500), expected("mov_2", -2500)],
+            vec![existing(10, "mov_1", "2024-05-01", "-1500.0000")],
+            false,
+        );
+        assert_eq!(external_ids(&reconciliation.missing), ["mov_2"]);
+        assert!(reconciliation.orphans.is_empty());
+        assert!(reconciliation.mismatched.is_empty());
+    }
+
+    #[test]
+    fn transactions_without_a_movement_are_orphans() {
+        let reconciliation = reconcile(
+            vec![expected("mov_1", -1500)],
+            vec![
+                existing(10, "mov_1", "2024-05-01", "-1500.0000"),
+                existing(11, "mov_gone", "2024-05-01", "-900.0000"),
+            ],
+            false,
+        );
+        assert!(reconciliation.missing.is_empty());
+        assert_eq!(
+            reconciliation
+                .orphans
+                .iter()
+                .map(|orphan| orphan.id)
+                .collect::<Vec<_>>(),
+            [11]
+        );
+    }
+
+    #[test]
+    fn differing_amounts_and_dates_are_mismatched() {
+        let reconciliation = reconcile(
+            vec![expected("mov_1", -1500), expected("mov_2", -2500)],
+            vec![
+                existing(10, "mov_1", "2024-05-01", "-1600.0000"),
+                existing(11, "mov_2", "2024-05-02", "-2500.0000"),
+            ],
+            false,
+        );
+        assert!(reconciliation.missing.is_empty());
+        assert!(reconciliation.orphans.is_empty());
+        assert_eq!(
+            reconciliation
+                .mismatched
+                .iter()
+                .map(|(_, existing)| existing.id)
+                .collect::<Vec<_>>(),
+            [10, 11]
+        );
+    }
+
+    #[test]
+    fn amounts_within_the_tolerance_match() {
+        let reconciliation = reconcile(
+            vec![expected("mov_1", -1500)],
+            vec![existing(10, "mov_1", "2024-05-01", "-1500.00004")],
+            false,
+        );
+        assert!(reconciliation.is_clean());
+
+        let reconciliation = reconcile(
+            vec![expected("mov_1", -1500)],
+            vec![existing(10, "mov_1", "2024-05-01", "-1500.0001")],
+            false,
+        );
+        assert_eq!(reconciliation.mismatched.len(), 1);
+    }
+
+    #[test]
+    fn with_composite_dedup_a_changed_id_is_matched_by_composite_key() {
+        let transactions = || vec![expected("mov_new", -1500), expected("mov_2", -1500)];
+        let existing = || vec![existing(10, "mov_old", "2024-05-01", "-1500.0000")];
+
+        let reconciliation = reconcile(transactions(), existing(), true);
+        // Only one of the identical purchases can be the existing transaction
+        assert_eq!(external_ids(&reconciliation.missing), ["mov_2"]);
+        assert!(reconciliation.orphans.is_empty());
+
+        let reconciliation = reconcile(transactions(), existing(), false);
+        assert_eq!(external_ids(&reconciliation.missing), ["mov_new", "mov_2"]);
+        assert_eq!(reconciliation.orphans.len(), 1);
+    }
+}
@@ -0,0 +1,219 @@
+//! Payee, notes and status the tool last wrote to each transaction, so
+//! `update_existing` can tell its own values from manual edits made in Lunch
+//! Money. Only transactions synced with `update_existing` on are tracked.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::types::lunchmoney::{FetchedTransaction, Transaction, TransactionUpdate};
+
+/// Values of the fields `update_existing` may change.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct WrittenFields {
+    pub payee: Option<String>,
+    pub notes: Option<String>,
+    pub status: Option<String>,
+}
+
+impl WrittenFields {
+    /// The values a converted transaction is written with.
+    pub fn of(transaction: &Transaction) -> Self {
+        Self {
+            payee: transaction.payee.clone(),
+            notes: transaction.notes.clone(),
+            status: Some(transaction.status.as_str().to_string()),
+        }
+    }
+
+    /// The values a transaction currently has in Lunch Money.
+    pub fn of_fetched(transaction: &FetchedTransaction) -> Self {
+        Self {
+            payee: transaction.payee.clone(),
+            notes: transaction.notes.clone(),
+            status: transaction.status.clone(),
+        }
+    }
+
+    /// These values, with the fields set in `update` replaced.
+    pub fn updated(&self, update: &TransactionUpdate) -> Self {
+        Self {
+            payee: update.payee.clone().or_else(|| self.payee.clone()),
+            notes: update.notes.clone().or_else(|| self.notes.clone()),
+            status: update.status.clone().or_else(|| self.status.clone()),
+        }
+    }
+}
+
+/// The change bringing a transaction from its `current` values to the
+/// `converted` ones. A field is only changed when the conversion has a value
+/// for it, and Lunch Money still has what the tool `written` last, i.e. it
+/// wasn't edited by hand since.
+pub fn update_for(
+    converted: &WrittenFields,
+    current: &WrittenFields,
+    written: &WrittenFields,
+) -> TransactionUpdate {
+    let field = |converted: &Option<String>, current: &Option<String>, written: &Option<String>| {
+        converted
+            .clone()
+            .filter(|value| current.as_ref() != Some(value) && current == written)
+    };
+
+    TransactionUpdate {
+        payee: field(&converted.payee, &current.payee, &written.payee),
+        notes: field(&converted.notes, &current.notes, &written.notes),
+        status: field(&converted.status, &current.status, &written.status),
+        ..Default::default()
+    }
+}
+
+/// Written values by `"<asset id>/<external id>"`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct WrittenLedger {
+    transactions: BTreeMap<String, WrittenFields>,
+}
+
+impl WrittenLedger {
+    /// Reads the ledger. A missing file means nothing was tracked yet.
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => {
+                return Err(err).with_context(|| {
+                    format!("Failed to read written fields file {}", path.display())
+                })
+            }
+        };
+
+        serde_json::from_str(&contents)
+            .with_context(|| format!("Invalid written fields file {}", path.display()))
+    }
+
+    /// Writes the ledger, replacing it atomically like the state file.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let temp_path = path.with_extension("json.tmp");
+        fs::write(&temp_path, serde_json::to_string_pretty(self)?).with_context(|| {
+            format!(
+                "Failed to write written fields file {}",
+                temp_path.display()
+            )
+        })?;
+        fs::rename(&temp_path, path)
+            .with_context(|| format!("Failed to write written fields file {}", path.display()))
+    }
+
+    pub fn get(&self, asset_id: u64, external_id: &str) -> Option<&WrittenFields> {
+        self.transactions.get(&Self::key(asset_id, external_id))
+    }
+
+    pub fn record(&mut self, asset_id: u64, external_id: &str, fields: WrittenFields) {
+        self.transactions
+            .insert(Self::key(asset_id, external_id), fields);
+    }
+
+    fn key(asset_id: u64, external_id: &str) -> String {
+        format!("{}/{}", asset_id, external_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(payee: &str, notes: Option<&str>) -> WrittenFields {
+        WrittenFields {
+            payee: Some(payee.to_string()),
+            notes: notes.map(str::to_string),
+            status: Some("uncleared".to_string()),
+        }
+    }
+
+    #[test]
+    fn changed_fintoc_data_updates_untouched_fields() {
+        let written = fields("LIDER", None);
+        let converted = fields("Lider Express", Some("cuota 1"));
+
+        let update = update_for(&converted, &written, &written);
+
+        assert_eq!(update.payee.as_deref(), Some("Lider Express"));
+        assert_eq!(update.notes.as_deref(), Some("cuota 1"));
+        assert_eq!(update.status, None);
+    }
+
+    #[test]
+    fn manual_edits_are_kept() {
+        let written = fields("LIDER", None);
+        let current = fields("Supermercado", Some("weekly shop"));
+        let converted = fields("Lider Express", Some("cuota 1"));
+
+        assert!(update_for(&converted, &current, &written).is_empty());
+    }
+
+    #[test]
+    fn only_the_edited_field_is_kept() {
+        let written = fields("LIDER", None);
+        let current = fields("Supermercado", None);
+        let converted = fields("Lider Express", Some("cuota 1"));
+
+        let update = update_for(&converted, &current, &written);
+
+        assert_eq!(update.payee, None);
+        assert_eq!(update.notes.as_deref(), Some("cuota 1"));
+    }
+
+    #[test]
+    fn status_changed_by_hand_is_kept() {
+        let written = fields("LIDER", None);
+        let current = WrittenFields {
+            status: Some("cleared".to_string()),
+            ..written.clone()
+        };
+
+        assert!(update_for(&written, &current, &written).is_empty());
+    }
+
+    #[test]
+    fn fields_without_a_converted_value_are_left_alone() {
+        let written = fields("LIDER", Some("old comment"));
+        let converted = fields("LIDER", None);
+
+        assert!(update_for(&converted, &written, &written).is_empty());
+    }
+
+    #[test]
+    fn updated_keeps_fields_not_in_the_update() {
+        let written = fields("LIDER", Some("old comment"));
+        let update = TransactionUpdate {
+            payee: Some("Lider Express".to_string()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            written.updated(&update),
+            fields("Lider Express", Some("old comment"))
+        );
+    }
+
+    #[test]
+    fn ledger_round_trips_through_its_file() {
+        let path = std::env::temp_dir().join(format!(
+            "lunchmoney-fintoc-written-{}.json",
+            std::process::id()
+        ));
+        let mut ledger = WrittenLedger::default();
+        ledger.record(7, "mov_1", fields("LIDER", None));
+        ledger.save(&path).unwrap();
+
+        let loaded = WrittenLedger::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.get(7, "mov_1"), Some(&fields("LIDER", None)));
+        assert_eq!(loaded.get(8, "mov_1"), None);
+    }
+}